@@ -0,0 +1,120 @@
+use crate::backup::{copy_directory, BackupStats, CopyOptions};
+use crate::events::{Event, EventSink, SkipReason};
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// An owned copy of an `Event` emitted while copying, sent through the progress channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    DirEntered { path: PathBuf },
+    FileCopied { path: PathBuf, bytes: u64 },
+    FileSkipped { path: PathBuf, reason: SkipReason },
+    FileDeleted { path: PathBuf },
+    Warning { message: String },
+    Error { message: String },
+}
+
+impl Progress {
+    /// Copies the events a copy emits; run-level events (hooks, summaries) give `None`.
+    fn from_event(event: &Event) -> Option<Progress> {
+        Some(match event {
+            Event::DirEntered { path } => Progress::DirEntered {
+                path: path.to_path_buf(),
+            },
+            Event::FileCopied { path, bytes } => Progress::FileCopied {
+                path: path.to_path_buf(),
+                bytes: *bytes,
+            },
+            Event::FileSkipped { path, reason } => Progress::FileSkipped {
+                path: path.to_path_buf(),
+                reason: *reason,
+            },
+            Event::FileDeleted { path } => Progress::FileDeleted {
+                path: path.to_path_buf(),
+            },
+            Event::Warning { message } => Progress::Warning {
+                message: message.clone(),
+            },
+            Event::Error { message } => Progress::Error {
+                message: message.clone(),
+            },
+            Event::HookOutput { .. } | Event::Summary { .. } | Event::RunFinished { .. } => {
+                return None
+            }
+        })
+    }
+}
+
+/// Forwards events to a channel from the blocking thread that copies.
+struct ChannelSink {
+    sender: mpsc::Sender<Progress>,
+    cancel: CancellationToken,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl EventSink for ChannelSink {
+    fn emit(&self, event: &Event) {
+        // Every entry emits an event, so this stops the copy before the next one even while the
+        // runtime is too busy to run the watcher task
+        if self.cancel.is_cancelled() {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        if let Some(progress) = Progress::from_event(event) {
+            // A dropped receiver only means nobody is listening; the copy goes on
+            let _ = self.sender.blocking_send(progress);
+        }
+    }
+}
+
+/// Runs `copy_directory` on tokio's blocking thread pool, so it does not stall the runtime.
+///
+/// # Parameters
+/// - `src`: Source directory path to be copied.
+/// - `dest`: Destination directory; it must exist.
+/// - `options`: Exclusion settings applied to every entry.
+/// - `cancel`: Cancelling it stops the copy before its next entry.
+/// - `progress`: Receives every event of the copy. The copy waits while the channel is full, so
+///   the receiver must keep reading it (or be dropped) until this function returns.
+///
+/// # Returns
+/// - The counters of the copy.
+///
+/// # Errors
+/// - Will return the errors of `copy_directory`, including an error of kind
+///   `ErrorKind::Interrupted` once `cancel` was cancelled.
+/// - Will return an error if the copy panicked.
+pub async fn copy_directory_async(
+    src: PathBuf,
+    dest: PathBuf,
+    options: CopyOptions,
+    cancel: CancellationToken,
+    progress: mpsc::Sender<Progress>,
+) -> std::io::Result<BackupStats> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    // Also wakes a copy that emits nothing for a while, such as one waiting to retry a file
+    let watcher = tokio::spawn({
+        let (cancel, cancelled) = (cancel.clone(), Arc::clone(&cancelled));
+        async move {
+            cancel.cancelled().await;
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    });
+
+    let copy = tokio::task::spawn_blocking(move || {
+        let sink = ChannelSink {
+            sender: progress,
+            cancel,
+            cancelled: Arc::clone(&cancelled),
+        };
+        let mut stats = BackupStats::default();
+        copy_directory(&src, &dest, &options, &mut stats, &cancelled, &sink)?;
+        Ok(stats)
+    });
+    let result = copy.await;
+    watcher.abort();
+    result.map_err(Error::other)?
+}
@@ -0,0 +1,50 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Atomically replaces the contents of `path`.
+///
+/// The contents are first written to `<path>.tmp` next to `path` and then renamed over it, so a
+/// reader such as a metrics scraper or a monitoring script never observes a partially written
+/// file. The temporary file is removed if writing it fails.
+///
+/// # Parameters
+/// - `path`: File to create or replace.
+/// - `contents`: Its new contents.
+///
+/// # Errors
+/// - Will return an error if the temporary file cannot be written or renamed.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let written = File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_replacement_leaves_no_temporary_file() {
+        let temp = tempfile::tempdir().unwrap();
+        // A directory cannot be replaced by a file
+        let path = temp.path().join("summary.json");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("kept"), "").unwrap();
+
+        assert!(write_atomic(&path, b"{}").is_err());
+
+        assert!(path.join("kept").exists());
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+}
@@ -1,58 +1,2822 @@
-use std::fs;
-use std::path::Path;
+use crate::dedup::{DedupIndex, Lookup};
+use crate::events::{Event, EventSink, SkipReason};
+use crate::naming::LEVEL_SEPARATOR;
+use crate::patterns::Pattern;
+#[cfg(not(windows))]
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Counters collected while copying a directory tree.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BackupStats {
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_skipped: u64,
+    /// Files skipped because copying them would exceed the size budget (also in `files_skipped`).
+    pub files_over_budget: u64,
+    /// Files skipped because of their sniffed content type (also in `files_skipped`).
+    pub files_excluded_by_type: u64,
+    /// FIFOs, sockets and device nodes that were not copied.
+    pub special_files_skipped: u64,
+    /// Entries skipped because their name cannot be created on the target platform.
+    pub invalid_names_skipped: u64,
+    /// Files that needed at least one retry after a transient I/O error.
+    pub files_retried: u64,
+    /// Total number of retries across all files.
+    pub retry_attempts: u64,
+    /// Entries whose owner could not be set with `preserve_owner`.
+    pub owner_errors: u64,
+    /// Mount points not descended into because of `one_file_system`.
+    pub mount_points_skipped: u64,
+    /// Files already up to date in the destination (with `update_in_place`).
+    pub files_unchanged: u64,
+    /// Entries removed from a mirror because they no longer exist in the source.
+    pub files_deleted: u64,
+    /// Files and directories that could not be copied; the rest of the backup went ahead.
+    pub files_failed: u64,
+    /// Files hard-linked to an identical file copied earlier (also in `files_copied`).
+    pub files_deduplicated: u64,
+    /// Bytes not written because of deduplication.
+    pub bytes_deduplicated: u64,
+    /// Directories not descended into because they are deeper than `max_depth`.
+    pub dirs_too_deep: u64,
+    /// Directories not descended into because a symbolic link led back to one of their parents.
+    pub cycles_skipped: u64,
+    /// Entries copied under another name because their name differs only in case from one
+    /// copied before them (`--rename-collisions`).
+    pub case_collisions_renamed: u64,
+    /// Files deleted from the source between being listed and being copied.
+    pub files_vanished: u64,
+    /// Files whose size changed while they were copied; their copy may be inconsistent.
+    pub files_changed: u64,
+    /// Files copied by an interrupted run that `--resume` continued, and not copied again.
+    pub files_resumed: u64,
+    /// Files whose earlier copy matched the source in size and modification time but not in
+    /// contents (`paranoid`), and that were copied again.
+    pub files_mismatched: u64,
+    /// Files left out because the process is not allowed to read them.
+    pub files_unreadable: Vec<PathBuf>,
+}
+
+/// Outcome of backing up a single source directory.
+#[derive(Debug)]
+pub struct SourceReport {
+    pub source: String,
+    pub stats: BackupStats,
+    pub duration: Duration,
+    /// Final backup directory on success, or the error message on failure.
+    pub result: Result<PathBuf, String>,
+}
+
+impl SourceReport {
+    /// Whether the source was backed up completely: the run succeeded and no entry failed.
+    pub fn succeeded(&self) -> bool {
+        self.result.is_ok() && self.stats.files_failed == 0
+    }
+}
+
+/// Prefix of backup directories that are still being written.
+pub const IN_PROGRESS_PREFIX: &str = ".tmp.";
+
+/// Maximum number of suffixed names tried when a backup directory name is already taken.
+const MAX_NAME_ATTEMPTS: u32 = 100;
+
+/// Returns the name of a source directory, used to name its backups.
+///
+/// # Errors
+/// - Will return an `InvalidInput` error if no name can be derived from `source` (e.g. `/`).
+pub fn source_name(source: &Path) -> std::io::Result<String> {
+    // Paths such as `.` have no file name of their own, so fall back to the resolved path
+    let name = match source.file_name() {
+        Some(name) => name.to_os_string(),
+        None => source
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_os_string())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Cannot derive a backup name from source {:?}", source),
+                )
+            })?,
+    };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Refuses a source root that is a symbolic link.
+///
+/// A link given as the source is usually a mistake (`data` pointing at another disk), and the
+/// canonical path a backup works with no longer shows that it was one, so this is checked on
+/// the path as given.
+///
+/// # Errors
+/// - Will return an error if `src` cannot be inspected or is a symbolic link.
+pub fn refuse_symlinked_root(src: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(src)?.file_type().is_symlink() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{:?} is a symbolic link; pass --dereference-root to back up the directory it points to",
+                src
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a backup directory called `name` inside `target` under an in-progress name.
+///
+/// The directory is named `.tmp.<name>` so that tools watching the target never pick up an
+/// incomplete backup; `finalize_backup_dir` gives it its final name. If a backup with the same
+/// name already exists (e.g. two runs within the same second), a numeric suffix (`_2`, `_3`,
+/// ...) is appended instead of reusing the existing directory.
+///
+/// A nested name such as `web1/docs/20240131` creates the parent directories as needed; only the
+/// last level gets the prefix and the suffix.
+///
+/// # Parameters
+/// - `target`: Directory in which the backup directory is created.
+/// - `base_name`: Final name of the backup directory, rendered from the name template.
+///
+/// # Returns
+/// - The path of the in-progress backup directory.
+///
+/// # Errors
+/// - Will return an `AlreadyExists` error if no unique name is found after `MAX_NAME_ATTEMPTS`.
+/// - Will return an error if the directory cannot be created.
+pub fn create_backup_dir(target: &Path, base_name: &str) -> std::io::Result<PathBuf> {
+    let (parent, base_name) = match base_name.rsplit_once(LEVEL_SEPARATOR) {
+        Some((parent, leaf)) => (target.join(parent), leaf),
+        None => (target.to_path_buf(), base_name),
+    };
+    let target = parent.as_path();
+    fs::create_dir_all(target)?;
+
+    for attempt in 1..=MAX_NAME_ATTEMPTS {
+        let name = if attempt == 1 {
+            base_name.to_string()
+        } else {
+            format!("{}_{}", base_name, attempt)
+        };
+
+        // The final name must be free too, or the rename on success would collide
+        if target.join(&name).exists() {
+            continue;
+        }
+
+        let backup_dir = target.join(format!("{}{}", IN_PROGRESS_PREFIX, name));
+        match fs::create_dir(&backup_dir) {
+            Ok(()) => return Ok(backup_dir),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::AlreadyExists,
+        format!(
+            "Could not find a free backup directory name for {}",
+            base_name
+        ),
+    ))
+}
+
+/// Renames an in-progress backup directory to its final name by dropping `IN_PROGRESS_PREFIX`.
+///
+/// # Parameters
+/// - `backup_dir`: Path returned by `create_backup_dir`.
+///
+/// # Returns
+/// - The final path of the backup directory.
+///
+/// # Errors
+/// - Will return an error if the directory cannot be renamed.
+pub fn finalize_backup_dir(backup_dir: &Path) -> std::io::Result<PathBuf> {
+    let name = backup_dir.file_name().unwrap_or_default().to_string_lossy();
+    let final_name = name.strip_prefix(IN_PROGRESS_PREFIX).unwrap_or(&name);
+    let final_dir = backup_dir.with_file_name(final_name);
+
+    fs::rename(backup_dir, &final_dir)?;
+    Ok(final_dir)
+}
+
+/// Name of the lock file created in the target directory while a backup runs.
+pub const LOCK_FILE_NAME: &str = ".snapshotter.lock";
+
+/// Name of the file that marks a `--no-timestamp` target as snapshotter's. It is written on
+/// every run, so the target is recognized even when `--no-lock` leaves no lock file behind.
+pub const MARKER_FILE_NAME: &str = ".snapshotter";
+
+/// Directory of a `--repo` target holding the chunks of file contents.
+pub const OBJECTS_DIR_NAME: &str = "objects";
+
+/// Directory of a `--repo` target holding one manifest per backup.
+pub const MANIFESTS_DIR_NAME: &str = "manifests";
+
+/// Contents of `MARKER_FILE_NAME`.
+const MARKER_CONTENTS: &str = "This directory is kept up to date by snapshotter --no-timestamp.\n";
+
+/// Writes `MARKER_FILE_NAME` into `target`, unless it is already there.
+///
+/// # Errors
+/// - Will return an error if the file cannot be written.
+pub fn mark_target(target: &Path) -> std::io::Result<()> {
+    let marker = target.join(MARKER_FILE_NAME);
+    if marker.is_file() {
+        return Ok(());
+    }
+    fs::write(marker, MARKER_CONTENTS)
+}
+
+/// Takes an exclusive advisory lock on `<target>/.snapshotter.lock`, creating the target if needed.
+///
+/// The lock is held until the returned file is dropped. Because the operating system releases
+/// it when the process exits, a lock file left behind by a crashed run does not block later ones.
+/// The holder writes its process ID into the file so that a blocked run can name it.
+///
+/// # Parameters
+/// - `target`: The target directory to lock.
+/// - `wait`: How long to keep retrying while another run holds the lock; zero fails at once.
+///
+/// # Errors
+/// - Will return an error of kind `ErrorKind::WouldBlock` if another run still holds the lock
+///   after `wait`.
+/// - Will return an error if the target or the lock file cannot be created.
+pub fn lock_target(target: &Path, wait: Duration) -> std::io::Result<File> {
+    fs::create_dir_all(target)?;
+    let lock_path = target.join(LOCK_FILE_NAME);
+    let mut file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+
+    let deadline = std::time::Instant::now() + wait;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(fs::TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                std::thread::sleep(LOCK_POLL_INTERVAL);
+            }
+            Err(fs::TryLockError::WouldBlock) => {
+                // Windows does not let other handles read a locked file; the PID is optional
+                let holder = fs::read_to_string(&lock_path)
+                    .ok()
+                    .and_then(|contents| contents.trim().parse::<u32>().ok())
+                    .map(|pid| format!(" by process {}", pid))
+                    .unwrap_or_default();
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!(
+                        "Another backup is already running on {:?} (lock held on {:?}{})",
+                        target, lock_path, holder
+                    ),
+                ));
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e),
+        }
+    }
+
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
+/// How often `lock_target` retries while waiting for another run to release the lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How zero-filled regions of source files are written to the destination.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SparseMode {
+    /// Write sparse copies only of files that are sparse in the source (Unix only).
+    #[default]
+    Auto,
+    /// Skip every zero-filled block, so destination files are always written sparse.
+    Always,
+    /// Copy every byte, materializing holes in the destination.
+    Never,
+}
+
+/// Where the contents of a source go inside its backup directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathStyle {
+    /// Directly into the backup directory.
+    #[default]
+    Basename,
+    /// Below the path of the source relative to `--relative-root`.
+    Relative,
+    /// Below the full path of the source, without its root (`var/lib/myapp/data`).
+    Absolute,
+}
+
+/// Returns where the contents of `source` go inside its backup directory, as a relative path
+/// that is empty for `PathStyle::Basename`.
+///
+/// # Parameters
+/// - `source`: Canonical path of the source directory.
+/// - `style`: How the path of the source is kept.
+/// - `relative_root`: Canonical directory the path is made relative to with `PathStyle::Relative`.
+///
+/// # Errors
+/// - Will return an `InvalidInput` error if the source is not below `relative_root`, if
+///   `relative_root` is missing for `PathStyle::Relative`, or if the path holds `..`.
+pub fn backup_subpath(
+    source: &Path,
+    style: PathStyle,
+    relative_root: Option<&Path>,
+) -> std::io::Result<PathBuf> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+    let path = match style {
+        PathStyle::Basename => return Ok(PathBuf::new()),
+        PathStyle::Relative => {
+            let root = relative_root.ok_or_else(|| {
+                invalid("--backup-path-style relative requires --relative-root".to_string())
+            })?;
+            source.strip_prefix(root).map_err(|_| {
+                invalid(format!(
+                    "The source {:?} is not inside --relative-root {:?}",
+                    source, root
+                ))
+            })?
+        }
+        PathStyle::Absolute => source,
+    };
+
+    let mut subpath = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => subpath.push(name),
+            Component::RootDir | Component::CurDir => {}
+            // The drive or share becomes the first directory: `C:\data` is kept as `C/data`
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    subpath.push(char::from(letter).to_string());
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    subpath.push(server);
+                    subpath.push(share);
+                }
+                Prefix::Verbatim(_) | Prefix::DeviceNS(_) => {
+                    return Err(invalid(format!(
+                        "Cannot keep the path of {:?} in a backup",
+                        source
+                    )));
+                }
+            },
+            Component::ParentDir => {
+                return Err(invalid(format!(
+                    "Paths with .. cannot be kept in a backup: {:?}",
+                    source
+                )));
+            }
+        }
+    }
+    Ok(subpath)
+}
+
+/// Whether files are cloned (reflinked) instead of copied when the filesystem supports it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReflinkMode {
+    /// Try a reflink first and fall back to a regular copy.
+    Auto,
+    /// Require a reflink and fail the file if the filesystem cannot clone it.
+    Always,
+    /// Always copy the data (portable default).
+    #[default]
+    Never,
+}
+
+/// Size of the blocks checked for zeros when writing sparse copies.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Size of the buffer used when copying files block by block.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copies at least this large are memory-mapped for hashing instead of read in chunks.
+const MMAP_HASH_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Settings that decide which entries `copy_directory` copies.
+#[derive(Debug, Default, Clone)]
+pub struct CopyOptions {
+    /// Files or directories to exclude from copying, matched against their paths relative to
+    /// the source root.
+    pub exclude_list: Vec<Pattern>,
+    /// File extensions to exclude from copying.
+    pub exclude_extensions: Vec<String>,
+    /// MIME types (`video/mp4`) or categories (`video`, `video/*`) of files to exclude, detected
+    /// from the first bytes of each file.
+    pub exclude_mime_types: Vec<String>,
+    /// Paths that are never copied or descended into (e.g. a target nested in the source).
+    pub excluded_paths: Vec<PathBuf>,
+    /// Whether holes in sparse files are preserved.
+    pub sparse: SparseMode,
+    /// Whether files are cloned instead of copied where possible.
+    pub reflink: ReflinkMode,
+    /// Maximum number of bytes copied; files that would exceed it are skipped.
+    pub max_total_size: Option<u64>,
+    /// Skip hidden files and directories (dotfiles on Unix, the hidden attribute on Windows).
+    pub skip_hidden: bool,
+    /// Recreate FIFOs, sockets and device nodes instead of skipping them (Unix only).
+    pub special_files: bool,
+    /// How many times a file is retried after a transient I/O error.
+    pub retries: u32,
+    /// Delay before the first retry; it doubles with every further attempt.
+    pub retry_delay: Duration,
+    /// Raw OS error codes (e.g. `EIO`) treated as transient in addition to the default kinds.
+    pub retry_os_errors: Vec<i32>,
+    /// Give copied entries the uid/gid of their source (Unix only, usually requires root).
+    pub preserve_owner: bool,
+    /// Copy the owner and access control list of every entry (Windows only).
+    pub preserve_acls: bool,
+    /// Match excluded names and extensions regardless of case.
+    pub case_insensitive: bool,
+    /// Update an existing copy: files whose size and modification time match are left alone,
+    /// and entries of the wrong type are replaced.
+    pub update_in_place: bool,
+    /// Device of the source root (see `device_id`) when directories on other filesystems are
+    /// not descended into.
+    pub one_file_system: Option<u64>,
+    /// Walk and report as usual, but don't create, copy or delete anything.
+    pub dry_run: bool,
+    /// Remove destination directories that end up without any files.
+    pub no_empty_dirs: bool,
+    /// Store files with identical contents once and hard-link the duplicates.
+    pub dedup: bool,
+    /// Copy every file into the destination root instead of recreating the directory tree.
+    pub flatten: bool,
+    /// Copy only files owned by these users or groups (Unix only).
+    pub owner_filter: Option<OwnerFilter>,
+    /// How many directory levels below the source root are descended into; 0 copies only the
+    /// files directly in the source.
+    pub max_depth: Option<usize>,
+    /// Copy only these entries, given relative to the source root, instead of walking the
+    /// whole source. Listed directories are copied with everything below them.
+    pub files_from: Option<Vec<PathBuf>>,
+    /// What happens to entries whose names differ only in case from another entry of the same
+    /// directory; `None` when the target tells such names apart.
+    pub case_collisions: Option<CaseCollisions>,
+    /// Source files an interrupted run already copied (`--resume`); they are kept as long as
+    /// their copy still matches them in size and modification time.
+    pub already_copied: HashSet<PathBuf>,
+    /// Count files the process is not allowed to read as failed entries instead of skipping
+    /// them with a warning.
+    pub fail_on_unreadable: bool,
+    /// Compare the contents of files whose existing copy is up to date by size and
+    /// modification time, and copy them again if they differ.
+    pub paranoid: bool,
+}
+
+/// How entries whose names collide on a case-insensitive target are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Report the later entry as an error instead of letting it overwrite the earlier one.
+    Fail,
+    /// Copy the later entry under a numbered name (`--rename-collisions`).
+    Rename,
+}
+
+/// The names of the entries copied into one destination directory, compared the way a
+/// case-insensitive filesystem compares them.
+#[derive(Default)]
+struct FoldedNames {
+    names: HashMap<String, OsString>,
+}
+
+impl FoldedNames {
+    /// Records `name` and returns `None`, or returns the name recorded earlier that differs
+    /// from it only in case.
+    fn insert(&mut self, name: &OsStr) -> Option<&OsString> {
+        match self.names.entry(name.to_string_lossy().to_lowercase()) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                entry.insert(name.to_os_string());
+                None
+            }
+        }
+    }
+
+    /// Returns the first numbered variant of `name` (see `numbered_names`) that does not
+    /// collide with a recorded name, and records it.
+    fn rename(&mut self, name: &OsStr) -> OsString {
+        let renamed = numbered_names(name)
+            .find(|candidate| !self.names.contains_key(&candidate.to_lowercase()))
+            .expect("some numbered name is free");
+        let renamed = OsString::from(renamed);
+        self.insert(&renamed);
+        renamed
+    }
+}
+
+/// Checks whether the filesystem holding `dir` treats names that differ only in case as the
+/// same name, by creating a probe file in `dir` and looking it up in upper case.
+///
+/// # Errors
+/// - Will return an error if the probe file cannot be created or removed.
+pub fn is_case_insensitive(dir: &Path) -> std::io::Result<bool> {
+    let probe = dir.join(".snapshotter-case-probe");
+    File::create(&probe)?;
+    let insensitive = fs::symlink_metadata(dir.join(".SNAPSHOTTER-CASE-PROBE")).is_ok();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+/// Users and groups whose files are copied by `--owner` and `--group`; files of everyone else
+/// are skipped. Directories are always descended into.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct OwnerFilter {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl OwnerFilter {
+    /// Resolves user and group names (or numeric IDs) to a filter.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if neither users nor groups are given.
+    ///
+    /// # Errors
+    /// - Returns a message naming the first user or group that does not exist.
+    #[cfg(unix)]
+    pub fn resolve(users: &[String], groups: &[String]) -> Result<Option<OwnerFilter>, String> {
+        if users.is_empty() && groups.is_empty() {
+            return Ok(None);
+        }
+        let lookup = |names: &[String], kind: &str, find: fn(&str) -> Option<u32>| {
+            names
+                .iter()
+                .map(|name| {
+                    name.parse()
+                        .ok()
+                        .or_else(|| find(name))
+                        .ok_or_else(|| format!("Unknown {}: {}", kind, name))
+                })
+                .collect::<Result<Vec<u32>, String>>()
+        };
+        Ok(Some(OwnerFilter {
+            uids: lookup(users, "user", user_id)?,
+            gids: lookup(groups, "group", group_id)?,
+        }))
+    }
+
+    /// Files have no Unix owner on this platform.
+    ///
+    /// # Errors
+    /// - Returns a message if any user or group is given.
+    #[cfg(not(unix))]
+    pub fn resolve(users: &[String], groups: &[String]) -> Result<Option<OwnerFilter>, String> {
+        if users.is_empty() && groups.is_empty() {
+            Ok(None)
+        } else {
+            Err("--owner and --group are not supported on this platform".to_string())
+        }
+    }
+
+    /// Checks whether a file with this uid and gid belongs to one of the users or groups.
+    #[cfg(unix)]
+    pub fn matches(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// Looks up the uid of the user called `name`.
+#[cfg(unix)]
+fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut buffer = vec![0; 16 * 1024];
+    // SAFETY: passwd is plain data that getpwnam_r fills in; all-zero is a valid value for it
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid and the buffer length matches the buffer
+    let status = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    (status == 0 && !result.is_null()).then_some(entry.pw_uid)
+}
+
+/// Looks up the gid of the group called `name`.
+#[cfg(unix)]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut buffer = vec![0; 16 * 1024];
+    // SAFETY: group is plain data that getgrnam_r fills in; all-zero is a valid value for it
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid and the buffer length matches the buffer
+    let status = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    (status == 0 && !result.is_null()).then_some(entry.gr_gid)
+}
+
+/// Identifies a directory independently of the path it was reached by.
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+/// Returns the identity of the directory at `path`: its device and inode.
+#[cfg(unix)]
+fn dir_id(_path: &Path, metadata: &fs::Metadata) -> DirId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+/// Returns the identity of the directory at `path`: its path with all links resolved.
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _metadata: &fs::Metadata) -> DirId {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns a free path in `dir` for a file called `file_name` in a flattened backup.
+///
+/// If the name is taken (by a file of the same name from another directory), the first free
+/// name from `numbered_names` is used.
+fn flat_destination(dir: &Path, file_name: &OsStr) -> PathBuf {
+    let is_free = |path: &Path| fs::symlink_metadata(path).is_err();
+    let path = dir.join(file_name);
+    if is_free(&path) {
+        return path;
+    }
+
+    numbered_names(file_name)
+        .map(|name| dir.join(name))
+        .find(|path| is_free(path))
+        .expect("some numbered name is free")
+}
+
+/// Returns the names tried when `file_name` is taken: `_2`, `_3`, ... inserted before the
+/// extension, so `photo.jpg` becomes `photo_2.jpg`.
+fn numbered_names(file_name: &OsStr) -> impl Iterator<Item = String> {
+    let name = Path::new(file_name);
+    let stem = name
+        .file_stem()
+        .unwrap_or(file_name)
+        .to_string_lossy()
+        .into_owned();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (2u64..).map(move |n| format!("{}_{}{}", stem, n, extension))
+}
+
+/// Checks whether the file described by `metadata` is left out by `--owner`/`--group`.
+#[cfg(unix)]
+fn has_other_owner(options: &CopyOptions, metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    options
+        .owner_filter
+        .as_ref()
+        .is_some_and(|filter| !filter.matches(metadata.uid(), metadata.gid()))
+}
+
+/// Files have no Unix owner on this platform, so none can match a filter (`OwnerFilter::resolve`
+/// rejects filters here, so this only matters if one is set directly).
+#[cfg(not(unix))]
+fn has_other_owner(options: &CopyOptions, _metadata: &fs::Metadata) -> bool {
+    options.owner_filter.is_some()
+}
+
+/// Checks whether a file occupies fewer blocks on disk than its length, i.e. contains holes.
+#[cfg(unix)]
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+/// Allocation information is not available, so files are never considered sparse.
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Clones `src` into a new file at `dest` with the `FICLONE` ioctl, sharing the data extents
+/// on copy-on-write filesystems such as btrfs and XFS.
+#[cfg(target_os = "linux")]
+fn reflink_file(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let reader = File::open(src)?;
+    let writer = File::create(dest)?;
+    // SAFETY: both descriptors are valid open files for the duration of the call
+    let result = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+    if result == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reflinks are only implemented on Linux; `fs::copy` already clones files on macOS.
+#[cfg(not(target_os = "linux"))]
+fn reflink_file(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Reflinks are not supported on this platform",
+    ))
+}
+
+/// Copies a single file, cloning it or seeking over zero-filled blocks instead of writing them
+/// when the options ask for it. The modification time of the source is kept.
+///
+/// This is how `copy_directory` copies each file, without the exclusion checks, retries and
+/// events around it. The parent directory of `dest` must exist, and an existing file at `dest`
+/// is overwritten.
+///
+/// # Parameters
+/// - `src`: File to copy.
+/// - `dest`: Destination path of the copy.
+/// - `options`: Sparse and reflink handling modes.
+///
+/// # Returns
+/// - The logical number of bytes copied.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read or the copy cannot be written.
+/// - With `ReflinkMode::Always`, will return an error if the file cannot be cloned.
+pub fn copy_file(src: &Path, dest: &Path, options: &CopyOptions) -> std::io::Result<u64> {
+    let metadata = fs::metadata(src)?;
+    let copied = copy_file_data(src, dest, &metadata, options)?;
+
+    // Keep the source mtime so snapshots can be compared by size and modification time
+    set_mtime(dest, &metadata)?;
+    Ok(copied)
+}
+
+/// Checks whether an error is worth retrying: interruptions, timeouts and would-block errors,
+/// plus any OS error code listed in `options.retry_os_errors`.
+fn is_transient_error(options: &CopyOptions, error: &Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock
+    ) || error
+        .raw_os_error()
+        .is_some_and(|code| options.retry_os_errors.contains(&code))
+}
+
+/// Runs `operation`, retrying it with exponential backoff while it fails with a transient error.
+///
+/// The operation must start over from scratch on every attempt, since partially written state
+/// cannot be trusted after a failure. Once `walk.cancel` is set, failures are no longer retried:
+/// an interrupted system call counts as transient, but after Ctrl-C it must end the run.
+///
+/// # Parameters
+/// - `path`: Entry the operation works on, used in the retry warnings.
+/// - `options`: Number of retries, initial delay and extra transient error codes.
+/// - `walk`: Retry counters are updated in its statistics, and its sink receives a warning for
+///   every retry.
+/// - `operation`: The I/O operation to run.
+///
+/// # Errors
+/// - Returns the last error once the retries are used up or the walk was cancelled, or the
+///   first non-transient error.
+fn with_retries<T>(
+    path: &Path,
+    options: &CopyOptions,
+    walk: &mut Walk,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let cancelled = |walk: &Walk| walk.cancel.load(Ordering::Relaxed);
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e)
+                if attempt < options.retries
+                    && is_transient_error(options, &e)
+                    && !cancelled(walk) =>
+            {
+                if attempt == 0 {
+                    walk.stats.files_retried += 1;
+                }
+                walk.stats.retry_attempts += 1;
+                walk.sink.emit(&Event::Warning {
+                    message: format!(
+                        "retrying {:?} after error: {} (attempt {} of {})",
+                        path,
+                        e,
+                        attempt + 1,
+                        options.retries
+                    ),
+                });
+                std::thread::sleep(options.retry_delay.saturating_mul(1 << attempt.min(16)));
+                if cancelled(walk) {
+                    return Err(e);
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Gives `dest` the modification time recorded in `metadata`.
+#[cfg(not(windows))]
+fn set_mtime(dest: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    filetime::set_file_mtime(dest, FileTime::from_last_modification_time(metadata))
+}
+
+/// Gives `dest` the modification time recorded in `metadata`. Only attribute access is
+/// requested, so this also works on files whose read-only attribute was already copied.
+#[cfg(windows)]
+fn set_mtime(dest: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_BACKUP_SEMANTICS, FILE_WRITE_ATTRIBUTES,
+    };
+
+    let file = File::options()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(dest)?;
+    file.set_modified(metadata.modified()?)
+}
+
+/// Writes the contents and permissions of `src` to `dest` using the modes from `options`.
+fn copy_file_data(
+    src: &Path,
+    dest: &Path,
+    metadata: &fs::Metadata,
+    options: &CopyOptions,
+) -> std::io::Result<u64> {
+    if options.reflink != ReflinkMode::Never {
+        match reflink_file(src, dest) {
+            Ok(()) => {
+                fs::set_permissions(dest, metadata.permissions())?;
+                return Ok(metadata.len());
+            }
+            Err(e) if options.reflink == ReflinkMode::Always => return Err(e),
+            Err(_) => {} // Fall back to copying the data
+        }
+    }
+
+    let write_sparse = match options.sparse {
+        SparseMode::Auto => is_sparse(metadata),
+        SparseMode::Always => true,
+        SparseMode::Never => false,
+    };
+    if !write_sparse {
+        return fs::copy(src, dest);
+    }
+
+    let mut reader = File::open(src)?;
+    let mut writer = File::create(dest)?;
+    let length = reader.metadata()?.len();
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    // Holes reported by the filesystem are skipped without reading them at all
+    for (start, end) in data_regions(&reader, length) {
+        reader.seek(SeekFrom::Start(start))?;
+        writer.seek(SeekFrom::Start(start))?;
+        let mut region = (&mut reader).take(end - start);
+        loop {
+            let read = region.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            for block in buffer[..read].chunks(SPARSE_BLOCK_SIZE) {
+                if block.iter().all(|&byte| byte == 0) {
+                    // Leave a hole instead of writing zeros
+                    writer.seek(SeekFrom::Current(block.len() as i64))?;
+                } else {
+                    writer.write_all(block)?;
+                }
+            }
+        }
+    }
+
+    // Extend the file to its full length in case it ends with a hole
+    writer.set_len(length)?;
+    fs::set_permissions(dest, metadata.permissions())?;
+    Ok(length)
+}
+
+/// Lists the `(start, end)` byte ranges of `file` that hold data, using `SEEK_DATA`/`SEEK_HOLE`.
+///
+/// Falls back to a single range covering the whole file when the filesystem cannot report holes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn data_regions(file: &File, length: u64) -> Vec<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < length {
+        // SAFETY: `fd` is a valid open file for the duration of the call
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start == -1 {
+            // ENXIO means there is no more data after `offset`, only a trailing hole
+            if Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return vec![(0, length)];
+        }
+        // SAFETY: as above
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end == -1 {
+            return vec![(0, length)];
+        }
+        regions.push((start as u64, (end as u64).min(length)));
+        offset = end as u64;
+    }
+    regions
+}
+
+/// Hole detection is not available, so the whole file is treated as data.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn data_regions(_file: &File, length: u64) -> Vec<(u64, u64)> {
+    vec![(0, length)]
+}
+
+/// Device names that Windows reserves in every directory, with or without an extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that Windows does not allow in file names, besides control characters.
+const WINDOWS_FORBIDDEN_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Checks whether a file name cannot be created on Windows: reserved device names such as
+/// `aux` or `con.txt` (case-insensitive), names ending in a dot or a space, and names
+/// containing a control character or one of `<>:"/\|?*`.
+///
+/// # Parameters
+/// - `name`: A single path component.
+///
+/// # Returns
+/// - `true` if Windows would refuse or silently alter the name.
+pub fn is_invalid_windows_name(name: &str) -> bool {
+    if name.ends_with('.') || name.ends_with(' ') {
+        return true;
+    }
+    if name
+        .chars()
+        .any(|c| c.is_ascii_control() || WINDOWS_FORBIDDEN_CHARS.contains(&c))
+    {
+        return true;
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+/// Recreates a FIFO, socket or device node at `dest` with the type, permissions and device
+/// number of `src`. Device nodes require root privileges.
+#[cfg(unix)]
+fn copy_special_file(dest: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let path = CString::new(dest.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is a valid NUL-terminated string for the duration of the call
+    let result = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )
+    };
+    if result == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Special files only exist on Unix.
+#[cfg(not(unix))]
+fn copy_special_file(_dest: &Path, _metadata: &fs::Metadata) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Special files are not supported on this platform",
+    ))
+}
+
+/// Checks whether the process runs with root privileges, i.e. can change file ownership.
+#[cfg(unix)]
+pub fn running_as_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Ownership is not preserved on this platform.
+#[cfg(not(unix))]
+pub fn running_as_root() -> bool {
+    false
+}
+
+/// Gives `dest` the uid and gid of the source described by `metadata`.
+#[cfg(unix)]
+fn copy_owner(dest: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    std::os::unix::fs::chown(dest, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+/// Unix ownership does not exist on this platform.
+#[cfg(not(unix))]
+fn copy_owner(_dest: &Path, _metadata: &fs::Metadata) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Copies the owner, group and access control list of `src` to `dest`.
+///
+/// Setting the owner requires the restore privilege (an elevated process), so without it only
+/// the access control list is copied.
+#[cfg(windows)]
+fn copy_acl(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+    use windows_sys::Win32::Foundation::{LocalFree, ERROR_SUCCESS};
+    use windows_sys::Win32::Security::Authorization::{
+        GetNamedSecurityInfoW, SetNamedSecurityInfoW, SE_FILE_OBJECT,
+    };
+    use windows_sys::Win32::Security::{
+        DACL_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION, OWNER_SECURITY_INFORMATION,
+    };
+
+    let wide = |path: &Path| -> Vec<u16> { path.as_os_str().encode_wide().chain([0]).collect() };
+    let (src, dest) = (wide(src), wide(dest));
+    let all = OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+
+    let (mut owner, mut group, mut dacl, mut descriptor) =
+        (null_mut(), null_mut(), null_mut(), null_mut());
+    // SAFETY: `src` is NUL-terminated; the returned pointers point into `descriptor`
+    let status = unsafe {
+        GetNamedSecurityInfoW(
+            src.as_ptr(),
+            SE_FILE_OBJECT,
+            all,
+            &mut owner,
+            &mut group,
+            &mut dacl,
+            null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return Err(Error::from_raw_os_error(status as i32));
+    }
+
+    // SAFETY: `dest` is NUL-terminated and the security data stays alive until LocalFree
+    let mut status = unsafe {
+        SetNamedSecurityInfoW(
+            dest.as_ptr(),
+            SE_FILE_OBJECT,
+            all,
+            owner,
+            group,
+            dacl,
+            null_mut(),
+        )
+    };
+    if status != ERROR_SUCCESS {
+        // SAFETY: as above
+        status = unsafe {
+            SetNamedSecurityInfoW(
+                dest.as_ptr(),
+                SE_FILE_OBJECT,
+                DACL_SECURITY_INFORMATION,
+                null_mut(),
+                null_mut(),
+                dacl,
+                null_mut(),
+            )
+        };
+    }
+    // SAFETY: `descriptor` was allocated by GetNamedSecurityInfoW
+    unsafe { LocalFree(descriptor) };
+
+    if status != ERROR_SUCCESS {
+        return Err(Error::from_raw_os_error(status as i32));
+    }
+    Ok(())
+}
+
+/// Access control lists are only copied on Windows.
+#[cfg(not(windows))]
+fn copy_acl(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Applies the source owner (`options.preserve_owner`) and access control list
+/// (`options.preserve_acls`) to `dest`.
+///
+/// Failures (typically when not running as root) are counted in `stats`; only the first one
+/// is reported, so an unprivileged run does not print a warning for every file.
+fn preserve_owner(
+    src: &Path,
+    dest: &Path,
+    metadata: &fs::Metadata,
+    options: &CopyOptions,
+    stats: &mut BackupStats,
+    sink: &dyn EventSink,
+) {
+    let mut result = Ok(());
+    if options.preserve_owner {
+        result = copy_owner(dest, metadata);
+    }
+    if options.preserve_acls {
+        result = result.and_then(|()| copy_acl(src, dest));
+    }
+
+    if let Err(e) = result {
+        if stats.owner_errors == 0 {
+            sink.emit(&Event::Warning {
+                message: format!(
+                    "cannot preserve the owner of {:?}: {} (further failures are only counted)",
+                    dest, e
+                ),
+            });
+        }
+        stats.owner_errors += 1;
+    }
+}
+
+/// Computes the SHA-256 digest of a file's contents.
+///
+/// The file is streamed, so a file that is truncated or changed while it is hashed yields an
+/// error or a digest of neither version, never a crash. Source files and anything else that
+/// other programs may be writing to are hashed this way.
+///
+/// # Parameters
+/// - `path`: File to hash.
+///
+/// # Returns
+/// - The digest as a lowercase hexadecimal string.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read.
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Computes the SHA-256 digest of a copy inside the backup, like `hash_file`.
+///
+/// Copies of at least `MMAP_HASH_THRESHOLD` bytes are memory-mapped and hashed in one pass,
+/// which saves a read call per chunk; smaller copies, and copies that cannot be mapped, are
+/// streamed. A mapped file truncated while it is hashed crashes the process (`SIGBUS` on Unix),
+/// so only files that snapshotter itself writes may be passed here, never source files.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read.
+fn hash_copy(path: &Path) -> std::io::Result<String> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() >= MMAP_HASH_THRESHOLD {
+        // SAFETY: the mapping is only read while it is alive, and the backup is not changed
+        // by anyone else while snapshotter runs
+        if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+            return Ok(format!("{:x}", Sha256::digest(&map[..])));
+        }
+    }
+    hash_file(path)
+}
+
+/// Returns the ID of the device holding an entry (`st_dev`).
+#[cfg(unix)]
+pub fn device_id(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+/// Device IDs are not available; mount points are detected as reparse points instead.
+#[cfg(not(unix))]
+pub fn device_id(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Checks whether a directory lies on another filesystem than the source root.
+#[cfg(unix)]
+fn is_other_filesystem(options: &CopyOptions, _path: &Path, metadata: &fs::Metadata) -> bool {
+    options
+        .one_file_system
+        .is_some_and(|root_device| device_id(metadata) != root_device)
+}
+
+/// Checks whether a directory is a reparse point such as a mounted volume or a junction.
+#[cfg(windows)]
+fn is_other_filesystem(options: &CopyOptions, path: &Path, _metadata: &fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+    options.one_file_system.is_some()
+        && fs::symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0)
+}
+
+/// Mount points cannot be detected on this platform.
+#[cfg(not(any(unix, windows)))]
+fn is_other_filesystem(_options: &CopyOptions, _path: &Path, _metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Gives a copied directory the permissions and modification time of its source.
+///
+/// Must run after the directory's contents were written, since creating entries updates its
+/// modification time. On Windows only the modification time is copied: the read-only attribute
+/// has no effect on a directory's contents and would keep it from being deleted.
+pub fn copy_dir_metadata(dest: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    if cfg!(unix) {
+        fs::set_permissions(dest, metadata.permissions())?;
+    }
+    set_mtime(dest, metadata)
+}
+
+/// Lets the owner write to an existing directory again, so an update can change a directory
+/// that an earlier run gave read-only permissions.
+#[cfg(unix)]
+pub fn make_dir_writable(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(dir)?.permissions();
+    if permissions.mode() & 0o200 == 0 {
+        permissions.set_mode(permissions.mode() | 0o200);
+        fs::set_permissions(dir, permissions)?;
+    }
+    Ok(())
+}
+
+/// Directory permissions are not copied on this platform, so directories stay writable.
+#[cfg(not(unix))]
+pub fn make_dir_writable(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Checks whether `dest` already holds an up-to-date copy of the file described by `metadata`.
+fn is_unchanged(dest: &Path, metadata: &fs::Metadata) -> bool {
+    fs::metadata(dest).is_ok_and(|existing| {
+        existing.is_file()
+            && existing.len() == metadata.len()
+            && existing.modified().ok() == metadata.modified().ok()
+    })
+}
+
+/// Checks whether `dest` already holds an up-to-date copy of the file `src`, like
+/// `is_unchanged`. With `options.paranoid`, a copy that passes that check must also have the
+/// same SHA-256 digest as `src`; one that does not is reported, counted and copied again.
+///
+/// # Errors
+/// - With `options.paranoid`, will return an error if either file cannot be read.
+fn is_current_copy(
+    src: &Path,
+    dest: &Path,
+    metadata: &fs::Metadata,
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<bool> {
+    if !is_unchanged(dest, metadata) {
+        return Ok(false);
+    }
+    if !options.paranoid || hash_file(src)? == hash_copy(dest)? {
+        return Ok(true);
+    }
+    walk.stats.files_mismatched += 1;
+    walk.sink.emit(&Event::Warning {
+        message: format!(
+            "the copy of {:?} differs from the source although its size and modification time match; copying it again",
+            src
+        ),
+    });
+    Ok(false)
+}
+
+/// Removes whatever is at `dest` so a new entry can be created there. A directory is kept
+/// when `keep_dir` is set, so its contents can be updated.
+fn clear_destination(dest: &Path, keep_dir: bool) -> std::io::Result<()> {
+    match fs::symlink_metadata(dest) {
+        Ok(existing) if existing.is_dir() && !keep_dir => fs::remove_dir_all(dest),
+        Ok(existing) if !existing.is_dir() => fs::remove_file(dest),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compares an extension against an excluded extension.
+fn extension_matches(options: &CopyOptions, excluded: &str, extension: &str) -> bool {
+    if options.case_insensitive {
+        excluded.eq_ignore_ascii_case(extension)
+    } else {
+        excluded == extension
+    }
+}
+
+/// Checks whether an entry is excluded by name or path, before looking at its metadata.
+///
+/// # Parameters
+/// - `root`: Source root the exclusion patterns are relative to.
+/// - `path`: The entry, below `root`.
+/// - `file_name`: Name of the entry.
+/// - `is_dir`: Tells whether the entry is a directory; only called when a directory-only
+///   pattern matches its path.
+pub fn is_excluded_name(
+    options: &CopyOptions,
+    root: &Path,
+    path: &Path,
+    file_name: &str,
+    is_dir: impl Fn() -> bool,
+) -> bool {
+    let matches_pattern = || {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        options
+            .exclude_list
+            .iter()
+            .any(|pattern| pattern.matches(&relative, &is_dir, options.case_insensitive))
+    };
+    (!options.exclude_list.is_empty() && matches_pattern())
+        || options
+            .excluded_paths
+            .iter()
+            .any(|excluded| excluded == path)
+        || (options.skip_hidden && is_hidden(path, file_name))
+}
+
+/// Checks whether an entry is hidden: its name starts with a dot.
+#[cfg(not(windows))]
+fn is_hidden(_path: &Path, file_name: &str) -> bool {
+    file_name.starts_with('.')
+}
+
+/// Checks whether an entry is hidden: it carries the `FILE_ATTRIBUTE_HIDDEN` attribute.
+#[cfg(windows)]
+fn is_hidden(path: &Path, _file_name: &str) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+    fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+/// Checks whether a file is excluded because of its extension.
+///
+/// Every suffix after a dot is tested, so `backup.tar.gz` is excluded by both `gz` and `tar.gz`.
+/// A leading dot (as in `.bashrc`) does not start an extension.
+pub fn has_excluded_extension(options: &CopyOptions, path: &Path) -> bool {
+    let Some(file_name) = path.file_name() else {
+        return false;
+    };
+    let file_name = file_name.to_string_lossy();
+    let name = file_name.strip_prefix('.').unwrap_or(&file_name);
+
+    name.match_indices('.').any(|(dot, _)| {
+        let suffix = &name[dot + 1..];
+        options
+            .exclude_extensions
+            .iter()
+            .any(|excluded| extension_matches(options, excluded, suffix))
+    })
+}
+
+/// Checks whether a regular file's content type, sniffed from its first bytes, is excluded.
+///
+/// Files whose type cannot be recognized or read are never excluded; the copy reports read errors.
+pub fn has_excluded_mime_type(options: &CopyOptions, path: &Path) -> bool {
+    if options.exclude_mime_types.is_empty() {
+        return false;
+    }
+    let Ok(Some(kind)) = infer::get_from_path(path) else {
+        return false;
+    };
+    let mime = kind.mime_type();
+    let category = mime.split('/').next().unwrap_or(mime);
+
+    options.exclude_mime_types.iter().any(|excluded| {
+        let excluded = excluded.strip_suffix("/*").unwrap_or(excluded);
+        excluded.eq_ignore_ascii_case(mime) || excluded.eq_ignore_ascii_case(category)
+    })
+}
+
+/// Sums the sizes of the files `copy_directory` would copy from `src`, so the space a backup
+/// needs can be checked up front.
+///
+/// The estimate is a dry run of the copy itself, so exclusions, `--max-depth`, the size budget
+/// and every other rule apply exactly as they will. Duplicates are counted in full and file
+/// contents are never compared, which keeps the estimate cheap and on the safe side.
+///
+/// # Parameters
+/// - `src`: Source directory.
+/// - `dest`: Destination the backup will be written to; nothing is created there.
+/// - `options`: The options of the backup.
+///
+/// # Returns
+/// - The number of bytes the backup is expected to copy.
+///
+/// # Errors
+/// - Will return an error if the source directory cannot be read.
+pub fn estimate_size(src: &Path, dest: &Path, options: &CopyOptions) -> std::io::Result<u64> {
+    let options = CopyOptions {
+        dry_run: true,
+        dedup: false,
+        paranoid: false,
+        retries: 0,
+        ..options.clone()
+    };
+    let mut stats = BackupStats::default();
+    copy_directory(
+        src,
+        dest,
+        &options,
+        &mut stats,
+        &AtomicBool::new(false),
+        &|_: &Event| {},
+    )?;
+    Ok(stats.bytes_copied)
+}
 
 /// Recursively copies a directory while excluding specific items and file extensions.
 ///
 /// # Parameters
-/// - `src`: Source directory path to be copied.
-/// - `dest`: Destination directory where the contents will be copied.
-/// - `exclude_list`: A list of items (files or directories) to exclude from copying.
-/// - `exclude_extensions`: A list of file extensions to exclude from copying.
+/// - `src`: Source directory path to be copied.
+/// - `dest`: Destination directory where the contents will be copied.
+/// - `options`: Exclusion settings applied to every entry.
+/// - `stats`: Counters updated with every copied or skipped item.
+/// - `cancel`: Flag checked before each entry; once set, copying stops.
+/// - `sink`: Receives an event for every directory entered and every file copied or skipped.
+///
+/// # Returns
+/// - `std::io::Result<()>`: Returns an empty `Ok(())` if successful.
+///
+/// An entry that cannot be read or copied is reported as an error event and counted in
+/// `stats.files_failed`, and copying continues with the next entry. With `options.files_from`,
+/// only the listed entries are copied, the same way as if the walk had reached them.
+///
+/// # Errors
+/// - Will return an error if `src` cannot be read or the target filesystem is full.
+/// - Will return an error of kind `ErrorKind::Interrupted` if `cancel` was set.
+///
+/// # Panics
+/// - The function does not panic unless the caller unwraps a potential error result.
+pub fn copy_directory(
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+    stats: &mut BackupStats,
+    cancel: &AtomicBool,
+    sink: &dyn EventSink,
+) -> std::io::Result<()> {
+    let root = fs::metadata(src)?;
+    let mut walk = Walk {
+        root: src,
+        stats,
+        cancel,
+        sink,
+        dedup_index: DedupIndex::default(),
+        ancestors: vec![dir_id(src, &root)],
+    };
+    match &options.files_from {
+        Some(list) => copy_listed(src, dest, list, options, &mut walk),
+        None => copy_tree(src, dest, options, &mut walk),
+    }
+}
+
+/// Copies a directory with the default options, like `copy_directory` with nothing excluded.
+///
+/// # Parameters
+/// - `src`: Source directory path to be copied.
+/// - `dest`: Destination directory; it is created if it does not exist.
+///
+/// # Returns
+/// - The counters of the copy.
+///
+/// # Errors
+/// - Will return an error if `dest` cannot be created or `src` cannot be read.
+pub fn copy_directory_simple(src: &Path, dest: &Path) -> std::io::Result<BackupStats> {
+    fs::create_dir_all(dest)?;
+    let mut stats = BackupStats::default();
+    copy_directory(
+        src,
+        dest,
+        &CopyOptions::default(),
+        &mut stats,
+        &AtomicBool::new(false),
+        &|_: &Event| {},
+    )?;
+    Ok(stats)
+}
+
+/// State carried through one walk of a source tree by `copy_directory`.
+struct Walk<'a> {
+    /// The source root, which exclusion patterns are relative to.
+    root: &'a Path,
+    stats: &'a mut BackupStats,
+    cancel: &'a AtomicBool,
+    sink: &'a dyn EventSink,
+    /// Files copied so far, used when `options.dedup` is set.
+    dedup_index: DedupIndex,
+    /// The directories from the source root down to the one being copied. Its length is the
+    /// depth of the entries being copied plus one.
+    ancestors: Vec<DirId>,
+}
+
+/// Copies the subdirectory `src`, identified by `id`, into `dest` one level deeper.
+fn descend(
+    src: &Path,
+    dest: &Path,
+    id: DirId,
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<()> {
+    walk.ancestors.push(id);
+    let result = copy_tree(src, dest, options, walk);
+    walk.ancestors.pop();
+    result
+}
+
+/// Copies the entries of `src` into `dest`; see `copy_directory`.
+fn copy_tree(
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<()> {
+    walk.sink.emit(&Event::DirEntered { path: src });
+
+    // A flattened backup finds free names on its own, by looking at the destination
+    let mut names = options
+        .case_collisions
+        .filter(|_| !options.flatten)
+        .map(|mode| (mode, FoldedNames::default()));
+    for entry in fs::read_dir(src)? {
+        // Stop before touching the next entry once cancellation was requested
+        if walk.cancel.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::Interrupted, "Backup cancelled"));
+        }
+
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let mut dest_name = file_name.clone();
+        if let Some((mode, names)) = &mut names {
+            if let Some(earlier) = names.insert(&file_name) {
+                let earlier = earlier.clone();
+                if *mode == CaseCollisions::Fail {
+                    let error = Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!(
+                            "the name differs only in case from {:?}, which the target cannot tell apart (see --rename-collisions)",
+                            earlier
+                        ),
+                    );
+                    report_failure(&path, error, options, walk)?;
+                    continue;
+                }
+                dest_name = names.rename(&file_name);
+                walk.stats.case_collisions_renamed += 1;
+                walk.sink.emit(&Event::Warning {
+                    message: format!(
+                        "copying {:?} as {:?}: the name differs only in case from {:?}",
+                        path, dest_name, earlier
+                    ),
+                });
+            }
+        }
+        if let Err(e) = copy_entry(&path, &dest_name, dest, options, walk) {
+            report_failure(&path, e, options, walk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the entries of `list`, relative to `src`, into the same places below `dest`; see
+/// `copy_directory`. Their parent directories are created as needed.
+fn copy_listed(
+    src: &Path,
+    dest: &Path,
+    list: &[PathBuf],
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<()> {
+    walk.sink.emit(&Event::DirEntered { path: src });
+
+    for relative in list {
+        if walk.cancel.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::Interrupted, "Backup cancelled"));
+        }
+
+        // Every entry starts again from the source root
+        walk.ancestors.truncate(1);
+        if let Err(e) = copy_listed_entry(src, dest, relative, options, walk) {
+            report_failure(&src.join(relative), e, options, walk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the entry at `relative` below `src` into `dest`, after checking its parent
+/// directories against the exclusions and `--max-depth` like a walk would.
+fn copy_listed_entry(
+    src: &Path,
+    dest: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<()> {
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "listed paths must be relative to the source directory and stay inside it",
+                ))
+            }
+        }
+    }
+    let Some((file_name, parents)) = names.split_last() else {
+        return Err(Error::new(ErrorKind::InvalidInput, "listed path is empty"));
+    };
+
+    let listed = src.join(relative);
+    let skip = |reason| Event::FileSkipped {
+        path: &listed,
+        reason,
+    };
+    let mut src_dir = src.to_path_buf();
+    let mut dest_dir = dest.to_path_buf();
+    for parent in parents {
+        src_dir.push(parent);
+        if is_excluded_name(options, src, &src_dir, &parent.to_string_lossy(), || true) {
+            walk.stats.files_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::Excluded));
+            return Ok(());
+        }
+        if options
+            .max_depth
+            .is_some_and(|max_depth| walk.ancestors.len() > max_depth)
+        {
+            walk.stats.dirs_too_deep += 1;
+            walk.sink.emit(&skip(SkipReason::TooDeep));
+            return Ok(());
+        }
+        walk.ancestors
+            .push(dir_id(&src_dir, &fs::metadata(&src_dir)?));
+        if !options.flatten {
+            dest_dir.push(parent);
+        }
+    }
+
+    // A listed entry that does not exist is a mistake in the list, not a file that vanished
+    fs::symlink_metadata(&listed)?;
+    if !options.dry_run {
+        fs::create_dir_all(&dest_dir)?;
+    }
+    copy_entry(
+        &src_dir.join(file_name),
+        file_name,
+        &dest_dir,
+        options,
+        walk,
+    )
+}
+
+/// Finds the entries below `root` matched by any of `patterns`, for use as
+/// `CopyOptions::files_from`.
+///
+/// Patterns are matched against paths relative to `root` like exclusions are. A matched
+/// directory is listed without the entries below it, which are still checked so that every
+/// pattern matching them is marked as used. Symbolic links are not followed.
+///
+/// # Parameters
+/// - `root`: Directory to search.
+/// - `patterns`: Patterns selecting entries.
+/// - `case_insensitive`: Match regardless of case.
 ///
 /// # Returns
-/// - `std::io::Result<()>`: Returns an empty `Ok(())` if successful.
+/// - The relative paths of the matched entries in sorted order, and whether each pattern
+///   matched anything.
+///
+/// # Errors
+/// - Will return an error if a directory below `root` cannot be read.
+pub fn find_matching(
+    root: &Path,
+    patterns: &[Pattern],
+    case_insensitive: bool,
+) -> std::io::Result<(Vec<PathBuf>, Vec<bool>)> {
+    fn visit(
+        root: &Path,
+        dir: &Path,
+        patterns: &[Pattern],
+        case_insensitive: bool,
+        inside_match: bool,
+        found: &mut (Vec<PathBuf>, Vec<bool>),
+    ) -> std::io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let relative_str = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let is_dir = entry.file_type()?.is_dir();
+            let mut matched = false;
+            for (pattern, used) in patterns.iter().zip(&mut found.1) {
+                if pattern.matches(&relative_str, || is_dir, case_insensitive) {
+                    *used = true;
+                    matched = true;
+                }
+            }
+            if matched && !inside_match {
+                found.0.push(relative.to_path_buf());
+            }
+            if is_dir {
+                visit(
+                    root,
+                    &path,
+                    patterns,
+                    case_insensitive,
+                    inside_match || matched,
+                    found,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = (Vec::new(), vec![false; patterns.len()]);
+    visit(root, root, patterns, case_insensitive, false, &mut found)?;
+    Ok(found)
+}
+
+/// Reads the list of entries for `--files-from` (or `--files-from0` when `nul_separated` is
+/// set) from the file at `path`, or from standard input if it is `-`.
 ///
 /// # Errors
-/// - Will return an error if any file or directory operations fail during copying.
+/// - Will return an error if the list cannot be read, or on Windows if a path in it is not
+///   valid UTF-8.
+pub fn read_file_list(path: &str, nul_separated: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut contents = Vec::new();
+    if path == "-" {
+        std::io::stdin().read_to_end(&mut contents)?;
+    } else {
+        File::open(path)?.read_to_end(&mut contents)?;
+    }
+    parse_file_list(&contents, nul_separated)
+}
+
+/// Splits a file list into paths: one per line, or NUL-terminated when `nul_separated` is set.
+/// Empty entries are ignored, as are the carriage returns of Windows line endings.
+pub fn parse_file_list(contents: &[u8], nul_separated: bool) -> std::io::Result<Vec<PathBuf>> {
+    let separator = if nul_separated { b'\0' } else { b'\n' };
+    contents
+        .split(|&byte| byte == separator)
+        .map(|entry| match entry {
+            [rest @ .., b'\r'] if !nul_separated => rest,
+            _ => entry,
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+/// Converts the raw bytes of a listed path into a path.
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> std::io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+/// Converts the raw bytes of a listed path into a path.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> std::io::Result<PathBuf> {
+    String::from_utf8(bytes.to_vec())
+        .map(PathBuf::from)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid path in file list: {}", e),
+            )
+        })
+}
+
+/// Checks whether `error` happened because `path` was deleted after it was listed. A dangling
+/// symbolic link fails with the same error, but still exists itself.
+fn has_vanished(path: &Path, error: &Error) -> bool {
+    error.kind() == ErrorKind::NotFound && fs::symlink_metadata(path).is_err()
+}
+
+/// Checks whether `error` happened because the process may not read the file `path`, rather
+/// than because the copy could not be written.
+fn is_unreadable(path: &Path, error: &Error) -> bool {
+    error.kind() == ErrorKind::PermissionDenied
+        && fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file())
+        && File::open(path).is_err_and(|e| e.kind() == ErrorKind::PermissionDenied)
+}
+
+/// Reports an entry that could not be copied and counts it in `stats.files_failed`. Files the
+/// process may not read are recorded in `stats.files_unreadable` and, unless
+/// `fail_on_unreadable` is set, only skipped with a warning.
 ///
-/// # Panics
-/// - The function does not panic unless the caller unwraps a potential error result.
-pub fn copy_directory(
-    src: &Path,
+/// # Errors
+/// - Will return `error` itself if it ends the run: cancellation and a full target would fail
+///   every remaining entry as well.
+fn report_failure(
+    path: &Path,
+    error: Error,
+    options: &CopyOptions,
+    walk: &mut Walk,
+) -> std::io::Result<()> {
+    if walk.cancel.load(Ordering::Relaxed) || error.kind() == ErrorKind::StorageFull {
+        return Err(error);
+    }
+    if is_unreadable(path, &error) {
+        walk.stats.files_unreadable.push(path.to_path_buf());
+        if !options.fail_on_unreadable {
+            walk.sink.emit(&Event::FileSkipped {
+                path,
+                reason: SkipReason::Unreadable,
+            });
+            return Ok(());
+        }
+    }
+    // One unreadable or vanished entry must not keep its siblings from being copied
+    walk.stats.files_failed += 1;
+    walk.sink.emit(&Event::Error {
+        message: format!("Failed to copy {:?}: {}", path, error),
+    });
+    Ok(())
+}
+
+/// Copies the entry `src_item` into `dest` under the name `dest_name` (recursively, if it is a
+/// directory).
+///
+/// # Errors
+/// - Will return an error if the entry cannot be read or copied. Errors of entries inside a
+///   subdirectory are reported and counted by the nested `copy_tree` instead.
+fn copy_entry(
+    src_item: &Path,
+    dest_name: &OsStr,
     dest: &Path,
-    exclude_list: &[String],
-    exclude_extensions: &[String],
+    options: &CopyOptions,
+    walk: &mut Walk,
 ) -> std::io::Result<()> {
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let file_name = entry.file_name();
-        let src_item = entry.path();
+    let file_name = src_item.file_name().unwrap_or(dest_name);
+    let skip = |reason| Event::FileSkipped {
+        path: src_item,
+        reason,
+    };
+
+    // Skip excluded directories or files based on the exclude list
+    let file_name_str = file_name.to_string_lossy().to_string();
+    let is_dir = || fs::metadata(src_item).is_ok_and(|metadata| metadata.is_dir());
+    if is_excluded_name(options, walk.root, src_item, &file_name_str, is_dir) {
+        walk.stats.files_skipped += 1;
+        walk.sink.emit(&skip(SkipReason::Excluded));
+        return Ok(()); // Skip this item, as it's in the exclude list
+    }
+
+    // Windows can't create reserved device names or forbidden characters, so report them
+    // instead of failing the run
+    if cfg!(windows) && is_invalid_windows_name(&file_name_str) {
+        walk.stats.invalid_names_skipped += 1;
+        walk.sink.emit(&skip(SkipReason::InvalidName));
+        return Ok(());
+    }
+
+    let dest_item = dest.join(dest_name);
+    // Symbolic links are followed, so linked files and directories are copied
+    let metadata = match with_retries(src_item, options, walk, || fs::metadata(src_item)) {
+        Err(e) if has_vanished(src_item, &e) => {
+            walk.stats.files_vanished += 1;
+            walk.sink.emit(&skip(SkipReason::Vanished));
+            return Ok(());
+        }
+        result => result?,
+    };
+
+    if metadata.is_dir() {
+        // Mount points such as /proc or network shares are left out with --one-file-system
+        if is_other_filesystem(options, src_item, &metadata) {
+            walk.stats.mount_points_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::OtherFilesystem));
+            return Ok(());
+        }
 
-        // Skip excluded directories or files based on the exclude list
-        let file_name_str = file_name.to_string_lossy().to_string();
-        if exclude_list.contains(&file_name_str) {
-            continue; // Skip this item, as it's in the exclude list
+        // Symbolic links are followed, so a link to an ancestor would nest the tree endlessly
+        let id = dir_id(src_item, &metadata);
+        if walk.ancestors.contains(&id) {
+            walk.stats.cycles_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::Cycle));
+            return Ok(());
+        }
+        // Directories at depth `max_depth` are pruned along with everything below them
+        if options
+            .max_depth
+            .is_some_and(|max_depth| walk.ancestors.len() > max_depth)
+        {
+            walk.stats.dirs_too_deep += 1;
+            walk.sink.emit(&skip(SkipReason::TooDeep));
+            return Ok(());
         }
 
-        let dest_item = dest.join(&file_name);
+        // A flattened backup has no subdirectories: their files go straight into `dest`
+        if options.flatten {
+            return descend(src_item, dest, id, options, walk);
+        }
 
-        if src_item.is_dir() {
-            // Recursively copy directories
+        // Recursively copy directories
+        if !options.dry_run {
+            if options.update_in_place {
+                clear_destination(&dest_item, true)?;
+            }
             fs::create_dir_all(&dest_item)?;
-            copy_directory(&src_item, &dest_item, exclude_list, exclude_extensions)?;
+            if options.update_in_place {
+                make_dir_writable(&dest_item)?;
+            }
+            preserve_owner(
+                src_item, &dest_item, &metadata, options, walk.stats, walk.sink,
+            );
+        }
+        let files_kept =
+            |stats: &BackupStats| stats.files_copied + stats.files_unchanged + stats.files_resumed;
+        let files_before = files_kept(walk.stats);
+        descend(src_item, &dest_item, id, options, walk)?;
+        if options.dry_run {
+            return Ok(());
+        }
+
+        if options.no_empty_dirs && files_kept(walk.stats) == files_before {
+            // A mirror directory may still hold excluded files, which are kept along with it
+            match fs::remove_dir(&dest_item) {
+                Err(e) if e.kind() != ErrorKind::DirectoryNotEmpty => return Err(e),
+                _ => return Ok(()),
+            }
+        }
+        // The directory's contents are complete, so its modification time won't change again
+        if let Err(e) = copy_dir_metadata(&dest_item, &metadata) {
+            walk.sink.emit(&Event::Warning {
+                message: format!(
+                    "cannot copy the permissions and modification time of {:?}: {}",
+                    src_item, e
+                ),
+            });
+        }
+    } else {
+        let dest_item = if options.flatten {
+            flat_destination(dest, file_name)
         } else {
-            // Check file extension and skip if it's in the exclude_extensions list
-            if let Some(extension) = src_item.extension() {
-                let extension_str = extension.to_string_lossy().to_string();
-                if exclude_extensions.contains(&extension_str) {
-                    // Skip the file if its extension is in the exclude list
-                    continue;
+            dest_item
+        };
+
+        // Check file extension and skip if it's in the exclude_extensions list
+        if has_excluded_extension(options, src_item) {
+            walk.stats.files_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::Excluded));
+            return Ok(());
+        }
+
+        // Only files are filtered by owner; a directory of root may hold files of the user
+        if has_other_owner(options, &metadata) {
+            walk.stats.files_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::OtherOwner));
+            return Ok(());
+        }
+
+        if options.already_copied.contains(src_item)
+            && is_current_copy(src_item, &dest_item, &metadata, options, walk)?
+        {
+            walk.stats.files_resumed += 1;
+            return Ok(());
+        }
+
+        if options.update_in_place {
+            if metadata.is_file()
+                && is_current_copy(src_item, &dest_item, &metadata, options, walk)?
+            {
+                walk.stats.files_unchanged += 1;
+                return Ok(());
+            }
+            if !options.dry_run {
+                clear_destination(&dest_item, false)?;
+            }
+        }
+
+        // FIFOs, sockets and device nodes would block or fail when opened for reading
+        if !metadata.is_file() {
+            if options.special_files {
+                let result = if options.dry_run {
+                    Ok(())
+                } else {
+                    copy_special_file(&dest_item, &metadata).map(|()| {
+                        preserve_owner(
+                            src_item, &dest_item, &metadata, options, walk.stats, walk.sink,
+                        )
+                    })
+                };
+                match result {
+                    Ok(()) => {
+                        walk.stats.files_copied += 1;
+                        walk.sink.emit(&Event::FileCopied {
+                            path: src_item,
+                            bytes: 0,
+                        });
+                        return Ok(());
+                    }
+                    Err(e) => walk.sink.emit(&Event::Warning {
+                        message: format!("cannot recreate {:?}: {}", src_item, e),
+                    }),
+                }
+            }
+            walk.stats.special_files_skipped += 1;
+            walk.sink.emit(&skip(SkipReason::SpecialFile));
+            return Ok(());
+        }
+
+        // Sniffing reads the file, so it only happens for files that would otherwise be copied
+        if has_excluded_mime_type(options, src_item) {
+            walk.stats.files_skipped += 1;
+            walk.stats.files_excluded_by_type += 1;
+            walk.sink.emit(&skip(SkipReason::Excluded));
+            return Ok(());
+        }
+
+        // Files identical to one copied earlier are linked to it instead of taking up space again
+        let mut hash = None;
+        if options.dedup && metadata.len() > 0 {
+            match walk.dedup_index.find(src_item, metadata.len())? {
+                Lookup::Duplicate(existing) => {
+                    // Hard links are not supported everywhere; copy the file when linking fails
+                    if options.dry_run || fs::hard_link(&existing, &dest_item).is_ok() {
+                        walk.stats.files_deduplicated += 1;
+                        walk.stats.bytes_deduplicated += metadata.len();
+                        walk.stats.files_copied += 1;
+                        walk.sink.emit(&Event::FileCopied {
+                            path: src_item,
+                            bytes: 0,
+                        });
+                        return Ok(());
+                    }
                 }
+                Lookup::Unique(computed) => hash = computed,
             }
+        }
+
+        // Skip files that no longer fit into the size budget
+        if let Some(max_total_size) = options.max_total_size {
+            if walk.stats.bytes_copied + metadata.len() > max_total_size {
+                walk.stats.files_skipped += 1;
+                walk.stats.files_over_budget += 1;
+                walk.sink.emit(&skip(SkipReason::BudgetExceeded));
+                return Ok(());
+            }
+        }
 
-            // If the file extension is not in the exclude list, copy the file
-            fs::copy(&src_item, &dest_item)?;
+        // If the file extension is not in the exclude list, copy the file
+        let bytes = if options.dry_run {
+            metadata.len()
+        } else {
+            let bytes = match with_retries(src_item, options, walk, || {
+                copy_file(src_item, &dest_item, options)
+            }) {
+                Err(e) if has_vanished(src_item, &e) => {
+                    // Don't leave a partial copy of a file that is no longer part of the source
+                    let _ = fs::remove_file(&dest_item);
+                    walk.stats.files_vanished += 1;
+                    walk.sink.emit(&skip(SkipReason::Vanished));
+                    return Ok(());
+                }
+                result => result?,
+            };
+            preserve_owner(
+                src_item, &dest_item, &metadata, options, walk.stats, walk.sink,
+            );
+            // Files being written to (e.g. logs) are copied as they were at some point during
+            // the copy, which may not be a consistent state
+            if bytes != metadata.len() {
+                walk.stats.files_changed += 1;
+                walk.sink.emit(&Event::Warning {
+                    message: format!(
+                        "{:?} changed during the backup: {} bytes when listed, {} bytes copied",
+                        src_item,
+                        metadata.len(),
+                        bytes
+                    ),
+                });
+            }
+            bytes
+        };
+        if options.dedup && metadata.len() > 0 {
+            walk.dedup_index
+                .insert(src_item, &dest_item, metadata.len(), hash);
         }
+        walk.stats.bytes_copied += bytes;
+        walk.stats.files_copied += 1;
+        walk.sink.emit(&Event::FileCopied {
+            path: src_item,
+            bytes,
+        });
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deletes every other file of the directory once the first file was copied, like a
+    /// cache cleaned up while it is backed up.
+    struct DeleteOthersOnCopy {
+        dir: PathBuf,
+    }
+
+    impl EventSink for DeleteOthersOnCopy {
+        fn emit(&self, event: &Event) {
+            if let Event::FileCopied { path, .. } = event {
+                for entry in fs::read_dir(&self.dir).unwrap() {
+                    let other = entry.unwrap().path();
+                    if other != *path {
+                        fs::remove_file(other).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the path of every event about an entry.
+    #[derive(Default)]
+    struct RecordPaths {
+        paths: std::sync::Mutex<Vec<PathBuf>>,
+    }
+
+    impl EventSink for RecordPaths {
+        fn emit(&self, event: &Event) {
+            let path = match event {
+                Event::DirEntered { path }
+                | Event::FileCopied { path, .. }
+                | Event::FileSkipped { path, .. } => path,
+                _ => return,
+            };
+            self.paths.lock().unwrap().push(path.to_path_buf());
+        }
+    }
+
+    fn extensions(extensions: &[&str], case_insensitive: bool) -> CopyOptions {
+        CopyOptions {
+            exclude_extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            case_insensitive,
+            ..CopyOptions::default()
+        }
+    }
+
+    #[test]
+    fn paths_without_a_file_name_are_not_excluded() {
+        let options = extensions(&["gz", ""], false);
+        assert!(!has_excluded_extension(&options, Path::new("/")));
+        assert!(!has_excluded_extension(&options, Path::new("archive/..")));
+    }
+
+    #[test]
+    fn every_suffix_of_a_multi_dot_name_is_an_extension() {
+        let options = extensions(&["tar.gz"], false);
+        assert!(has_excluded_extension(&options, Path::new("backup.tar.gz")));
+        assert!(!has_excluded_extension(&options, Path::new("backup.gz")));
+        assert!(!has_excluded_extension(
+            &options,
+            Path::new("backup.tar.gz.part")
+        ));
+
+        let options = extensions(&["gz"], false);
+        assert!(has_excluded_extension(&options, Path::new("backup.tar.gz")));
+        assert!(!has_excluded_extension(&options, Path::new("backup.tgz")));
+
+        // A leading dot marks a hidden file, not an extension
+        let options = extensions(&["bashrc"], false);
+        assert!(!has_excluded_extension(&options, Path::new(".bashrc")));
+        assert!(has_excluded_extension(&options, Path::new(".old.bashrc")));
+    }
+
+    #[test]
+    fn extensions_ignore_case_only_when_asked_to() {
+        let path = Path::new("IMG_0001.JPG");
+        assert!(!has_excluded_extension(&extensions(&["jpg"], false), path));
+        assert!(has_excluded_extension(&extensions(&["jpg"], true), path));
+    }
+
+    #[test]
+    fn matched_directories_are_listed_once_and_patterns_below_them_count() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("docs/2024")).unwrap();
+        fs::write(temp.path().join("docs/2024/report.txt"), "").unwrap();
+        fs::write(temp.path().join("notes.txt"), "").unwrap();
+        let patterns = ["docs", "report.txt", "*.pdf", "/notes.txt"]
+            .map(|pattern| Pattern::new(pattern).unwrap());
+
+        let (selected, used) = find_matching(temp.path(), &patterns, false).unwrap();
+
+        assert_eq!(
+            selected,
+            [PathBuf::from("docs"), PathBuf::from("notes.txt")]
+        );
+        assert_eq!(used, [true, true, false, true]);
+    }
+
+    #[test]
+    fn estimate_applies_the_rules_of_the_copy() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("build")).unwrap();
+        fs::create_dir_all(src.join("sub/build/deep")).unwrap();
+        fs::write(src.join("a.bin"), [0; 100]).unwrap();
+        fs::write(src.join("build/b.bin"), [0; 1000]).unwrap();
+        fs::write(src.join("sub/build/c.bin"), [0; 10]).unwrap();
+        fs::write(src.join("sub/build/deep/d.bin"), [0; 5000]).unwrap();
+        let options = CopyOptions {
+            exclude_list: vec![Pattern::new("/build").unwrap()],
+            max_depth: Some(2),
+            dedup: true,
+            ..CopyOptions::default()
+        };
+        let dest = temp.path().join("dest");
+
+        assert_eq!(estimate_size(&src, &dest, &options).unwrap(), 110);
+        assert!(!dest.exists());
+
+        // Files that no longer fit into the budget are skipped, as the copy would
+        let options = CopyOptions {
+            max_total_size: Some(50),
+            ..options
+        };
+        assert_eq!(estimate_size(&src, &dest, &options).unwrap(), 10);
+    }
+
+    #[test]
+    fn progress_callback_fires_once_per_copied_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let dest = temp.path().join("dest");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        for name in ["a.txt", "b.txt", "sub/c.txt"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        let copied = std::sync::Mutex::new(Vec::new());
+        let progress = |event: &Event| {
+            if let Event::FileCopied { path, .. } = event {
+                copied.lock().unwrap().push(path.to_path_buf());
+            }
+        };
+        let mut stats = BackupStats::default();
+
+        copy_directory(
+            &src,
+            &dest,
+            &CopyOptions::default(),
+            &mut stats,
+            &AtomicBool::new(false),
+            &progress,
+        )
+        .unwrap();
+
+        let mut copied = copied.into_inner().unwrap();
+        copied.sort();
+        assert_eq!(
+            copied,
+            [src.join("a.txt"), src.join("b.txt"), src.join("sub/c.txt")]
+        );
+        assert_eq!(stats.files_copied, 3);
+    }
+
+    #[test]
+    fn simple_copy_creates_the_destination() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let dest = temp.path().join("new/dest");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "a").unwrap();
+
+        let stats = copy_directory_simple(&src, &dest).unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("sub/a.txt")).unwrap(), "a");
+        assert_eq!(stats.files_copied, 1);
+    }
+
+    // Refusing a symlinked source is a policy of the command line, not of the library
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_roots_are_followed() {
+        let temp = tempfile::tempdir().unwrap();
+        let real = temp.path().join("real");
+        let src = temp.path().join("src");
+        fs::create_dir_all(&real).unwrap();
+        fs::write(real.join("a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink(&real, &src).unwrap();
+
+        let stats = copy_directory_simple(&src, &temp.path().join("dest")).unwrap();
+
+        assert_eq!(stats.files_copied, 1);
+    }
+
+    #[test]
+    fn excluded_directories_are_not_walked() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let dest = temp.path().join("dest");
+        fs::create_dir_all(src.join("node_modules/left-pad/lib")).unwrap();
+        fs::create_dir_all(src.join("lib/deep/deeper")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(src.join("node_modules/left-pad/index.js"), "").unwrap();
+        fs::write(src.join("lib/deep/deeper/main.js"), "").unwrap();
+        let options = CopyOptions {
+            exclude_list: vec![Pattern::new("node_modules").unwrap()],
+            ..CopyOptions::default()
+        };
+        let sink = RecordPaths::default();
+        let mut stats = BackupStats::default();
+
+        copy_directory(
+            &src,
+            &dest,
+            &options,
+            &mut stats,
+            &AtomicBool::new(false),
+            &sink,
+        )
+        .unwrap();
+
+        // The directory itself is reported as skipped; nothing below it is ever listed
+        let paths = sink.paths.into_inner().unwrap();
+        let excluded = src.join("node_modules");
+        assert_eq!(
+            paths
+                .iter()
+                .filter(|path| path.starts_with(&excluded))
+                .collect::<Vec<_>>(),
+            [&excluded]
+        );
+        assert!(paths.contains(&src.join("lib/deep/deeper/main.js")));
+        assert_eq!(stats.files_skipped, 1);
+        assert!(!dest.join("node_modules").exists());
+    }
+
+    #[test]
+    fn files_deleted_during_the_copy_are_counted_as_vanished() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let dest = temp.path().join("dest");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        for name in ["a.log", "b.log", "c.log"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        let mut stats = BackupStats::default();
+
+        copy_directory(
+            &src,
+            &dest,
+            &CopyOptions::default(),
+            &mut stats,
+            &AtomicBool::new(false),
+            &DeleteOthersOnCopy { dir: src.clone() },
+        )
+        .unwrap();
+
+        // The directory is listed in one go, so the deleted files are still visited
+        assert_eq!(stats.files_copied, 1);
+        assert_eq!(stats.files_vanished, 2);
+        assert_eq!(stats.files_failed, 0);
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn mapped_and_streamed_files_hash_the_same() {
+        let temp = tempfile::tempdir().unwrap();
+        let (small, large) = (temp.path().join("small"), temp.path().join("large"));
+        fs::write(&small, b"tail").unwrap();
+        // A sparse file just above the threshold, ending in the contents of the small file
+        let mut file = File::create(&large).unwrap();
+        file.set_len(MMAP_HASH_THRESHOLD).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"tail").unwrap();
+
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(&large).unwrap(), &mut hasher).unwrap();
+
+        let expected = format!("{:x}", hasher.finalize());
+        assert_eq!(hash_copy(&large).unwrap(), expected);
+        assert_eq!(hash_file(&large).unwrap(), expected);
+        assert_eq!(
+            hash_copy(&small).unwrap(),
+            format!("{:x}", Sha256::digest(b"tail"))
+        );
+    }
+
+    #[test]
+    fn back_to_back_backups_get_distinct_directories() {
+        let target = tempfile::tempdir().unwrap();
+        let name = "docs_backup_2024-01-31_23-59-59";
+
+        let first = create_backup_dir(target.path(), name).unwrap();
+        let second = create_backup_dir(target.path(), name).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            target.path().join(".tmp.docs_backup_2024-01-31_23-59-59")
+        );
+        assert_eq!(
+            second,
+            target.path().join(".tmp.docs_backup_2024-01-31_23-59-59_2")
+        );
+        // Also when the first one was already finalized under the shared name
+        let first = finalize_backup_dir(&first).unwrap();
+        let third = create_backup_dir(target.path(), name).unwrap();
+        assert_ne!(finalize_backup_dir(&third).unwrap(), first);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zero_filled_blocks_become_holes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (temp.path().join("src.img"), temp.path().join("dest.img"));
+        // Fully allocated: the zeros are written rather than left as a hole
+        let mut data = vec![7; 1 << 20];
+        data.resize(9 << 20, 0);
+        data.resize(10 << 20, 7);
+        fs::write(&src, &data).unwrap();
+        let options = CopyOptions {
+            sparse: SparseMode::Always,
+            ..CopyOptions::default()
+        };
+
+        assert_eq!(copy_file(&src, &dest, &options).unwrap(), data.len() as u64);
+
+        let (source, copy) = (fs::metadata(&src).unwrap(), fs::metadata(&dest).unwrap());
+        assert_eq!(copy.len(), source.len());
+        assert!(
+            copy.blocks() * 2 < source.blocks(),
+            "the copy occupies {} blocks, the source {}",
+            copy.blocks(),
+            source.blocks()
+        );
+        assert!(fs::read(&dest).unwrap() == data);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn holes_are_located_without_reading_them() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("sparse.img");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        file.set_len(32 << 20).unwrap();
+        file.seek(SeekFrom::End(-4096)).unwrap();
+        file.write_all(&[2; 4096]).unwrap();
+
+        let regions = data_regions(&File::open(&path).unwrap(), 32 << 20);
+
+        assert_eq!(regions.first().map(|region| region.0), Some(0));
+        assert_eq!(regions.last().map(|region| region.1), Some(32 << 20));
+        let data: u64 = regions.iter().map(|(start, end)| end - start).sum();
+        assert!(data < 1 << 20, "{:?} is mostly data", regions);
+    }
+
+    #[test]
+    fn reserved_windows_device_names_are_invalid() {
+        for name in [
+            "CON",
+            "aux",
+            "Nul",
+            "com1",
+            "LPT9",
+            "con.txt",
+            "aux.tar.gz",
+            "nul .txt",
+        ] {
+            assert!(is_invalid_windows_name(name), "{}", name);
+        }
+        for name in [
+            "console",
+            "auxiliary.txt",
+            "com10",
+            "lpt",
+            "my.con",
+            "x.aux",
+        ] {
+            assert!(!is_invalid_windows_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_invalid() {
+        for name in ["notes.", "notes ", "...", "a. ", " "] {
+            assert!(is_invalid_windows_name(name), "{:?}", name);
+        }
+        for name in [".hidden", " leading", "a.b", "a b"] {
+            assert!(!is_invalid_windows_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn forbidden_windows_characters_are_invalid() {
+        for name in [
+            "a<b",
+            "a>b",
+            "12:30",
+            "say \"hi\"",
+            "a/b",
+            "a\\b",
+            "a|b",
+            "why?",
+            "*.txt",
+            "tab\there",
+            "nul\0byte",
+        ] {
+            assert!(is_invalid_windows_name(name), "{:?}", name);
+        }
+        for name in ["a-b_c (1)", "[x]{y}", "100%", "café", "a#b&c"] {
+            assert!(!is_invalid_windows_name(name), "{:?}", name);
+        }
+    }
+
+    /// Runs `operation` through `with_retries` with two quick retries.
+    fn retry<T>(
+        stats: &mut BackupStats,
+        cancel: &AtomicBool,
+        operation: impl FnMut() -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let options = CopyOptions {
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+            ..CopyOptions::default()
+        };
+        let mut walk = Walk {
+            root: Path::new(""),
+            stats,
+            cancel,
+            sink: &RecordPaths::default(),
+            dedup_index: DedupIndex::default(),
+            ancestors: Vec::new(),
+        };
+        with_retries(Path::new("flaky.bin"), &options, &mut walk, operation)
+    }
+
+    #[test]
+    fn transient_errors_are_retried_until_the_operation_succeeds() {
+        let mut stats = BackupStats::default();
+        let mut attempts = 0;
+
+        let result = retry(&mut stats, &AtomicBool::new(false), || {
+            attempts += 1;
+            if attempts <= 2 {
+                Err(Error::from(ErrorKind::TimedOut))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(stats.files_retried, 1);
+        assert_eq!(stats.retry_attempts, 2);
+    }
+
+    #[test]
+    fn retries_are_limited_and_skip_permanent_errors() {
+        let mut stats = BackupStats::default();
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry(&mut stats, &AtomicBool::new(false), || {
+            attempts += 1;
+            Err(Error::from(ErrorKind::TimedOut))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry(&mut stats, &AtomicBool::new(false), || {
+            attempts += 1;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn nothing_is_retried_once_the_backup_is_cancelled() {
+        let mut stats = BackupStats::default();
+        let cancel = AtomicBool::new(false);
+        let mut attempts = 0;
+
+        // Ctrl-C interrupts the read, which then fails with the otherwise transient EINTR
+        let result: std::io::Result<()> = retry(&mut stats, &cancel, || {
+            attempts += 1;
+            cancel.store(true, Ordering::Relaxed);
+            Err(Error::from(ErrorKind::Interrupted))
+        });
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Interrupted);
+        assert_eq!(attempts, 1);
+        assert_eq!(stats.retry_attempts, 0);
+    }
+
+    #[test]
+    fn basename_style_copies_into_the_backup_root() {
+        let subpath = backup_subpath(Path::new("/var/lib/myapp/data"), PathStyle::Basename, None);
+        assert_eq!(subpath.unwrap(), PathBuf::new());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_style_keeps_the_path_without_its_root() {
+        for source in [
+            "/var/lib/myapp/data",
+            "/var/lib/myapp/data/",
+            "/var/./lib/myapp//data",
+        ] {
+            let subpath = backup_subpath(Path::new(source), PathStyle::Absolute, None).unwrap();
+            assert_eq!(subpath, Path::new("var/lib/myapp/data"), "{}", source);
+        }
+        assert_eq!(
+            backup_subpath(Path::new("/"), PathStyle::Absolute, None).unwrap(),
+            PathBuf::new()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_style_keeps_the_path_below_the_root() {
+        let root = Some(Path::new("/var/lib/"));
+        let relative = |source: &str| backup_subpath(Path::new(source), PathStyle::Relative, root);
+
+        assert_eq!(
+            relative("/var/lib/myapp/data/").unwrap(),
+            Path::new("myapp/data")
+        );
+        assert_eq!(relative("/var/lib").unwrap(), PathBuf::new());
+        assert!(relative("/var/libx/data").is_err());
+        assert!(relative("/srv/data").is_err());
+        assert!(backup_subpath(Path::new("/var/lib/a"), PathStyle::Relative, None).is_err());
+    }
+
+    #[test]
+    fn parent_components_are_rejected() {
+        let source = Path::new("/var/lib/../data");
+        assert!(backup_subpath(source, PathStyle::Absolute, None).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_letters_and_shares_become_directories() {
+        let absolute = |source: &str| backup_subpath(Path::new(source), PathStyle::Absolute, None);
+
+        assert_eq!(
+            absolute(r"C:\Users\me\data").unwrap(),
+            Path::new(r"C\Users\me\data")
+        );
+        assert_eq!(absolute(r"\\?\D:\data\").unwrap(), Path::new(r"D\data"));
+        assert_eq!(
+            absolute(r"\\server\share\data").unwrap(),
+            Path::new(r"server\share\data")
+        );
+    }
+
+    #[test]
+    fn names_differing_only_in_case_collide() {
+        let mut names = FoldedNames::default();
+
+        assert_eq!(names.insert(OsStr::new("README.md")), None);
+        assert_eq!(names.insert(OsStr::new("notes.txt")), None);
+        assert_eq!(
+            names.insert(OsStr::new("readme.md")),
+            Some(&OsString::from("README.md"))
+        );
+        assert_eq!(
+            names.insert(OsStr::new("Notes.TXT")),
+            Some(&OsString::from("notes.txt"))
+        );
+    }
+
+    #[test]
+    fn renamed_collisions_skip_names_already_taken() {
+        let mut names = FoldedNames::default();
+        for name in ["README.md", "readme_2.md"] {
+            names.insert(OsStr::new(name));
+        }
+
+        assert_eq!(names.rename(OsStr::new("readme.md")), "readme_3.md");
+        assert_eq!(names.rename(OsStr::new("Readme.md")), "Readme_4.md");
+        // The new names collide like any other
+        assert!(names.insert(OsStr::new("README_3.MD")).is_some());
+    }
+
+    #[test]
+    fn file_lists_are_split_by_line_or_nul() {
+        let expected = ["a.txt", "dir/with space.txt", "sub"].map(PathBuf::from);
+
+        let lines = parse_file_list(b"a.txt\r\ndir/with space.txt\n\nsub\n", false).unwrap();
+        let nul = parse_file_list(b"a.txt\0dir/with space.txt\0sub\0", true).unwrap();
+
+        assert_eq!(lines, expected);
+        assert_eq!(nul, expected);
+        assert_eq!(
+            parse_file_list(b"line\nbreak\0", true).unwrap(),
+            [PathBuf::from("line\nbreak")]
+        );
+    }
+
+    #[test]
+    fn second_lock_on_a_target_fails_and_names_the_holder() {
+        let target = tempfile::tempdir().unwrap();
+        let _held = lock_target(target.path(), Duration::ZERO).unwrap();
+
+        let error = lock_target(target.path(), Duration::ZERO).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+        #[cfg(unix)]
+        assert!(error
+            .to_string()
+            .ends_with(&format!("by process {})", std::process::id())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owner_filter_matches_any_user_or_group() {
+        let filter = OwnerFilter {
+            uids: vec![1000, 1001],
+            gids: vec![50],
+        };
+
+        assert!(filter.matches(1000, 0));
+        assert!(filter.matches(1001, 0));
+        assert!(filter.matches(0, 50));
+        assert!(!filter.matches(0, 0));
+        assert!(!filter.matches(1002, 51));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owner_filter_resolves_names_and_numbers() {
+        assert_eq!(OwnerFilter::resolve(&[], &[]), Ok(None));
+
+        let filter = OwnerFilter::resolve(&["root".to_string(), "1234".to_string()], &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.uids, [0, 1234]);
+        assert!(OwnerFilter::resolve(&[], &["no-such-group-xyz".to_string()]).is_err());
+    }
+
+    #[test]
+    fn waiting_for_the_lock_succeeds_once_it_is_released() {
+        let target = tempfile::tempdir().unwrap();
+        let held = lock_target(target.path(), Duration::ZERO).unwrap();
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+
+        let path = target.path().to_path_buf();
+        let waiter = std::thread::spawn(move || lock_target(&path, Duration::from_secs(10)));
+
+        releaser.join().unwrap();
+        assert!(waiter.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn lock_is_released_when_the_holder_panics() {
+        let target = tempfile::tempdir().unwrap();
+        let path = target.path().to_path_buf();
+
+        let result = std::thread::spawn(move || {
+            let _held = lock_target(&path, Duration::ZERO).unwrap();
+            panic!("backup failed");
+        })
+        .join();
+
+        assert!(result.is_err());
+        assert!(lock_target(target.path(), Duration::ZERO).is_ok());
+    }
+}
@@ -0,0 +1,399 @@
+use crate::backup::{IN_PROGRESS_PREFIX, LOCK_FILE_NAME, MANIFESTS_DIR_NAME, OBJECTS_DIR_NAME};
+use crate::naming::{NameTemplate, LEVEL_SEPARATOR};
+use chrono::{Local, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A backup directory found in a target directory.
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pub path: PathBuf,
+    /// Name of the source directory the backup was taken of, if the name template records it.
+    pub source_name: Option<String>,
+    /// When the backup was taken, in local time (names in UTC are converted).
+    pub timestamp: NaiveDateTime,
+    /// Numeric suffix added when several backups share a name (1 when there is none).
+    pub sequence: u32,
+    /// `false` while the backup is being written, or if its run failed.
+    pub complete: bool,
+}
+
+/// Kind of an entry stored in a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// An entry stored in a backup, as listed by `backup_contents`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentEntry {
+    /// Path relative to the backup directory.
+    pub path: PathBuf,
+    pub kind: ContentKind,
+    /// Size in bytes of a file; 0 for other entries.
+    pub size: u64,
+}
+
+/// Parses a directory name of the form `[.tmp.]<rendered template>[_<n>]`.
+///
+/// # Parameters
+/// - `path`: The backup directory.
+/// - `relative`: Its path relative to the target, with levels separated by `/`.
+///
+/// # Returns
+/// - `None` if the name was not produced by snapshotter with this template.
+fn parse_entry(path: PathBuf, relative: &str, template: &NameTemplate) -> Option<CatalogEntry> {
+    // Only the last level of a nested name carries the in-progress prefix
+    let (parent, leaf) = match relative.rsplit_once(LEVEL_SEPARATOR) {
+        Some((parent, leaf)) => (Some(parent), leaf),
+        None => (None, relative),
+    };
+    let (complete, leaf) = match leaf.strip_prefix(IN_PROGRESS_PREFIX) {
+        Some(rest) => (false, rest),
+        None => (true, leaf),
+    };
+    let name = match parent {
+        Some(parent) => format!("{}{}{}", parent, LEVEL_SEPARATOR, leaf),
+        None => leaf.to_string(),
+    };
+    let name = name.as_str();
+
+    // Prefer the name as a whole, so a template ending in digits is not mistaken for a suffix
+    let (parsed, sequence) = match template.parse_name(name) {
+        Some(parsed) => (parsed, 1),
+        None => {
+            let (base, suffix) = name.rsplit_once('_')?;
+            (template.parse_name(base)?, suffix.parse().ok()?)
+        }
+    };
+
+    // Bring backups named in UTC and in local time into one order
+    let timestamp = if parsed.utc {
+        Utc.from_utc_datetime(&parsed.time)
+            .with_timezone(&Local)
+            .naive_local()
+    } else {
+        parsed.time
+    };
+    Some(CatalogEntry {
+        path,
+        source_name: parsed.source,
+        timestamp,
+        sequence,
+        complete,
+    })
+}
+
+/// Lists the backups in `target`, oldest first.
+///
+/// A nested template is followed as many directory levels down as it has. Files and directories
+/// whose names do not match `template` are ignored.
+///
+/// # Errors
+/// - Will return an error if `target` or a directory below it cannot be read.
+pub fn scan_backups(target: &Path, template: &NameTemplate) -> std::io::Result<Vec<CatalogEntry>> {
+    let mut backups = Vec::new();
+    scan_level(target, "", template.levels(), template, &mut backups)?;
+
+    backups.sort_by(|a, b| {
+        (a.timestamp, a.sequence, &a.source_name).cmp(&(b.timestamp, b.sequence, &b.source_name))
+    });
+    Ok(backups)
+}
+
+/// Collects the backups below `dir`, which is `prefix` relative to the target and `levels`
+/// levels above the backup directories.
+fn scan_level(
+    dir: &Path,
+    prefix: &str,
+    levels: usize,
+    template: &NameTemplate,
+    backups: &mut Vec<CatalogEntry>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str() else {
+            continue;
+        };
+        let relative = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}{}{}", prefix, LEVEL_SEPARATOR, name)
+        };
+
+        if levels > 1 {
+            // Only the backup directory itself carries the in-progress prefix
+            if !name.starts_with(IN_PROGRESS_PREFIX) {
+                scan_level(&entry.path(), &relative, levels - 1, template, backups)?;
+            }
+        } else if let Some(backup) = parse_entry(entry.path(), &relative, template) {
+            backups.push(backup);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the entries directly in `target` that snapshotter did not create, sorted by name.
+///
+/// Snapshotter's own entries are the lock file, `--mirror` directories (`<source>_mirror`),
+/// `--resume` journals, and backup directories matching `template` (with a nested template,
+/// the directories leading to them). Anything else, such as unrelated data in a mistyped
+/// target, is foreign. A target that does not exist has no foreign entries.
+///
+/// # Errors
+/// - Will return an error if `target` or a directory below it cannot be read.
+pub fn foreign_entries(target: &Path, template: &NameTemplate) -> std::io::Result<Vec<PathBuf>> {
+    if !target.is_dir() {
+        return Ok(Vec::new());
+    }
+    let backups = scan_backups(target, template)?;
+    let mut foreign = Vec::new();
+    for entry in fs::read_dir(target)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_dir = entry.file_type()?.is_dir();
+        let ours = name == LOCK_FILE_NAME
+            || (is_dir && (name == OBJECTS_DIR_NAME || name == MANIFESTS_DIR_NAME))
+            || (is_dir && name.ends_with("_mirror"))
+            || (!is_dir && name.starts_with(IN_PROGRESS_PREFIX) && name.ends_with(".journal"))
+            || backups.iter().any(|backup| backup.path.starts_with(&path));
+        if !ours {
+            foreign.push(path);
+        }
+    }
+    foreign.sort();
+    Ok(foreign)
+}
+
+/// Returns the newest complete backup, optionally restricted to one source name.
+pub fn latest<'a>(
+    backups: &'a [CatalogEntry],
+    source_name: Option<&str>,
+) -> Option<&'a CatalogEntry> {
+    backups
+        .iter()
+        .filter(|backup| backup.complete)
+        .filter(|backup| source_name.is_none_or(|name| backup.source_name.as_deref() == Some(name)))
+        .max_by_key(|backup| (backup.timestamp, backup.sequence))
+}
+
+/// Lists every entry stored in the backup directory `backup`, sorted by path. Symbolic links
+/// are listed as links and not followed.
+///
+/// # Errors
+/// - Will return an error if a directory or its metadata cannot be read.
+pub fn backup_contents(backup: &Path) -> std::io::Result<Vec<ContentEntry>> {
+    let mut entries = Vec::new();
+    collect_contents(backup, backup, &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Recursively adds the entries below `dir` to `entries`, relative to `root`.
+fn collect_contents(
+    root: &Path,
+    dir: &Path,
+    entries: &mut Vec<ContentEntry>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+        let (kind, size) = if file_type.is_dir() {
+            (ContentKind::Dir, 0)
+        } else if file_type.is_symlink() {
+            (ContentKind::Symlink, 0)
+        } else if file_type.is_file() {
+            (ContentKind::File, metadata.len())
+        } else {
+            (ContentKind::Other, 0)
+        };
+        entries.push(ContentEntry {
+            path: path.strip_prefix(root).unwrap_or(&path).to_path_buf(),
+            kind,
+            size,
+        });
+        if kind == ContentKind::Dir {
+            collect_contents(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sums the sizes of all files below `dir`. Symbolic links are not followed.
+///
+/// # Errors
+/// - Will return an error if a directory or its metadata cannot be read.
+pub fn directory_size(dir: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += directory_size(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(paths: Vec<PathBuf>) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn parse(name: &str) -> Option<CatalogEntry> {
+        parse_entry(PathBuf::from(name), name, &NameTemplate::default())
+    }
+
+    #[test]
+    fn names_with_extra_underscores_are_parsed() {
+        let entry = parse("my_old_docs_backup_2024-01-31_23-59-59").unwrap();
+        assert_eq!(entry.source_name.as_deref(), Some("my_old_docs"));
+        assert_eq!(entry.sequence, 1);
+        assert!(entry.complete);
+
+        let entry = parse(".tmp.my_docs_backup_2024-01-31_23-59-59_12").unwrap();
+        assert_eq!(entry.source_name.as_deref(), Some("my_docs"));
+        assert_eq!(entry.sequence, 12);
+        assert!(!entry.complete);
+
+        // A source named like a backup is still split at the last timestamp
+        let entry = parse("a_backup_2024-01-01_00-00-00_backup_2024-01-31_23-59-59").unwrap();
+        assert_eq!(
+            entry.source_name.as_deref(),
+            Some("a_backup_2024-01-01_00-00-00")
+        );
+
+        for name in [
+            "docs_backup_2024-01-31_23-59-59_",
+            "docs_backup_2024-01-31_23-59-59_x",
+            "docs_backup_2024-01-31_23-59-59_2_3",
+            "_backup_2024-01-31_23-59-59",
+        ] {
+            assert!(parse(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn near_miss_timestamps_are_not_backups() {
+        assert!(parse("docs_backup_2024-01-31_23-59-59").is_some());
+        assert!(parse("docs_backup_2024-01-31_23-59-59Z").is_some());
+        for name in [
+            "docs_backup_2024-13-01_00-00-00",
+            "docs_backup_2024-02-30_00-00-00",
+            "docs_backup_2024-01-31_24-00-00",
+            "docs_backup_2024-01-31_23-59",
+            "docs_backup_2024-01-31 23-59-59",
+            "docs_backup_2024-1-31_23-59-59",
+            "docs_backup_2024-01-31_23-59-59z",
+            "docs_backup_2024-01-31_23-59-590",
+            "docs-backup-2024-01-31_23-59-59",
+        ] {
+            assert!(parse(name).is_none(), "{}", name);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn non_utf8_names_are_skipped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path();
+        fs::create_dir(target.join("docs_backup_2024-01-31_23-59-59")).unwrap();
+        fs::create_dir(target.join(OsStr::from_bytes(b"caf\xe9_backup_2024-01-31_23-59-58")))
+            .unwrap();
+
+        let backups = scan_backups(target, &NameTemplate::default()).unwrap();
+
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].source_name.as_deref(), Some("docs"));
+    }
+
+    #[test]
+    fn snapshotter_entries_are_not_foreign() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path();
+        for dir in [
+            "docs_backup_2024-01-31_23-59-59",
+            "docs_backup_2024-01-31_23-59-59_2",
+            ".tmp.docs_backup_2024-02-01_10-00-00",
+            "docs_mirror",
+            OBJECTS_DIR_NAME,
+            MANIFESTS_DIR_NAME,
+        ] {
+            fs::create_dir(target.join(dir)).unwrap();
+        }
+        fs::write(target.join(LOCK_FILE_NAME), "").unwrap();
+        fs::write(
+            target.join(".tmp.docs_backup_2024-02-01_10-00-00.journal"),
+            "",
+        )
+        .unwrap();
+
+        let foreign = foreign_entries(target, &NameTemplate::default()).unwrap();
+
+        assert!(foreign.is_empty(), "{:?}", foreign);
+    }
+
+    #[test]
+    fn other_entries_are_foreign() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path();
+        fs::create_dir(target.join("docs_backup_2024-01-31_23-59-59")).unwrap();
+        fs::create_dir(target.join("Photos")).unwrap();
+        // Named like a backup, but a file, and like a mirror, but not a directory
+        fs::write(target.join("docs_backup_2024-01-31_23-59-58"), "").unwrap();
+        fs::write(target.join("notes_mirror"), "").unwrap();
+        fs::write(target.join("taxes.pdf"), "").unwrap();
+
+        let foreign = foreign_entries(target, &NameTemplate::default()).unwrap();
+
+        assert_eq!(
+            names(foreign),
+            [
+                "Photos",
+                "docs_backup_2024-01-31_23-59-58",
+                "notes_mirror",
+                "taxes.pdf"
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_leading_to_nested_backups_are_not_foreign() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path();
+        let template = NameTemplate::parse("{host}/{source}/{timestamp}").unwrap();
+        fs::create_dir_all(target.join("web1/docs/2024-01-31_23-59-59")).unwrap();
+        fs::create_dir_all(target.join("old-stuff/docs")).unwrap();
+
+        let foreign = foreign_entries(target, &template).unwrap();
+
+        assert_eq!(names(foreign), ["old-stuff"]);
+        assert!(foreign_entries(&target.join("missing"), &template)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,12 +1,112 @@
-use serde::Deserialize;
+use crate::backup::{PathStyle, ReflinkMode, SparseMode};
+use crate::patterns::{self, Pattern};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 
 /// Struct representing the configuration loaded from the JSON file.
 #[derive(Deserialize)]
 pub struct Config {
     pub excluded_items: Vec<String>,
     pub excluded_extensions: Vec<String>,
+    /// Match `excluded_items` and `excluded_extensions` regardless of case. Defaults to the
+    /// convention of the platform: case-insensitive on Windows and macOS.
+    pub case_insensitive_patterns: Option<bool>,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+
+    // Defaults for command-line options; an option given on the command line takes precedence
+    pub metrics_file: Option<String>,
+    pub summary_file: Option<String>,
+    pub snapshot_command: Option<String>,
+    pub release_snapshot_command: Option<String>,
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    pub clean_on_failure: Option<bool>,
+    pub sparse: Option<SparseMode>,
+    pub backup_path_style: Option<PathStyle>,
+    pub relative_root: Option<String>,
+    pub reflink: Option<ReflinkMode>,
+    /// Size such as `"2G"`, in the same format as `--max-total-size`.
+    pub max_total_size: Option<String>,
+    pub skip_hidden: Option<bool>,
+    pub special_files: Option<bool>,
+    pub quiet: Option<bool>,
+    pub warnings_as_errors: Option<bool>,
+    pub fail_on_unreadable: Option<bool>,
+    pub no_space_check: Option<bool>,
+    pub no_lock: Option<bool>,
+    pub wait_for_lock_secs: Option<u64>,
+    pub preserve_owner: Option<bool>,
+    pub one_file_system: Option<bool>,
+    pub dereference_root: Option<bool>,
+    pub preserve_acls: Option<bool>,
+    pub name_template: Option<String>,
+    pub utc: Option<bool>,
+    pub no_timestamp: Option<bool>,
+    pub resume: Option<bool>,
+    pub mirror: Option<bool>,
+    pub no_delete: Option<bool>,
+    pub allow_mixed_target: Option<bool>,
+    pub paranoid: Option<bool>,
+    pub no_empty_dirs: Option<bool>,
+    pub dedup: Option<bool>,
+    pub flatten: Option<bool>,
+    pub repo: Option<bool>,
+    pub target_case_insensitive: Option<bool>,
+    pub rename_collisions: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub retry_errno: Vec<i32>,
+    /// Content types in the format of `--exclude-mime`.
+    #[serde(default, alias = "skip_content_types")]
+    pub exclude_mime: Vec<String>,
+
+    /// `excluded_items`, compiled when the section is loaded.
+    #[serde(skip)]
+    pub exclusions: Vec<Pattern>,
+
+    /// Problems found while loading the section that do not keep it from being used, such as
+    /// unknown keys.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+impl Config {
+    /// Returns the excluded extensions that can never match anything.
+    ///
+    /// Extensions are compared with the end of file names, so an entry containing a path
+    /// separator (e.g. `"generated/rs"`) would silently exclude nothing.
+    pub fn unmatchable_patterns(&self) -> Vec<&str> {
+        self.excluded_extensions
+            .iter()
+            .map(String::as_str)
+            .filter(|pattern| pattern.contains(['/', '\\']))
+            .collect()
+    }
+}
+
+/// Where the result of each run is reported.
+#[derive(Deserialize, Default)]
+pub struct NotificationConfig {
+    /// URL that receives a JSON POST after every run.
+    pub webhook_url: Option<String>,
+}
+
+/// Returns the configuration file used when none is given: `config.json` in the directory of
+/// the executable.
+///
+/// # Errors
+/// - Will return an error if the path of the executable cannot be determined.
+pub fn default_config_path() -> std::io::Result<PathBuf> {
+    let exe_path = env::current_exe()?;
+    Ok(exe_path.parent().unwrap().join("config.json"))
 }
 
 /// Loads the configuration for a specific section from the JSON file located in the same directory as the executable.
@@ -20,25 +120,248 @@ pub struct Config {
 ///
 /// # Errors
 /// - Will return an error if the config file cannot be read or parsed, or if the section does not exist.
-///
-/// # Panics
-/// - Panics if the JSON file cannot be deserialized into the `Config` struct.
 pub fn load_config(section: &str) -> std::io::Result<Config> {
-    let exe_path = env::current_exe()?; // Get the path of the executable
-    let config_path = exe_path.parent().unwrap().join("config.json"); // Construct the path to the configuration file
+    load_config_from(&default_config_path()?, section)
+}
 
+/// Loads the configuration for a specific section from the JSON file at `config_path`.
+///
+/// Keys the section does not know are reported in `Config::warnings`, with the closest known
+/// key when one looks like a typo of it.
+///
+/// # Errors
+/// - Will return an error if the config file cannot be read, is not valid JSON, has neither
+///   `section` nor a default section, or a value of the section has the wrong type. Errors
+///   name the offending key and its line.
+pub fn load_config_from(config_path: &Path, section: &str) -> std::io::Result<Config> {
     let data = fs::read_to_string(config_path)?; // Read the config file as a string
-    let all_config: serde_json::Value = serde_json::from_str(&data).expect("Error parsing JSON");
+    parse_config(&data, section).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", config_path.display(), e),
+        )
+    })
+}
+
+/// Returns the names of the sections of the JSON configuration file at `config_path`.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read or is not a JSON object.
+pub fn section_names(config_path: &Path) -> std::io::Result<Vec<String>> {
+    let data = fs::read_to_string(config_path)?;
+    let all_config: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&data)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: invalid JSON: {}", config_path.display(), e),
+            )
+        })?;
+    Ok(all_config.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Parses the section `section` of the configuration file contents `data`, falling back to the
+/// default section if there is no such section.
+fn parse_config(data: &str, section: &str) -> Result<Config, String> {
+    let all_config: serde_json::Value =
+        serde_json::from_str(data).map_err(|e| format!("invalid JSON: {}", e))?;
 
     // Try to get the specified section, fallback to default if section is not found
-    let section_config = all_config
-        .get(section)
-        .or_else(|| all_config.get("default"))
-        .expect("No section found in config, and no default section provided");
+    let name = [section, "default"]
+        .into_iter()
+        .find(|name| all_config.get(name).is_some())
+        .ok_or_else(|| {
+            format!(
+                "no section {:?} found in config, and no default section provided",
+                section
+            )
+        })?;
 
-    // Deserialize the section into a Config struct
-    let config: Config =
-        serde_json::from_value(section_config.clone()).expect("Error parsing section config");
+    // Deserialize the section from the text rather than from the parsed value, so errors
+    // carry the line of the offending value; the path records its key
+    let mut track = serde_path_to_error::Track::new();
+    let mut json = serde_json::Deserializer::from_str(data);
+    let deserializer = serde_path_to_error::Deserializer::new(&mut json, &mut track);
+    let mut config = SectionSeed { name }
+        .deserialize(deserializer)
+        .map_err(|e| format!("invalid value for {:?}: {}", track.path().to_string(), e))?
+        .ok_or_else(|| format!("no section {:?} found in config", name))?;
+    config.exclusions = patterns::compile(&config.excluded_items)
+        .map_err(|e| format!("{:?}.excluded_items: {}", name, e))?;
 
+    if let Some(keys) = all_config[name].as_object() {
+        let known = known_keys();
+        for key in keys.keys().filter(|key| !known.contains(&key.as_str())) {
+            let closest = known
+                .iter()
+                .map(|known| (edit_distance(key, known), known))
+                .min()
+                .filter(|(distance, _)| *distance <= key.len().max(3) / 3);
+            config.warnings.push(match closest {
+                Some((_, known)) => format!(
+                    "unknown configuration key {:?} in section {:?} (did you mean {:?}?)",
+                    key, name, known
+                ),
+                None => format!("unknown configuration key {:?} in section {:?}", key, name),
+            });
+        }
+    }
     Ok(config)
 }
+
+/// Deserializes the section `name` of a configuration file into a `Config`, skipping the other
+/// sections without interpreting them.
+struct SectionSeed<'a> {
+    name: &'a str,
+}
+
+impl<'de> DeserializeSeed<'de> for SectionSeed<'_> {
+    type Value = Option<Config>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SectionSeed<'_> {
+    type Value = Option<Config>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object of configuration sections")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut config = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.name {
+                config = Some(map.next_value()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Returns the keys a configuration section can have, as derived from `Config`.
+fn known_keys() -> &'static [&'static str] {
+    /// A deserializer that only records the field names of the struct asked of it.
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("field names recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Config::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Returns the number of single-character insertions, deletions and substitutions that turn
+/// `a` into `b` (the Levenshtein distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_errors_name_the_key_and_line() {
+        let data = r#"{
+            "default": {"excluded_items": [], "excluded_extensions": []},
+            "python": {
+                "excluded_items": [],
+                "excluded_extensions": [],
+                "retries": "3"
+            }
+        }"#;
+
+        let error = parse_config(data, "python").err().unwrap();
+
+        assert!(
+            error.starts_with(r#"invalid value for "python.retries": invalid type: string "3""#)
+        );
+        assert!(error.contains("at line 6 column"), "{}", error);
+        // Other sections are not affected by the error
+        assert!(parse_config(data, "default").is_ok());
+    }
+
+    #[test]
+    fn syntax_errors_and_missing_sections_are_reported() {
+        let error = parse_config(r#"{"default": {"excluded_items": [}"#, "default");
+        assert!(error.err().unwrap().starts_with("invalid JSON: "));
+
+        let error = parse_config(r#"{"python": {}}"#, "rust").err().unwrap();
+        assert_eq!(
+            error,
+            r#"no section "rust" found in config, and no default section provided"#
+        );
+    }
+
+    #[test]
+    fn invalid_glob_patterns_are_errors() {
+        let data = r#"{"default": {
+            "excluded_items": ["node_modules", "[abc", "*.log", "a**"],
+            "excluded_extensions": []
+        }}"#;
+
+        let error = parse_config(data, "default").err().unwrap();
+
+        assert!(error.starts_with(r#""default".excluded_items: invalid patterns: "[abc" ("#));
+        assert!(error.contains(r#""a**" ("#), "{}", error);
+        assert!(!error.contains("node_modules"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_keys_are_warned_about_with_the_closest_known_key() {
+        let data = r#"{"default": {
+            "excluded_items": [],
+            "excluded_extensions": [],
+            "exclude_extensions": ["log"],
+            "retry_delay": 100,
+            "colour": "auto"
+        }}"#;
+
+        let config = parse_config(data, "default").unwrap();
+
+        assert_eq!(
+            config.warnings,
+            [
+                r#"unknown configuration key "colour" in section "default""#,
+                r#"unknown configuration key "exclude_extensions" in section "default" (did you mean "excluded_extensions"?)"#,
+                r#"unknown configuration key "retry_delay" in section "default" (did you mean "retry_delay_ms"?)"#,
+            ]
+        );
+    }
+}
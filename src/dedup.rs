@@ -0,0 +1,61 @@
+use crate::backup::hash_file;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A file copied earlier in the run that later files may be linked to.
+struct Copied {
+    src: PathBuf,
+    dest: PathBuf,
+    /// SHA-256 of the source, computed once another file of the same size shows up.
+    hash: Option<String>,
+}
+
+/// Result of looking a file up in a `DedupIndex`.
+pub enum Lookup {
+    /// A file with the same content was already copied to this destination.
+    Duplicate(PathBuf),
+    /// No file with the same content was copied yet. Carries the hash if it had to be computed,
+    /// so it does not need to be computed again when the file is added.
+    Unique(Option<String>),
+}
+
+/// The files copied so far in a backup, grouped by size, used by `--dedup` to store identical
+/// files only once.
+///
+/// Files are compared by size first; contents are only hashed when two files have the same size.
+#[derive(Default)]
+pub struct DedupIndex {
+    by_size: HashMap<u64, Vec<Copied>>,
+}
+
+impl DedupIndex {
+    /// Looks for an already copied file with the same content as `src`.
+    ///
+    /// # Errors
+    /// - Will return an error if `src` or a file of the same size cannot be read.
+    pub fn find(&mut self, src: &Path, size: u64) -> std::io::Result<Lookup> {
+        let Some(candidates) = self.by_size.get_mut(&size) else {
+            return Ok(Lookup::Unique(None));
+        };
+
+        let hash = hash_file(src)?;
+        for candidate in candidates {
+            if candidate.hash.is_none() {
+                candidate.hash = Some(hash_file(&candidate.src)?);
+            }
+            if candidate.hash.as_ref() == Some(&hash) {
+                return Ok(Lookup::Duplicate(candidate.dest.clone()));
+            }
+        }
+        Ok(Lookup::Unique(Some(hash)))
+    }
+
+    /// Records that `src` was copied to `dest`, so later duplicates can be linked to it.
+    pub fn insert(&mut self, src: &Path, dest: &Path, size: u64, hash: Option<String>) {
+        self.by_size.entry(size).or_default().push(Copied {
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+            hash,
+        });
+    }
+}
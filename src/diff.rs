@@ -0,0 +1,166 @@
+use crate::backup::{self, CopyOptions};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How files present on both sides are compared.
+#[derive(Debug, Default, Clone)]
+pub struct DiffOptions {
+    /// Compare file contents by SHA-256 instead of size and modification time.
+    pub hash: bool,
+    /// Exclusion rules of a backup; matching entries are left out on both sides, so comparing
+    /// a source with its backup does not list everything the backup excluded.
+    pub exclusions: Option<CopyOptions>,
+}
+
+/// Differences between two directory trees, as paths relative to their roots.
+#[derive(Debug, Default, Serialize)]
+pub struct DiffReport {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+}
+
+impl DiffReport {
+    /// Returns `true` if both trees are identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Kind and comparison data of a single entry in a tree.
+#[derive(Debug, PartialEq)]
+enum Entry {
+    File {
+        size: u64,
+        modified: Option<SystemTime>,
+    },
+    Dir,
+    Symlink(PathBuf),
+    Other,
+}
+
+/// Recursively records every entry below `dir` under its path relative to `root`.
+/// Symbolic links are recorded as links and not followed.
+fn collect_entries(
+    root: &Path,
+    dir: &Path,
+    exclusions: Option<&CopyOptions>,
+    entries: &mut BTreeMap<PathBuf, Entry>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+
+        let file_type = metadata.file_type();
+        if let Some(exclusions) = exclusions {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if backup::is_excluded_name(exclusions, root, &path, &file_name, || file_type.is_dir())
+                || (!file_type.is_dir() && backup::has_excluded_extension(exclusions, &path))
+            {
+                continue;
+            }
+        }
+
+        if file_type.is_dir() {
+            entries.insert(relative, Entry::Dir);
+            collect_entries(root, &path, exclusions, entries)?;
+        } else if file_type.is_symlink() {
+            entries.insert(relative, Entry::Symlink(fs::read_link(&path)?));
+        } else if file_type.is_file() {
+            let modified = metadata.modified().ok();
+            let size = metadata.len();
+            entries.insert(relative, Entry::File { size, modified });
+        } else {
+            entries.insert(relative, Entry::Other);
+        }
+    }
+    Ok(())
+}
+
+/// Compares two directory trees, typically two backups or a backup and the live source.
+///
+/// Files are considered modified when their size or modification time differ, or with
+/// `options.hash` when their SHA-256 digests differ. An entry whose type changed (e.g. a file
+/// replaced by a directory) is reported as removed and added. No files are copied, so comparing
+/// the last backup with the source shows what the next backup would change.
+///
+/// # Parameters
+/// - `a`: Older tree.
+/// - `b`: Newer tree.
+/// - `options`: Comparison settings.
+///
+/// # Returns
+/// - A `DiffReport` listing entries added in `b`, removed from `a`, and modified, sorted by path.
+///
+/// # Errors
+/// - Will return an error if either tree cannot be read.
+pub fn diff_backups(a: &Path, b: &Path, options: &DiffOptions) -> std::io::Result<DiffReport> {
+    let mut entries_a = BTreeMap::new();
+    let mut entries_b = BTreeMap::new();
+    let exclusions = options.exclusions.as_ref();
+    collect_entries(a, a, exclusions, &mut entries_a)?;
+    collect_entries(b, b, exclusions, &mut entries_b)?;
+
+    let mut report = DiffReport::default();
+    for (path, entry_a) in &entries_a {
+        let Some(entry_b) = entries_b.get(path) else {
+            report.removed.push(path.clone());
+            continue;
+        };
+
+        match (entry_a, entry_b) {
+            (Entry::File { size: size_a, .. }, Entry::File { size: size_b, .. })
+                if options.hash =>
+            {
+                if size_a != size_b
+                    || backup::hash_file(&a.join(path))? != backup::hash_file(&b.join(path))?
+                {
+                    report.modified.push(path.clone());
+                }
+            }
+            (Entry::File { .. }, Entry::File { .. }) | (Entry::Symlink(_), Entry::Symlink(_)) => {
+                if entry_a != entry_b {
+                    report.modified.push(path.clone());
+                }
+            }
+            (Entry::Dir, Entry::Dir) | (Entry::Other, Entry::Other) => {}
+            // The type changed, so the old entry is gone and a new one appeared
+            _ => {
+                report.removed.push(path.clone());
+                report.added.push(path.clone());
+            }
+        }
+    }
+
+    for path in entries_b.keys() {
+        if !entries_a.contains_key(path) {
+            report.added.push(path.clone());
+        }
+    }
+    report.added.sort();
+
+    Ok(report)
+}
+
+/// Formats a report as one line per changed entry: `+` added, `-` removed, `~` modified.
+pub fn format_report(report: &DiffReport) -> String {
+    let mut lines: Vec<(&PathBuf, char)> = Vec::new();
+    lines.extend(report.removed.iter().map(|path| (path, '-')));
+    lines.extend(report.added.iter().map(|path| (path, '+')));
+    lines.extend(report.modified.iter().map(|path| (path, '~')));
+    // Sorting by path keeps entries of the same directory together; the stable sort lists a
+    // removal before the addition of the same path
+    lines.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut output = String::new();
+    for (path, marker) in lines {
+        output.push_str(&format!("{} {}\n", marker, path.display()));
+    }
+    output
+}
@@ -0,0 +1,370 @@
+use crate::backup::BackupStats;
+use crate::format::{format_bytes, format_duration, format_rate};
+use serde::{Serialize, Serializer};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Why an entry was left out of a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Matched an exclusion rule from the configuration or the command line.
+    Excluded,
+    /// Copying it would exceed `--max-total-size`.
+    BudgetExceeded,
+    /// A FIFO, socket or device node.
+    SpecialFile,
+    /// Its name cannot be created on the target platform.
+    InvalidName,
+    /// A mount point skipped because of `--one-file-system`.
+    OtherFilesystem,
+    /// A file owned by a user or group not selected with `--owner`/`--group`.
+    OtherOwner,
+    /// A directory deeper than `--max-depth`, skipped with everything below it.
+    TooDeep,
+    /// A directory reached again through a symbolic link to one of its parents.
+    Cycle,
+    /// Deleted from the source between being listed and being copied.
+    Vanished,
+    /// A file the process is not allowed to read.
+    Unreadable,
+}
+
+/// Something that happened during a backup run, reported to an `EventSink`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    DirEntered {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+    },
+    FileCopied {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        bytes: u64,
+    },
+    FileSkipped {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        reason: SkipReason,
+    },
+    /// An entry removed from a mirror because it no longer exists in the source.
+    FileDeleted {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+    },
+    Warning {
+        message: String,
+    },
+    /// A line printed by a `--pre-hook` or `--post-hook` command.
+    HookOutput {
+        hook: &'a str,
+        line: &'a str,
+    },
+    Error {
+        message: String,
+    },
+    Summary {
+        source: &'a str,
+        #[serde(serialize_with = "serialize_path")]
+        backup_dir: &'a Path,
+        stats: &'a BackupStats,
+        #[serde(rename = "duration_seconds", serialize_with = "serialize_seconds")]
+        duration: Duration,
+        /// Nothing was written: the counts describe what the run would have done.
+        dry_run: bool,
+    },
+    /// Emitted once at the end of a run with the number of warnings and errors it reported.
+    RunFinished {
+        warnings: usize,
+        errors: usize,
+    },
+}
+
+/// Serializes a path as a string, replacing invalid UTF-8 instead of failing.
+fn serialize_path<S: Serializer>(path: &&Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// Serializes a duration as fractional seconds.
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Receives the events of a backup run. Implementations must be shareable between threads.
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &Event);
+}
+
+/// Any closure taking an event is a sink, so a progress callback can be passed directly.
+impl<F: Fn(&Event) + Send + Sync> EventSink for F {
+    fn emit(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Prints the human-readable output: warnings and errors to stderr, the summary to stdout.
+pub struct HumanSink {
+    /// Print errors only.
+    pub quiet: bool,
+    /// Word deletions as what would happen, since nothing is deleted.
+    pub dry_run: bool,
+}
+
+impl EventSink for HumanSink {
+    fn emit(&self, event: &Event) {
+        match event {
+            Event::Error { message } => eprintln!("{}", message),
+            _ if self.quiet => {}
+            Event::FileDeleted { path } if self.dry_run => println!("Would delete {:?}", path),
+            Event::FileDeleted { path } => println!("Deleted {:?}", path),
+            Event::HookOutput { hook, line } => println!("[{}] {}", hook, line),
+            Event::Summary {
+                backup_dir,
+                stats,
+                duration,
+                dry_run,
+                ..
+            } => print_summary(backup_dir, stats, *duration, *dry_run),
+            Event::RunFinished { warnings, errors } => {
+                if *warnings > 0 || *errors > 0 {
+                    println!("Finished with {} warnings and {} errors", warnings, errors);
+                }
+            }
+            _ => {
+                if let Some(message) = warning_message(event) {
+                    eprintln!("Warning: {}", message);
+                }
+            }
+        }
+    }
+}
+
+/// Describes `event` if it is a warning: a `Warning` event, or an entry skipped for a reason
+/// the user did not ask for (unlike exclusions or `--owner`, which are skipped silently).
+pub fn warning_message(event: &Event) -> Option<String> {
+    let (path, reason) = match event {
+        Event::Warning { message } => return Some(message.clone()),
+        Event::FileSkipped { path, reason } => (path, reason),
+        _ => return None,
+    };
+    match reason {
+        SkipReason::SpecialFile => Some(format!("skipping special file {:?}", path)),
+        SkipReason::OtherFilesystem => Some(format!("skipping mount point {:?}", path)),
+        SkipReason::TooDeep => Some(format!(
+            "not descending into {:?}: deeper than --max-depth",
+            path
+        )),
+        SkipReason::Cycle => Some(format!(
+            "skipping {:?}: a symbolic link leads back to a parent directory",
+            path
+        )),
+        SkipReason::InvalidName => Some(format!(
+            "skipping {:?}: Windows cannot create the name",
+            path
+        )),
+        SkipReason::Vanished => Some(format!(
+            "skipping {:?}: it was deleted during the backup",
+            path
+        )),
+        SkipReason::Unreadable => Some(format!("skipping {:?}: permission denied", path)),
+        SkipReason::Excluded | SkipReason::BudgetExceeded | SkipReason::OtherOwner => None,
+    }
+}
+
+/// Prints where a backup was created and what was copied or skipped.
+fn print_summary(backup_dir: &Path, stats: &BackupStats, duration: Duration, dry_run: bool) {
+    if dry_run {
+        println!(
+            "Dry run: would copy {} files ({}) to {:?}, {} items skipped",
+            stats.files_copied,
+            format_bytes(stats.bytes_copied),
+            backup_dir,
+            stats.files_skipped
+        );
+    } else {
+        println!(
+            "Backup created at {:?} in {} ({} files, {} copied at {}, {} items skipped)",
+            backup_dir,
+            format_duration(duration),
+            stats.files_copied,
+            format_bytes(stats.bytes_copied),
+            format_rate(stats.bytes_copied, duration),
+            stats.files_skipped
+        );
+    }
+    if stats.files_over_budget > 0 {
+        println!("{} files skipped: budget exceeded", stats.files_over_budget);
+    }
+    if stats.files_excluded_by_type > 0 {
+        println!(
+            "{} files skipped: content type excluded",
+            stats.files_excluded_by_type
+        );
+    }
+    if stats.special_files_skipped > 0 {
+        println!(
+            "{} special files (FIFOs, sockets, devices) skipped",
+            stats.special_files_skipped
+        );
+    }
+    if stats.invalid_names_skipped > 0 {
+        println!(
+            "{} entries skipped: name invalid on Windows",
+            stats.invalid_names_skipped
+        );
+    }
+    if stats.files_retried > 0 {
+        println!(
+            "{} files retried after transient errors ({} retries)",
+            stats.files_retried, stats.retry_attempts
+        );
+    }
+    if stats.files_deduplicated > 0 {
+        println!(
+            "{} duplicate files hard-linked ({} saved)",
+            stats.files_deduplicated,
+            format_bytes(stats.bytes_deduplicated)
+        );
+    }
+    if stats.files_resumed > 0 {
+        println!(
+            "{} files already copied by the interrupted run",
+            stats.files_resumed
+        );
+    }
+    if stats.files_unchanged > 0 {
+        println!("{} files unchanged", stats.files_unchanged);
+    }
+    if stats.files_deleted > 0 {
+        let verb = if dry_run {
+            "would be deleted"
+        } else {
+            "deleted"
+        };
+        println!("{} entries {} from the mirror", stats.files_deleted, verb);
+    }
+    if stats.mount_points_skipped > 0 {
+        println!(
+            "{} mount points on other filesystems skipped",
+            stats.mount_points_skipped
+        );
+    }
+    if stats.dirs_too_deep > 0 {
+        println!(
+            "{} directories deeper than --max-depth skipped",
+            stats.dirs_too_deep
+        );
+    }
+    if stats.cycles_skipped > 0 {
+        println!(
+            "{} symbolic links to parent directories skipped",
+            stats.cycles_skipped
+        );
+    }
+    if stats.case_collisions_renamed > 0 {
+        println!(
+            "{} entries renamed: name differs only in case from another",
+            stats.case_collisions_renamed
+        );
+    }
+    if stats.files_vanished > 0 {
+        println!(
+            "{} files vanished from the source during the backup",
+            stats.files_vanished
+        );
+    }
+    if stats.files_changed > 0 {
+        println!(
+            "{} files changed while being copied (see the warnings above)",
+            stats.files_changed
+        );
+    }
+    if stats.files_mismatched > 0 {
+        println!(
+            "{} files copied again: their earlier copy differed from the source",
+            stats.files_mismatched
+        );
+    }
+    if !stats.files_unreadable.is_empty() {
+        println!(
+            "{} files could not be read: permission denied",
+            stats.files_unreadable.len()
+        );
+    }
+    if stats.files_failed > 0 {
+        println!(
+            "{} entries could not be copied (see the errors above)",
+            stats.files_failed
+        );
+    }
+    if stats.owner_errors > 0 {
+        println!(
+            "{} entries could not be given their original owner",
+            stats.owner_errors
+        );
+    }
+}
+
+/// Prints every event as one JSON object per line on stdout, for tools wrapping snapshotter.
+pub struct JsonLinesSink {
+    /// Print error events only.
+    pub quiet: bool,
+}
+
+impl EventSink for JsonLinesSink {
+    fn emit(&self, event: &Event) {
+        if self.quiet && !matches!(event, Event::Error { .. }) {
+            return;
+        }
+        match serde_json::to_string(event) {
+            Ok(line) => println!("{}", line),
+            Err(e) => eprintln!("Failed to serialize event: {}", e),
+        }
+    }
+}
+
+/// Forwards events to another sink while collecting the warnings and errors among them, so the
+/// end of the run can count and list them.
+pub struct Diagnostics {
+    sink: Box<dyn EventSink>,
+    warnings: Mutex<Vec<String>>,
+    errors: Mutex<Vec<String>>,
+}
+
+impl Diagnostics {
+    pub fn new(sink: Box<dyn EventSink>) -> Self {
+        Diagnostics {
+            sink,
+            warnings: Mutex::default(),
+            errors: Mutex::default(),
+        }
+    }
+
+    /// The warnings reported so far, in order.
+    pub fn warnings(&self) -> Vec<String> {
+        lock(&self.warnings).clone()
+    }
+
+    /// The errors reported so far, in order.
+    pub fn errors(&self) -> Vec<String> {
+        lock(&self.errors).clone()
+    }
+}
+
+impl EventSink for Diagnostics {
+    fn emit(&self, event: &Event) {
+        if let Event::Error { message } = event {
+            lock(&self.errors).push(message.clone());
+        } else if let Some(message) = warning_message(event) {
+            lock(&self.warnings).push(message);
+        }
+        self.sink.emit(event);
+    }
+}
+
+/// Locks `list`, ignoring poisoning: a panic elsewhere does not make the messages invalid.
+fn lock(list: &Mutex<Vec<String>>) -> std::sync::MutexGuard<'_, Vec<String>> {
+    list.lock().unwrap_or_else(|e| e.into_inner())
+}
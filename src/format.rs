@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+/// Binary unit suffixes used by `format_bytes`, from kibibytes up.
+const BYTE_UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// Formats a byte count for people, e.g. `512 B`, `1.5 KiB` or `2.0 GiB`.
+///
+/// Units are binary (1 KiB = 1024 bytes) and shown with one decimal; counts below 1 KiB are
+/// shown exactly.
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // Move up a unit when rounding to one decimal would print 1024.0
+    while value >= 1023.95 && unit + 1 < BYTE_UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, BYTE_UNITS[unit])
+}
+
+/// Formats a duration for people, e.g. `0.4s`, `3m 07s` or `1h 03m 12s`.
+///
+/// Durations under a minute keep one decimal; longer ones are rounded down to whole seconds.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        // Truncate rather than round so that 59.96s does not print as 60.0s
+        return format!("{}.{}s", secs, duration.subsec_millis() / 100);
+    }
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours == 0 {
+        format!("{}m {:02}s", minutes, secs)
+    } else {
+        format!("{}h {:02}m {:02}s", hours, minutes, secs)
+    }
+}
+
+/// Formats the rate at which `bytes` were transferred in `duration`, e.g. `45.3 MiB/s`.
+///
+/// Without any elapsed time there is no meaningful rate, and `-` is returned.
+pub fn format_rate(bytes: u64, duration: Duration) -> String {
+    if duration.is_zero() {
+        return "-".to_string();
+    }
+    format!(
+        "{}/s",
+        format_bytes((bytes as f64 / duration.as_secs_f64()) as u64)
+    )
+}
+
+/// Parses a size such as `500`, `10K`, `1.5G` or `2TiB` into a number of bytes.
+///
+/// Suffixes are binary (`K` = 1024 bytes) and case-insensitive; a trailing `B` or `iB` is optional.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = split_number(value);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", value))?;
+
+    let unit = unit.to_ascii_uppercase();
+    let unit = unit.trim_end_matches("IB").trim_end_matches('B');
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid size unit: {}", value)),
+    };
+    let bytes = number * multiplier as f64;
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("Size too large: {}", value));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parses a duration such as `90`, `30s`, `15m`, `1.5h`, `30d` or `2w`.
+///
+/// A number without a unit is a number of seconds. Units are case-insensitive.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = split_number(value);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", value))?;
+
+    let unit_secs: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration unit: {}", value)),
+    };
+    Duration::try_from_secs_f64(number * unit_secs as f64)
+        .map_err(|_| format!("Duration out of range: {}", value))
+}
+
+/// Splits `value` into its leading number (digits and dots) and the unit that follows.
+fn split_number(value: &str) -> (&str, &str) {
+    let number_end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    value.split_at(number_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_below_one_kib_are_exact() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1), "1 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+    }
+
+    #[test]
+    fn bytes_use_binary_units_with_one_decimal() {
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(10 * 1024 * 1024), "10.0 MiB");
+        assert_eq!(format_bytes(3 << 30), "3.0 GiB");
+        assert_eq!(format_bytes(1 << 40), "1.0 TiB");
+        assert_eq!(format_bytes(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn bytes_move_up_a_unit_instead_of_rounding_to_1024() {
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_bytes(1023 * 1024), "1023.0 KiB");
+    }
+
+    #[test]
+    fn short_durations_keep_one_decimal() {
+        assert_eq!(format_duration(Duration::ZERO), "0.0s");
+        assert_eq!(format_duration(Duration::from_millis(400)), "0.4s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "59.9s");
+    }
+
+    #[test]
+    fn long_durations_show_minutes_and_hours() {
+        assert_eq!(format_duration(Duration::from_secs(60)), "1m 00s");
+        assert_eq!(format_duration(Duration::from_secs(187)), "3m 07s");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59m 59s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h 00m 00s");
+        assert_eq!(format_duration(Duration::from_secs(3792)), "1h 03m 12s");
+        assert_eq!(
+            format_duration(Duration::from_secs(100 * 3600)),
+            "100h 00m 00s"
+        );
+    }
+
+    #[test]
+    fn rates_divide_bytes_by_the_elapsed_time() {
+        assert_eq!(format_rate(3 << 20, Duration::from_secs(2)), "1.5 MiB/s");
+        assert_eq!(format_rate(100, Duration::from_millis(500)), "200 B/s");
+        assert_eq!(format_rate(0, Duration::from_secs(1)), "0 B/s");
+        assert_eq!(format_rate(1024, Duration::ZERO), "-");
+    }
+
+    #[test]
+    fn sizes_accept_binary_suffixes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10kb"), Ok(10 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("2TiB"), Ok(2 << 40));
+        assert_eq!(parse_size(" 0 "), Ok(0));
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        for value in ["", "K", "1.2.3M", "10X", "-5", "5 M", "99999999999T"] {
+            assert!(parse_size(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn durations_accept_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1.5H"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("30d"), Ok(Duration::from_secs(30 * 86_400)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 86_400)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for value in ["", "d", "1y", "-1d", "1.2.3s", "1e400d"] {
+            assert!(parse_duration(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::format::format_bytes;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// Returns the number of bytes available to the current user on the filesystem holding `path`.
+#[cfg(unix)]
+fn filesystem_available(path: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid, writable statvfs struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)] // Field types differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the number of bytes available to the current user on the volume holding `path`.
+#[cfg(windows)]
+fn filesystem_available(path: &Path) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: `wide` is NUL-terminated and `available` is a valid output location
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Returns the available space for `path`, which may not exist yet; its nearest existing
+/// ancestor is queried instead.
+///
+/// # Errors
+/// - Will return an error if no ancestor of `path` exists or the filesystem cannot be queried.
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    filesystem_available(existing)
+}
+
+/// Checks that the filesystem holding `path` can store `needed` more bytes.
+///
+/// # Errors
+/// - Will return an error of kind `ErrorKind::StorageFull` if less than `needed` bytes are free.
+/// - Will return an error if the available space cannot be determined.
+pub fn ensure_space(path: &Path, needed: u64) -> std::io::Result<()> {
+    let available = available_space(path)?;
+    if needed > available {
+        return Err(Error::new(
+            ErrorKind::StorageFull,
+            format!(
+                "Insufficient space on target: {} needed, {} available",
+                format_bytes(needed),
+                format_bytes(available)
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use crate::events::{Event, EventSink};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Builds a command that runs `command` through the shell of the platform (`sh -c` on Unix,
+/// `cmd /C` on Windows), so hooks can use pipes and variables.
+fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Looks up the program a hook command starts with: its first word, searched on `PATH` unless
+/// it contains a path separator (on Windows, with the extensions of `PATHEXT`).
+///
+/// # Returns
+/// - The path of the program, or `None` if it was not found. Shell builtins are not found, nor
+///   are programs named by a variable or in quotes.
+pub fn find_program(command: &str) -> Option<PathBuf> {
+    let program = Path::new(command.split_whitespace().next()?);
+    let extensions: Vec<String> = if cfg!(windows) {
+        let pathext = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+        std::iter::once(String::new())
+            .chain(pathext.split(';').map(str::to_string))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    let find_in = |dir: &Path| {
+        extensions
+            .iter()
+            .map(|extension| {
+                let mut name = program.as_os_str().to_os_string();
+                name.push(extension);
+                dir.join(name)
+            })
+            .find(|path| path.is_file())
+    };
+
+    if program.components().count() > 1 {
+        return find_in(Path::new(""));
+    }
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).find_map(|dir| find_in(&dir))
+}
+
+/// Runs `command` with its standard output captured; standard error goes to ours.
+///
+/// # Errors
+/// - Will return an error if the command cannot be started or exits unsuccessfully.
+fn run(command: &str, what: &str, env: &[(&str, &Path)]) -> std::io::Result<Output> {
+    let mut shell = shell(command);
+    for (name, value) in env {
+        shell.env(name, value);
+    }
+    let output = shell
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Cannot run {} {:?}: {}", what, command, e),
+            )
+        })?;
+
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "The {} {:?} failed with {}",
+            what, command, output.status
+        )));
+    }
+    Ok(output)
+}
+
+/// Runs the `--snapshot-command` for `source` and returns the root of the snapshot it created.
+///
+/// The command gets the source in `SNAPSHOTTER_SOURCE` and must print the path of the snapshot
+/// root as the last line of its standard output.
+///
+/// # Errors
+/// - Will return an error if the command fails or does not print an existing directory.
+pub fn take_snapshot(command: &str, source: &Path) -> std::io::Result<PathBuf> {
+    let output = run(
+        command,
+        "snapshot command",
+        &[("SNAPSHOTTER_SOURCE", source)],
+    )?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let root = stdout
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_default();
+
+    if !root.is_dir() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Snapshot command {:?} did not print the path of a directory (got {:?})",
+                command, root
+            ),
+        ));
+    }
+    Ok(root)
+}
+
+/// Runs the `--release-snapshot-command` for a snapshot taken by `take_snapshot`.
+///
+/// The command gets the source in `SNAPSHOTTER_SOURCE` and the snapshot root in
+/// `SNAPSHOTTER_SNAPSHOT`. Its standard output is discarded.
+///
+/// # Errors
+/// - Will return an error if the command fails.
+pub fn release_snapshot(command: &str, source: &Path, snapshot: &Path) -> std::io::Result<()> {
+    let env = [
+        ("SNAPSHOTTER_SOURCE", source),
+        ("SNAPSHOTTER_SNAPSHOT", snapshot),
+    ];
+    run(command, "release snapshot command", &env).map(|_| ())
+}
+
+/// Runs a `--pre-hook` or `--post-hook` command with `env` added to its environment.
+///
+/// Everything the command prints, on standard output or standard error, is reported line by
+/// line as `HookOutput` events once it exited.
+///
+/// # Parameters
+/// - `hook`: Name of the hook (`pre-hook` or `post-hook`), used in events and errors.
+///
+/// # Errors
+/// - Will return an error if the command cannot be started or exits unsuccessfully.
+pub fn run_hook(
+    command: &str,
+    hook: &str,
+    env: &[(&str, &OsStr)],
+    sink: &dyn EventSink,
+) -> std::io::Result<()> {
+    let output = shell(command)
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Cannot run the {} {:?}: {}", hook, command, e),
+            )
+        })?;
+
+    for stream in [&output.stdout, &output.stderr] {
+        for line in String::from_utf8_lossy(stream).lines() {
+            sink.emit(&Event::HookOutput { hook, line });
+        }
+    }
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "The {} {:?} failed with {}",
+            hook, command, output.status
+        )));
+    }
+    Ok(())
+}
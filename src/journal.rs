@@ -0,0 +1,249 @@
+use crate::backup::IN_PROGRESS_PREFIX;
+use crate::events::{Event, EventSink};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Appended to the name of an in-progress backup directory to name its journal, which sits
+/// next to it: `.tmp.docs_20240131.journal`.
+const JOURNAL_SUFFIX: &str = ".journal";
+
+/// How often the journal is flushed to disk while files are being copied.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the path of the journal of the in-progress backup directory `backup_dir`.
+pub fn journal_path(backup_dir: &Path) -> PathBuf {
+    let mut name = backup_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(JOURNAL_SUFFIX);
+    backup_dir.with_file_name(name)
+}
+
+/// Records every file of a `--resume` backup once it was copied completely, so an interrupted
+/// run can be continued without copying those files again.
+///
+/// The journal is an event sink placed in front of the sink of the run: it forwards every
+/// event and appends the files of `FileCopied` events to the journal file. The file starts
+/// with the source directory, followed by the copied paths relative to the copied root; all
+/// entries are NUL-terminated. It is only appended to and synced to disk at least every
+/// `SYNC_INTERVAL`, so a crash loses at most the last second of entries.
+pub struct Journal<'a> {
+    sink: &'a dyn EventSink,
+    /// Root the copied paths are relative to (the source, or its snapshot).
+    root: PathBuf,
+    writer: Mutex<JournalWriter>,
+}
+
+struct JournalWriter {
+    file: BufWriter<File>,
+    last_sync: Instant,
+    /// A write failed and was reported; the journal is no longer updated.
+    failed: bool,
+}
+
+impl<'a> Journal<'a> {
+    /// Opens the journal of `backup_dir` for appending, creating it with `source` as its header
+    /// if it does not exist yet.
+    ///
+    /// # Parameters
+    /// - `backup_dir`: In-progress backup directory the journal belongs to.
+    /// - `source`: Canonical path of the source directory, recorded to find the journal again.
+    /// - `root`: Directory the files are copied from: `source`, or its snapshot.
+    /// - `sink`: Receives every event after it was recorded.
+    ///
+    /// # Errors
+    /// - Will return an error if the journal cannot be created or opened.
+    pub fn open(
+        backup_dir: &Path,
+        source: &Path,
+        root: &Path,
+        sink: &'a dyn EventSink,
+    ) -> std::io::Result<Self> {
+        let path = journal_path(backup_dir);
+        let is_new = !path.exists();
+        let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        if is_new {
+            write_entry(&mut file, source)?;
+            file.flush()?;
+            file.get_ref().sync_data()?;
+        }
+        Ok(Journal {
+            sink,
+            root: root.to_path_buf(),
+            writer: Mutex::new(JournalWriter {
+                file,
+                last_sync: Instant::now(),
+                failed: false,
+            }),
+        })
+    }
+
+    /// Writes the entries still buffered to disk.
+    ///
+    /// # Errors
+    /// - Will return an error if the journal cannot be written.
+    pub fn sync(&self) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.file.flush()?;
+        writer.file.get_ref().sync_data()
+    }
+
+    fn record(&self, writer: &mut JournalWriter, path: &Path) -> std::io::Result<()> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        write_entry(&mut writer.file, relative)?;
+        if writer.last_sync.elapsed() >= SYNC_INTERVAL {
+            writer.file.flush()?;
+            writer.file.get_ref().sync_data()?;
+            writer.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for Journal<'_> {
+    fn emit(&self, event: &Event) {
+        if let Event::FileCopied { path, .. } = event {
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            if !writer.failed {
+                if let Err(e) = self.record(&mut writer, path) {
+                    writer.failed = true;
+                    self.sink.emit(&Event::Warning {
+                        message: format!(
+                            "cannot update the resume journal, an interrupted run will copy more files again: {}",
+                            e
+                        ),
+                    });
+                }
+            }
+        }
+        self.sink.emit(event);
+    }
+}
+
+/// Writes `path` as one NUL-terminated journal entry.
+fn write_entry(file: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+    file.write_all(&bytes)?;
+    file.write_all(b"\0")
+}
+
+/// Reads the journal of `backup_dir`.
+///
+/// # Returns
+/// - The source directory recorded in the header, and the paths of the files copied so far
+///   relative to the copied root. An entry cut off by a crash is left out.
+///
+/// # Errors
+/// - Will return an error if the journal cannot be read or has no header.
+pub fn read_journal(backup_dir: &Path) -> std::io::Result<(PathBuf, HashSet<PathBuf>)> {
+    let mut contents = Vec::new();
+    File::open(journal_path(backup_dir))?.read_to_end(&mut contents)?;
+    // Only entries followed by their terminator were written completely
+    let complete = contents
+        .iter()
+        .rposition(|&byte| byte == 0)
+        .map_or(&contents[..0], |end| &contents[..=end]);
+    let mut entries = crate::backup::parse_file_list(complete, true)?.into_iter();
+    let source = entries
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The resume journal is empty"))?;
+    Ok((source, entries.collect()))
+}
+
+/// Finds the most recent in-progress backup of `source` in `dir` that can be resumed: a
+/// directory with the in-progress prefix whose journal names `source`.
+///
+/// # Errors
+/// - Will return an error if `dir` cannot be read.
+pub fn find_resumable(dir: &Path, source: &Path) -> std::io::Result<Option<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with(IN_PROGRESS_PREFIX) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let backup_dir = entry.path();
+        // Journals that cannot be read belong to no resumable backup
+        let Ok((journal_source, _)) = read_journal(&backup_dir) else {
+            continue;
+        };
+        if journal_source != source {
+            continue;
+        }
+        let modified = fs::metadata(journal_path(&backup_dir))?.modified()?;
+        if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            latest = Some((modified, backup_dir));
+        }
+    }
+    Ok(latest.map(|(_, backup_dir)| backup_dir))
+}
+
+/// Removes the journal of `backup_dir`, if there is one.
+///
+/// # Errors
+/// - Will return an error if an existing journal cannot be removed.
+pub fn remove_journal(backup_dir: &Path) -> std::io::Result<()> {
+    match fs::remove_file(journal_path(backup_dir)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::JsonLinesSink;
+
+    #[test]
+    fn journal_records_copied_files_relative_to_the_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let backup_dir = temp.path().join(".tmp.docs_20240131");
+        fs::create_dir(&backup_dir).unwrap();
+        let sink = JsonLinesSink { quiet: true };
+        let root = Path::new("/snapshots/docs");
+
+        let journal = Journal::open(&backup_dir, Path::new("/home/docs"), root, &sink).unwrap();
+        for name in ["a.txt", "sub/with\nnewline.txt"] {
+            journal.emit(&Event::FileCopied {
+                path: &root.join(name),
+                bytes: 1,
+            });
+        }
+        journal.emit(&Event::DirEntered { path: root });
+        journal.sync().unwrap();
+        // A crash in the middle of an entry leaves it without its terminator
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal_path(&backup_dir))
+            .unwrap();
+        file.write_all(b"partial/entr").unwrap();
+
+        let (source, copied) = read_journal(&backup_dir).unwrap();
+
+        assert_eq!(source, Path::new("/home/docs"));
+        assert_eq!(
+            copied,
+            HashSet::from(["a.txt", "sub/with\nnewline.txt"].map(PathBuf::from))
+        );
+        assert_eq!(
+            find_resumable(temp.path(), Path::new("/home/docs")).unwrap(),
+            Some(backup_dir)
+        );
+        assert_eq!(
+            find_resumable(temp.path(), Path::new("/home/other")).unwrap(),
+            None
+        );
+    }
+}
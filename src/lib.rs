@@ -0,0 +1,51 @@
+//! The backup engine behind the `snapshotter` command-line tool, usable from other crates.
+//!
+//! `backup::copy_directory` copies a whole source tree with the exclusions and copy modes set
+//! in `backup::CopyOptions`, and reports every entry to an `events::EventSink`; with
+//! `CopyOptions::dry_run` it only reports what would be backed up. `backup::copy_file` copies a
+//! single file the same way the walk copies each file.
+//!
+//! Modules:
+//! - async_api: Runs a copy on a tokio blocking thread with cancellation and a progress channel
+//!   (requires the `async` feature)
+//! - atomic: Replaces files atomically so readers never see a partial write
+//! - backup: Handles directory backup operations
+//! - catalog: Finds and parses the backups stored in a target directory
+//! - config: Manages configuration settings for the backup process
+//! - dedup: Finds files identical to one copied earlier in a backup
+//! - diff: Compares two backups (or a backup and its source)
+//! - events: Reports progress as human-readable text or JSON lines
+//! - format: Formats and parses byte sizes and durations
+//! - fs_space: Queries free space on the target filesystem
+//! - hooks: Runs user-supplied commands around a backup, such as snapshot commands
+//! - journal: Records the files copied by a backup so an interrupted run can be resumed
+//! - mirror: Removes entries from a mirror that no longer exist in the source
+//! - naming: Renders and parses backup directory name templates
+//! - metrics: Writes Prometheus textfile metrics describing a backup run
+//! - notify: Sends the result of a run to a webhook (requires the `notify` feature)
+//! - patterns: Compiles and matches the glob patterns of exclusions
+//! - repo: Stores backups as deduplicated chunks and manifests (requires the `repo` feature)
+//! - summary: Writes a JSON record of a backup run for audit trails
+
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod atomic;
+pub mod backup;
+pub mod catalog;
+pub mod config;
+pub mod dedup;
+pub mod diff;
+pub mod events;
+pub mod format;
+pub mod fs_space;
+pub mod hooks;
+pub mod journal;
+pub mod metrics;
+pub mod mirror;
+pub mod naming;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod patterns;
+#[cfg(feature = "repo")]
+pub mod repo;
+pub mod summary;
@@ -22,9 +22,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 //
-// Modules:
-// - backup: Handles directory backup operations
-// - config: Manages configuration settings for the backup process
+// The backup engine lives in the library (src/lib.rs), which lists its modules.
 //
 // Usage:
 // This tool is designed to back up directories with the ability to exclude certain files
@@ -32,55 +30,2047 @@
 //
 // Example:
 // $ snapshotter <source_dir> <target_dir>
+// $ snapshotter diff <backup_a> <backup_b> [--hash] [--json]
+// $ snapshotter list <target_dir> [--name-template <template>]
+// $ snapshotter latest <target_dir> [--source-name <name>] [--name-template <template>]
+// $ snapshotter contents <backup_dir> [--json]
+// $ snapshotter restore <backup_dir|manifest> <dest_dir> [--pick <pattern>]...
+// $ snapshotter prune <target_dir>
+// $ snapshotter check-config [--config <path>] [section]...
 
-mod backup;
-mod config;
+// `describe_options` lists every option in a single `json!` invocation
+#![recursion_limit = "256"]
 
-use chrono::Local;
+use backup::{
+    BackupStats, CaseCollisions, CopyOptions, OwnerFilter, PathStyle, ReflinkMode, SourceReport,
+    SparseMode,
+};
+use chrono::{DateTime, Local, Utc};
 use config::Config;
+use events::{Diagnostics, Event, EventSink, HumanSink, JsonLinesSink};
+use format::{parse_duration, parse_size};
+use journal::Journal;
+use naming::{NameTemplate, NameValues};
+use serde_json::{json, Value};
+#[cfg(feature = "notify")]
+use snapshotter::notify;
+#[cfg(feature = "repo")]
+use snapshotter::repo;
+use snapshotter::{
+    backup, catalog, config, diff, events, format, fs_space, hooks, journal, metrics, mirror,
+    naming, patterns, summary,
+};
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
+use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-fn main() {
-    // Get command-line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 || args.len() > 4 {
-        eprintln!(
-            "Usage: {} <source_dir> <target_dir> [config_section]",
-            args[0]
+/// Whether exclusions match regardless of case when neither `--ignore-case` nor
+/// `--case-sensitive` is given: file names are case-insensitive on Windows and macOS.
+const IGNORE_CASE_BY_DEFAULT: bool = cfg!(any(windows, target_os = "macos"));
+
+/// Command-line arguments accepted by the utility.
+struct Args {
+    source_dirs: Vec<String>,
+    target_dir: String,
+    config_section: String,
+    /// Configuration file to use instead of `config.json` next to the executable.
+    config_path: Option<String>,
+    metrics_file: Option<String>,
+    summary_file: Option<String>,
+    clean_on_failure: bool,
+    sparse: Option<SparseMode>,
+    /// `None` copies the contents of a source directly into its backup directory.
+    backup_path_style: Option<PathStyle>,
+    /// Directory the source path is made relative to with `--backup-path-style relative`.
+    relative_root: Option<String>,
+    reflink: Option<ReflinkMode>,
+    max_total_size: Option<u64>,
+    notify_webhook: Option<String>,
+    /// Creates a snapshot of a source and prints its root, which is copied instead.
+    snapshot_command: Option<String>,
+    /// Removes a snapshot created by `snapshot_command`.
+    release_snapshot_command: Option<String>,
+    /// Runs before each source is backed up; a failure skips the source.
+    pre_hook: Option<String>,
+    /// Runs after each source was backed up, successfully or not.
+    post_hook: Option<String>,
+    skip_hidden: bool,
+    exclude_mime_types: Vec<String>,
+    special_files: bool,
+    quiet: bool,
+    /// Exit with a failure status when the run reported any warning.
+    warnings_as_errors: bool,
+    /// Fail the run on files it is not allowed to read instead of skipping them with a warning.
+    fail_on_unreadable: bool,
+    no_space_check: bool,
+    json_lines: bool,
+    no_lock: bool,
+    /// How long to wait for another run on the same target to finish.
+    wait_for_lock: Option<Duration>,
+    /// `None` preserves owners only when running as root.
+    preserve_owner: Option<bool>,
+    /// Copy only files of these users and groups.
+    owner_filter: Option<OwnerFilter>,
+    one_file_system: bool,
+    /// Follow a source directory that is a symbolic link instead of refusing it.
+    dereference_root: bool,
+    preserve_acls: bool,
+    name_template: Option<NameTemplate>,
+    utc: bool,
+    /// Back up into the target directory itself instead of a new directory inside it.
+    no_timestamp: bool,
+    /// Keep a journal of the copied files, and continue an interrupted backup of the source.
+    resume: bool,
+    mirror: bool,
+    no_delete: bool,
+    /// Let runs delete from a target that also holds entries snapshotter did not create.
+    allow_mixed_target: bool,
+    /// Compare the contents of files that look unchanged by size and modification time.
+    paranoid: bool,
+    dry_run: bool,
+    no_empty_dirs: bool,
+    dedup: bool,
+    flatten: bool,
+    /// Store files as deduplicated chunks with a manifest per backup (requires the `repo` feature).
+    repo: bool,
+    max_depth: Option<usize>,
+    /// File listing the entries to copy instead of walking the source (`-` for standard input).
+    files_from: Option<String>,
+    /// The entries in `files_from` are separated by NUL bytes instead of line breaks.
+    files_from_nul: bool,
+    /// The entries read from `files_from` by `main`, as standard input can only be read once.
+    listed_entries: Option<Vec<PathBuf>>,
+    /// Treat the target as case-insensitive without probing it.
+    target_case_insensitive: bool,
+    /// Copy entries whose names collide on a case-insensitive target under a numbered name.
+    rename_collisions: bool,
+    /// `None` follows the platform convention (see `IGNORE_CASE_BY_DEFAULT`).
+    ignore_case: Option<bool>,
+    retries: Option<u32>,
+    retry_delay: Option<Duration>,
+    retry_os_errors: Vec<i32>,
+}
+
+/// Parses the command-line arguments (without the program name) into `Args`.
+///
+/// # Returns
+/// - `Ok(Args)` if the positional arguments and options are valid.
+/// - `Err(String)` describing the problem otherwise.
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut positional = Vec::new();
+    let mut extra_sources = Vec::new();
+    let mut config_path = None;
+    let mut metrics_file = None;
+    let mut summary_file = None;
+    let mut clean_on_failure = false;
+    let mut sparse = None;
+    let mut backup_path_style = None;
+    let mut relative_root = None;
+    let mut reflink = None;
+    let mut max_total_size = None;
+    let mut notify_webhook = None;
+    let mut snapshot_command = None;
+    let mut release_snapshot_command = None;
+    let mut pre_hook = None;
+    let mut post_hook = None;
+    let mut skip_hidden = false;
+    let mut exclude_mime_types = Vec::new();
+    let mut special_files = false;
+    let mut quiet = false;
+    let mut warnings_as_errors = false;
+    let mut fail_on_unreadable = false;
+    let mut no_space_check = false;
+    let mut json_lines = false;
+    let mut no_lock = false;
+    let mut wait_for_lock = None;
+    let mut preserve_owner = None;
+    let mut owners = Vec::new();
+    let mut groups = Vec::new();
+    let mut one_file_system = false;
+    let mut dereference_root = false;
+    let mut preserve_acls = false;
+    let mut name_template = None;
+    let mut utc = false;
+    let mut no_timestamp = false;
+    let mut resume = false;
+    let mut mirror = false;
+    let mut no_delete = false;
+    let mut allow_mixed_target = false;
+    let mut paranoid = false;
+    let mut dry_run = false;
+    let mut no_empty_dirs = false;
+    let mut dedup = false;
+    let mut flatten = false;
+    let mut repo = false;
+    let mut max_depth = None;
+    let mut files_from = None;
+    let mut files_from_nul = false;
+    let mut target_case_insensitive = false;
+    let mut rename_collisions = false;
+    let mut ignore_case = None;
+    let mut retries = None;
+    let mut retry_delay = None;
+    let mut retry_os_errors = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--config" => {
+                let value = iter.next().ok_or("--config requires a path")?;
+                config_path = Some(value.clone());
+            }
+            "--metrics-file" => {
+                let value = iter.next().ok_or("--metrics-file requires a path")?;
+                metrics_file = Some(value.clone());
+            }
+            "--summary-file" => {
+                let value = iter.next().ok_or("--summary-file requires a path")?;
+                summary_file = Some(value.clone());
+            }
+            "--source" => {
+                let value = iter.next().ok_or("--source requires a directory")?;
+                extra_sources.push(value.clone());
+            }
+            "--clean-on-failure" => clean_on_failure = true,
+            "--skip-hidden" | "--exclude-hidden" => skip_hidden = true,
+            "--special-files" => special_files = true,
+            "--quiet" | "-q" => quiet = true,
+            "--warnings-as-errors" => warnings_as_errors = true,
+            "--fail-on-unreadable" => fail_on_unreadable = true,
+            "--no-space-check" => no_space_check = true,
+            "--no-lock" => no_lock = true,
+            "--preserve-owner" => preserve_owner = Some(true),
+            "--no-preserve-owner" => preserve_owner = Some(false),
+            "--owner" => {
+                let value = iter.next().ok_or("--owner requires a user")?;
+                owners.push(value.clone());
+            }
+            "--group" => {
+                let value = iter.next().ok_or("--group requires a group")?;
+                groups.push(value.clone());
+            }
+            "--one-file-system" | "-x" => one_file_system = true,
+            "--dereference-root" => dereference_root = true,
+            "--preserve-acls" => preserve_acls = true,
+            "--mirror" => mirror = true,
+            "--utc" => utc = true,
+            "--no-timestamp" => no_timestamp = true,
+            "--resume" => resume = true,
+            "--no-delete" => no_delete = true,
+            "--allow-mixed-target" => allow_mixed_target = true,
+            "--paranoid" => paranoid = true,
+            "--dry-run" | "-n" => dry_run = true,
+            "--no-empty-dirs" => no_empty_dirs = true,
+            "--dedup" => dedup = true,
+            "--flatten" => flatten = true,
+            "--repo" => repo = true,
+            "--target-case-insensitive" => target_case_insensitive = true,
+            "--rename-collisions" => rename_collisions = true,
+            "--ignore-case" => ignore_case = Some(true),
+            "--case-sensitive" => ignore_case = Some(false),
+            "--output" => {
+                json_lines = match iter.next().map(String::as_str) {
+                    Some("human") => false,
+                    Some("json-lines") => true,
+                    _ => return Err("--output requires one of: human, json-lines".to_string()),
+                };
+            }
+            "--backup-path-style" => {
+                backup_path_style = match iter.next().map(String::as_str) {
+                    Some("basename") => Some(PathStyle::Basename),
+                    Some("relative") => Some(PathStyle::Relative),
+                    Some("absolute") => Some(PathStyle::Absolute),
+                    _ => {
+                        return Err(
+                            "--backup-path-style requires one of: basename, relative, absolute"
+                                .to_string(),
+                        )
+                    }
+                };
+            }
+            "--relative-root" => {
+                let value = iter.next().ok_or("--relative-root requires a directory")?;
+                relative_root = Some(value.clone());
+            }
+            "--sparse" => {
+                sparse = match iter.next().map(String::as_str) {
+                    Some("auto") => Some(SparseMode::Auto),
+                    Some("always") => Some(SparseMode::Always),
+                    Some("never") => Some(SparseMode::Never),
+                    _ => return Err("--sparse requires one of: auto, always, never".to_string()),
+                };
+            }
+            "--reflink" => {
+                reflink = match iter.next().map(String::as_str) {
+                    Some("auto") => Some(ReflinkMode::Auto),
+                    Some("always") => Some(ReflinkMode::Always),
+                    Some("never") => Some(ReflinkMode::Never),
+                    _ => return Err("--reflink requires one of: auto, always, never".to_string()),
+                };
+            }
+            "--max-total-size" => {
+                let value = iter.next().ok_or("--max-total-size requires a size")?;
+                max_total_size = Some(parse_size(value)?);
+            }
+            "--wait-for-lock" => {
+                let value = iter.next().ok_or("--wait-for-lock requires a duration")?;
+                wait_for_lock = Some(parse_duration(value)?);
+            }
+            "--max-depth" => {
+                let value = iter.next().ok_or("--max-depth requires a number")?;
+                max_depth = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid depth: {}", value))?,
+                );
+            }
+            "--retries" => {
+                let value = iter.next().ok_or("--retries requires a count")?;
+                retries = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid retry count: {}", value))?,
+                );
+            }
+            "--retry-delay" => {
+                let value = iter.next().ok_or("--retry-delay requires milliseconds")?;
+                let millis = value
+                    .parse()
+                    .map_err(|_| format!("Invalid retry delay: {}", value))?;
+                retry_delay = Some(Duration::from_millis(millis));
+            }
+            "--retry-errno" => {
+                let value = iter.next().ok_or("--retry-errno requires an error code")?;
+                retry_os_errors.push(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid error code: {}", value))?,
+                );
+            }
+            "--exclude-mime" => {
+                let value = iter.next().ok_or("--exclude-mime requires a MIME type")?;
+                exclude_mime_types.push(value.clone());
+            }
+            "--name-template" => {
+                let value = iter.next().ok_or("--name-template requires a template")?;
+                name_template = Some(NameTemplate::parse(value)?);
+            }
+            "--notify-webhook" | "--notify-url" => {
+                let value = iter.next().ok_or(format!("{} requires a URL", arg))?;
+                notify_webhook = Some(value.clone());
+            }
+            "--snapshot-command" => {
+                let value = iter.next().ok_or("--snapshot-command requires a command")?;
+                snapshot_command = Some(value.clone());
+            }
+            "--release-snapshot-command" => {
+                let value = iter
+                    .next()
+                    .ok_or("--release-snapshot-command requires a command")?;
+                release_snapshot_command = Some(value.clone());
+            }
+            "--files-from" | "--files-from0" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("{} requires a path or -", arg))?;
+                files_from = Some(value.clone());
+                files_from_nul = arg == "--files-from0";
+            }
+            "--pre-hook" => {
+                let value = iter.next().ok_or("--pre-hook requires a command")?;
+                pre_hook = Some(value.clone());
+            }
+            "--post-hook" => {
+                let value = iter.next().ok_or("--post-hook requires a command")?;
+                post_hook = Some(value.clone());
+            }
+            option if option.starts_with("--") => {
+                return Err(format!("Unknown option: {}", option));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if positional.len() < 2 || positional.len() > 3 {
+        return Err("Expected <source_dir> <target_dir> [config_section]".to_string());
+    }
+
+    // Additional sources given with --source are backed up after the positional one
+    let mut source_dirs = vec![positional[0].clone()];
+    source_dirs.extend(extra_sources);
+
+    Ok(Args {
+        source_dirs,
+        target_dir: positional[1].clone(),
+        // Fallback to default section if none is specified
+        config_section: positional
+            .get(2)
+            .cloned()
+            .unwrap_or_else(|| "default".to_string()),
+        config_path,
+        metrics_file,
+        summary_file,
+        clean_on_failure,
+        sparse,
+        backup_path_style,
+        relative_root,
+        reflink,
+        max_total_size,
+        notify_webhook,
+        snapshot_command,
+        release_snapshot_command,
+        pre_hook,
+        post_hook,
+        skip_hidden,
+        exclude_mime_types,
+        special_files,
+        quiet,
+        warnings_as_errors,
+        fail_on_unreadable,
+        no_space_check,
+        json_lines,
+        no_lock,
+        wait_for_lock,
+        preserve_owner,
+        owner_filter: OwnerFilter::resolve(&owners, &groups)?,
+        one_file_system,
+        dereference_root,
+        preserve_acls,
+        name_template,
+        utc,
+        no_timestamp,
+        resume,
+        mirror,
+        no_delete,
+        allow_mixed_target,
+        paranoid,
+        dry_run,
+        no_empty_dirs,
+        dedup,
+        flatten,
+        repo,
+        max_depth,
+        files_from,
+        files_from_nul,
+        listed_entries: None,
+        target_case_insensitive,
+        rename_collisions,
+        ignore_case,
+        retries,
+        retry_delay,
+        retry_os_errors,
+    })
+}
+
+/// Checks that no source is the target directory itself, which would back a directory up
+/// into itself. With `--no-timestamp`, sources inside the target are refused as well, since
+/// the backup would be written around them (and a mirror would delete them).
+///
+/// # Errors
+/// - Will return an `InvalidInput` error naming the offending source.
+fn check_source_not_target(args: &Args) -> std::io::Result<()> {
+    // A target that doesn't exist yet cannot be one of the sources
+    let Ok(target) = fs::canonicalize(&args.target_dir) else {
+        return Ok(());
+    };
+    for source_dir in &args.source_dirs {
+        if fs::canonicalize(source_dir).is_ok_and(|source| source == target) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Source and target are the same directory: {:?}", target),
+            ));
+        }
+        if args.no_timestamp
+            && fs::canonicalize(source_dir).is_ok_and(|source| source.starts_with(&target))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--no-timestamp writes into the target directory itself, which contains the source {:?}",
+                    source_dir
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Warns about entries in the target directory that snapshotter did not create, which usually
+/// means the target was mistyped. Runs that may delete from the target (`--mirror` without
+/// `--no-delete`, `--clean-on-failure`) are refused then, unless `--allow-mixed-target` is
+/// given.
+///
+/// With `--no-timestamp` the target holds the copy itself, so its entries cannot be told apart
+/// by name; it counts as snapshotter's once a run left its marker file (or its lock file, for
+/// targets written before there was a marker) there.
+///
+/// # Errors
+/// - Will return an `InvalidInput` error if a run that deletes would go ahead in a target with
+///   foreign entries.
+/// - Will return an error if the target cannot be read.
+fn check_mixed_target(args: &Args, sink: &dyn EventSink) -> std::io::Result<()> {
+    let target = Path::new(&args.target_dir);
+    let mut foreign = if !args.no_timestamp {
+        let template = args.name_template.clone().unwrap_or_default();
+        catalog::foreign_entries(target, &template)?
+    } else if target.is_dir()
+        && !target.join(backup::MARKER_FILE_NAME).exists()
+        && !target.join(backup::LOCK_FILE_NAME).exists()
+    {
+        let mut entries = fs::read_dir(target)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        entries
+    } else {
+        Vec::new()
+    };
+    // Run reports configured to be written into the target are expected there
+    foreign.retain(|path| {
+        ![&args.summary_file, &args.metrics_file]
+            .into_iter()
+            .flatten()
+            .any(|file| Path::new(file) == path)
+    });
+    if foreign.is_empty() {
+        return Ok(());
+    }
+
+    let mut listed: Vec<_> = foreign
+        .iter()
+        .take(5)
+        .map(|path| format!("{:?}", path.file_name().unwrap_or_default()))
+        .collect();
+    if foreign.len() > listed.len() {
+        listed.push(format!("and {} more", foreign.len() - listed.len()));
+    }
+    let deletes = (args.mirror && !args.no_delete) || args.clean_on_failure;
+    if deletes && !args.allow_mixed_target {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "The target {:?} contains entries snapshotter did not create ({}); pass --allow-mixed-target to let --mirror or --clean-on-failure delete anything there",
+                target,
+                listed.join(", ")
+            ),
+        ));
+    }
+    sink.emit(&Event::Warning {
+        message: format!(
+            "the target {:?} contains entries snapshotter did not create, is it the right directory? {}",
+            target,
+            listed.join(", ")
+        ),
+    });
+    Ok(())
+}
+
+/// Checks that the options of a backup fit together, after the configuration section was
+/// applied: a conflict can come from the command line, the configuration or one of each.
+///
+/// # Errors
+/// - Returns a message naming the conflicting options.
+fn validate_args(args: &Args) -> Result<(), String> {
+    if args.no_delete && !args.mirror {
+        return Err("--no-delete requires --mirror".to_string());
+    }
+    // Updating a hard-linked file in place would change every file linked to it
+    if args.dedup && args.mirror {
+        return Err("--dedup cannot be combined with --mirror".to_string());
+    }
+    // A mirror is updated by comparing paths, which flattening does not keep
+    if args.flatten && args.mirror {
+        return Err("--flatten cannot be combined with --mirror".to_string());
+    }
+    // A mirror deletes whatever was not copied, which would be everything that is not listed
+    if args.files_from.is_some() && args.mirror {
+        return Err("--files-from cannot be combined with --mirror".to_string());
+    }
+    // Renamed entries would not be found in the source on the next update, and be deleted
+    if args.rename_collisions && args.mirror {
+        return Err("--rename-collisions cannot be combined with --mirror".to_string());
+    }
+    if args.no_timestamp && args.name_template.is_some() {
+        return Err("--name-template cannot be combined with --no-timestamp".to_string());
+    }
+    // Copies updated in place are continued by any run, and flattened names depend on the order
+    // in which files were copied
+    if args.resume && (args.mirror || args.no_timestamp || args.flatten) {
+        return Err(
+            "--resume cannot be combined with --mirror, --no-timestamp or --flatten".to_string(),
+        );
+    }
+    // Several sources copied into the same directory would overwrite each other's files
+    if args.no_timestamp && args.source_dirs.len() > 1 {
+        return Err("--no-timestamp takes a single source directory".to_string());
+    }
+    if args.backup_path_style == Some(PathStyle::Relative) && args.relative_root.is_none() {
+        return Err("--backup-path-style relative requires --relative-root".to_string());
+    }
+    if args.repo && !cfg!(feature = "repo") {
+        return Err(
+            "--repo requires building with the repo feature (cargo build --features repo)"
+                .to_string(),
+        );
+    }
+    // A repository only stores manifests of new backups, with paths relative to the source;
+    // chunks are already shared between all of them
+    if args.repo
+        && (args.mirror
+            || args.no_timestamp
+            || args.resume
+            || args.dedup
+            || args.flatten
+            || args.backup_path_style.is_some())
+    {
+        return Err("--repo cannot be combined with --mirror, --no-timestamp, --resume, --dedup, --flatten or --backup-path-style".to_string());
+    }
+    // Sources with the same name would share one mirror directory, where each would delete
+    // what the other copied
+    if args.mirror {
+        let mut mirrored = HashMap::new();
+        for source_dir in &args.source_dirs {
+            // A source without a name fails on its own when it is backed up
+            let Ok(name) = backup::source_name(Path::new(source_dir)) else {
+                continue;
+            };
+            if let Some(other) = mirrored.insert(name.clone(), source_dir) {
+                return Err(format!(
+                    "--mirror would copy {} and {} into the same mirror directory {}_mirror; back them up to different targets",
+                    other, source_dir, name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fills in options that were not given on the command line from the configuration section.
+///
+/// # Errors
+/// - Returns a message if a configured value is invalid.
+fn apply_config(args: &mut Args, config: &Config) -> Result<(), String> {
+    if args.metrics_file.is_none() {
+        args.metrics_file = config.metrics_file.clone();
+    }
+    if args.summary_file.is_none() {
+        args.summary_file = config.summary_file.clone();
+    }
+    if args.snapshot_command.is_none() {
+        args.snapshot_command = config.snapshot_command.clone();
+    }
+    if args.release_snapshot_command.is_none() {
+        args.release_snapshot_command = config.release_snapshot_command.clone();
+    }
+    if args.pre_hook.is_none() {
+        args.pre_hook = config.pre_hook.clone();
+    }
+    if args.post_hook.is_none() {
+        args.post_hook = config.post_hook.clone();
+    }
+    if args.max_total_size.is_none() {
+        if let Some(size) = &config.max_total_size {
+            args.max_total_size = Some(parse_size(size)?);
+        }
+    }
+    args.sparse = args.sparse.or(config.sparse);
+    args.backup_path_style = args.backup_path_style.or(config.backup_path_style);
+    if args.relative_root.is_none() {
+        args.relative_root = config.relative_root.clone();
+    }
+    args.reflink = args.reflink.or(config.reflink);
+    args.preserve_owner = args.preserve_owner.or(config.preserve_owner);
+    args.ignore_case = args.ignore_case.or(config.case_insensitive_patterns);
+    args.wait_for_lock = args
+        .wait_for_lock
+        .or(config.wait_for_lock_secs.map(Duration::from_secs));
+    args.max_depth = args.max_depth.or(config.max_depth);
+    args.retries = args.retries.or(config.retries);
+    args.retry_delay = args
+        .retry_delay
+        .or(config.retry_delay_ms.map(Duration::from_millis));
+    if args.name_template.is_none() {
+        if let Some(template) = &config.name_template {
+            args.name_template = Some(NameTemplate::parse(template)?);
+        }
+    }
+    if args.exclude_mime_types.is_empty() {
+        args.exclude_mime_types = config.exclude_mime.clone();
+    }
+    if args.retry_os_errors.is_empty() {
+        args.retry_os_errors = config.retry_errno.clone();
+    }
+
+    // Switches can only be turned on from the command line, so either source enables them
+    args.clean_on_failure |= config.clean_on_failure.unwrap_or(false);
+    args.skip_hidden |= config.skip_hidden.unwrap_or(false);
+    args.special_files |= config.special_files.unwrap_or(false);
+    args.quiet |= config.quiet.unwrap_or(false);
+    args.warnings_as_errors |= config.warnings_as_errors.unwrap_or(false);
+    args.fail_on_unreadable |= config.fail_on_unreadable.unwrap_or(false);
+    args.no_space_check |= config.no_space_check.unwrap_or(false);
+    args.no_lock |= config.no_lock.unwrap_or(false);
+    args.one_file_system |= config.one_file_system.unwrap_or(false);
+    args.dereference_root |= config.dereference_root.unwrap_or(false);
+    args.preserve_acls |= config.preserve_acls.unwrap_or(false);
+    args.mirror |= config.mirror.unwrap_or(false);
+    args.utc |= config.utc.unwrap_or(false);
+    args.no_timestamp |= config.no_timestamp.unwrap_or(false);
+    args.resume |= config.resume.unwrap_or(false);
+    args.no_delete |= config.no_delete.unwrap_or(false);
+    args.allow_mixed_target |= config.allow_mixed_target.unwrap_or(false);
+    args.paranoid |= config.paranoid.unwrap_or(false);
+    args.no_empty_dirs |= config.no_empty_dirs.unwrap_or(false);
+    args.dedup |= config.dedup.unwrap_or(false);
+    args.flatten |= config.flatten.unwrap_or(false);
+    args.repo |= config.repo.unwrap_or(false);
+    args.target_case_insensitive |= config.target_case_insensitive.unwrap_or(false);
+    args.rename_collisions |= config.rename_collisions.unwrap_or(false);
+    Ok(())
+}
+
+/// Describes the effective options of a run, for the summary file.
+fn describe_options(args: &Args, config: &Config) -> Value {
+    json!({
+        "source_dirs": args.source_dirs,
+        "excluded_items": config.excluded_items,
+        "excluded_extensions": config.excluded_extensions,
+        "exclude_mime": args.exclude_mime_types,
+        "ignore_case": args.ignore_case.unwrap_or(IGNORE_CASE_BY_DEFAULT),
+        "skip_hidden": args.skip_hidden,
+        "special_files": args.special_files,
+        "one_file_system": args.one_file_system,
+        "dereference_root": args.dereference_root,
+        "max_total_size": args.max_total_size,
+        "sparse": args.sparse.unwrap_or_default(),
+        "backup_path_style": args.backup_path_style.unwrap_or_default(),
+        "relative_root": args.relative_root,
+        "reflink": args.reflink.unwrap_or_default(),
+        "preserve_owner": args.preserve_owner.unwrap_or_else(backup::running_as_root),
+        "owner_filter": args.owner_filter,
+        "preserve_acls": args.preserve_acls,
+        "name_template": args.name_template.as_ref().map_or(naming::DEFAULT_TEMPLATE, NameTemplate::as_str),
+        "utc": args.utc,
+        "no_timestamp": args.no_timestamp,
+        "resume": args.resume,
+        "mirror": args.mirror,
+        "no_delete": args.no_delete,
+        "allow_mixed_target": args.allow_mixed_target,
+        "paranoid": args.paranoid,
+        "no_empty_dirs": args.no_empty_dirs,
+        "dedup": args.dedup,
+        "flatten": args.flatten,
+        "repo": args.repo,
+        "max_depth": args.max_depth,
+        "files_from": args.files_from,
+        "target_case_insensitive": args.target_case_insensitive,
+        "rename_collisions": args.rename_collisions,
+        "clean_on_failure": args.clean_on_failure,
+        "no_space_check": args.no_space_check,
+        "no_lock": args.no_lock,
+        "warnings_as_errors": args.warnings_as_errors,
+        "fail_on_unreadable": args.fail_on_unreadable,
+        "wait_for_lock_secs": args.wait_for_lock.map_or(0, |wait| wait.as_secs()),
+        "retries": args.retries.unwrap_or(0),
+        "retry_delay_ms": args.retry_delay.map_or(500, |delay| delay.as_millis()),
+        "retry_errno": args.retry_os_errors,
+        "snapshot_command": args.snapshot_command,
+        "release_snapshot_command": args.release_snapshot_command,
+        "pre_hook": args.pre_hook,
+        "post_hook": args.post_hook,
+    })
+}
+
+/// A source directory to back up and, with `--snapshot-command`, the snapshot it is read from.
+struct SourcePaths<'a> {
+    /// The source directory as given; it names the backup.
+    dir: &'a Path,
+    /// Root of a snapshot of `dir`, copied instead of `dir` itself.
+    snapshot: Option<&'a Path>,
+}
+
+/// Backs up a source through `run_backup`, reading it from a snapshot if a snapshot command is
+/// configured. The snapshot is released afterwards, also when the backup failed. The pre-hook
+/// runs first and keeps the source from being backed up if it fails.
+///
+/// # Errors
+/// - Will return an error if the backup, the pre-hook or a snapshot command fails.
+fn backup_source(
+    source_dir: &Path,
+    budget: Option<u64>,
+    args: &Args,
+    config: &Config,
+    stats: &mut BackupStats,
+    cancel: &AtomicBool,
+    sink: &dyn EventSink,
+) -> std::io::Result<PathBuf> {
+    // Hooks and snapshots act outside snapshotter, which a dry run must not do
+    if let Some(pre_hook) = args.pre_hook.as_ref().filter(|_| !args.dry_run) {
+        let env = [("SNAPSHOTTER_SOURCE", source_dir.as_os_str())];
+        hooks::run_hook(pre_hook, "pre-hook", &env, sink)?;
+    }
+
+    // A dry run reads the source itself rather than creating snapshots
+    let Some(command) = args.snapshot_command.as_ref().filter(|_| !args.dry_run) else {
+        let source = SourcePaths {
+            dir: source_dir,
+            snapshot: None,
+        };
+        return run_backup(&source, budget, args, config, stats, cancel, sink);
+    };
+
+    let canonical = fs::canonicalize(source_dir)?;
+    let snapshot = hooks::take_snapshot(command, &canonical)?;
+    let source = SourcePaths {
+        dir: source_dir,
+        snapshot: Some(&snapshot),
+    };
+    let result = run_backup(&source, budget, args, config, stats, cancel, sink);
+
+    let released = match &args.release_snapshot_command {
+        Some(release) => hooks::release_snapshot(release, &canonical, &snapshot),
+        None => Ok(()),
+    };
+    // A failed backup is the more useful error; report the release failure separately then
+    match (result, released) {
+        (Ok(backup_dir), Ok(())) => Ok(backup_dir),
+        (Ok(_), Err(e)) => Err(e),
+        (Err(e), Ok(())) => Err(e),
+        (Err(e), Err(release_err)) => {
+            sink.emit(&Event::Error {
+                message: release_err.to_string(),
+            });
+            Err(e)
+        }
+    }
+}
+
+/// Creates a timestamped backup directory inside the target and copies a source into it.
+///
+/// The backup is written under an in-progress name and only renamed to its final name once
+/// copying succeeded. On failure it is left under the in-progress name, or removed when
+/// `--clean-on-failure` is set. A target directory nested inside the source is excluded from
+/// the copy so the backup never contains itself. Unless `--no-space-check` is set, the run
+/// fails before copying anything when the target filesystem is too small.
+///
+/// With `--mirror`, the source is instead copied into `<target>/<source_name>_mirror` in place:
+/// unchanged files are skipped and, unless `--no-delete` is set, entries that no longer exist
+/// in the source are deleted once the copy succeeded.
+///
+/// With `--repo`, the target is a repository: the files are stored as chunks shared by all
+/// backups, and the backup is a manifest named like the backup directory would be.
+///
+/// When the source is read from a snapshot, the backup is still named after the source, and
+/// exclusions and relative paths are based on the snapshot root.
+///
+/// # Parameters
+/// - `source_paths`: Source directory to back up, and the snapshot to read it from.
+/// - `budget`: Bytes this source may still copy under `--max-total-size`, if set.
+/// - `args`: Parsed command-line arguments.
+/// - `config`: Exclusion settings loaded from the configuration file.
+/// - `stats`: Counters updated while copying, available to the caller even on failure.
+/// - `cancel`: Flag that stops copying when set (e.g. by the Ctrl-C handler).
+/// - `sink`: Receives progress, warning and error events.
+///
+/// # Returns
+/// - The final path of the backup directory (or the mirror, or the manifest).
+///
+/// # Errors
+/// - Will return an error if the backup directory cannot be created, copying fails or, in
+///   mirror mode, a deleted entry cannot be removed.
+fn run_backup(
+    source_paths: &SourcePaths,
+    budget: Option<u64>,
+    args: &Args,
+    config: &Config,
+    stats: &mut BackupStats,
+    cancel: &AtomicBool,
+    sink: &dyn EventSink,
+) -> std::io::Result<PathBuf> {
+    let source_dir = source_paths.dir;
+    // Canonical paths no longer show whether the source itself is a link
+    if !args.dereference_root {
+        backup::refuse_symlinked_root(source_dir)?;
+    }
+    // Compare canonical paths so relative paths and symlinked roots are detected too
+    let original_source = fs::canonicalize(source_dir)?;
+    let source = match source_paths.snapshot {
+        Some(snapshot) => fs::canonicalize(snapshot)?,
+        None => original_source.clone(),
+    };
+    // Checked before anything is created, so a source outside --relative-root leaves no trace
+    let relative_root = args
+        .relative_root
+        .as_ref()
+        .map(fs::canonicalize)
+        .transpose()?;
+    let subpath = backup::backup_subpath(
+        &original_source,
+        args.backup_path_style.unwrap_or_default(),
+        relative_root.as_deref(),
+    )?;
+    let backup_dir = if args.no_timestamp {
+        let target = PathBuf::from(&args.target_dir);
+        if !args.dry_run {
+            fs::create_dir_all(&target)?;
+            backup::mark_target(&target)?;
+        }
+        target
+    } else if args.mirror {
+        let mirror = mirror::mirror_dir(
+            Path::new(&args.target_dir),
+            &backup::source_name(source_dir)?,
+        );
+        if !args.dry_run {
+            fs::create_dir_all(&mirror)?;
+        }
+        mirror
+    } else {
+        let name = args
+            .name_template
+            .clone()
+            .unwrap_or_default()
+            .render(&NameValues {
+                source: &backup::source_name(source_dir)?,
+                time: naming::name_time(Utc::now(), args.utc),
+                utc: args.utc,
+                hostname: &naming::hostname(),
+                profile: &args.config_section,
+            })
+            .map_err(|message| Error::new(ErrorKind::InvalidInput, message))?;
+        let path = Path::new(&args.target_dir).join(&name);
+        // A repository only gets a manifest of that name, once its files are stored
+        if args.dry_run || args.repo {
+            path
+        } else {
+            // An interrupted run is continued in its in-progress directory, whatever its name
+            let resumable = match path.parent().filter(|_| args.resume) {
+                Some(dir) => journal::find_resumable(dir, &original_source)?,
+                None => None,
+            };
+            match resumable {
+                Some(backup_dir) => backup_dir,
+                None => backup::create_backup_dir(Path::new(&args.target_dir), &name)?,
+            }
+        }
+    };
+
+    let mut options = CopyOptions {
+        exclude_list: config.exclusions.clone(),
+        exclude_extensions: config.excluded_extensions.clone(),
+        exclude_mime_types: args.exclude_mime_types.clone(),
+        sparse: args.sparse.unwrap_or_default(),
+        reflink: args.reflink.unwrap_or_default(),
+        max_total_size: budget,
+        skip_hidden: args.skip_hidden,
+        special_files: args.special_files,
+        retries: args.retries.unwrap_or(0),
+        retry_delay: args.retry_delay.unwrap_or(Duration::from_millis(500)),
+        retry_os_errors: args.retry_os_errors.clone(),
+        preserve_owner: args.preserve_owner.unwrap_or_else(backup::running_as_root),
+        preserve_acls: args.preserve_acls,
+        case_insensitive: args.ignore_case.unwrap_or(IGNORE_CASE_BY_DEFAULT),
+        update_in_place: args.mirror || args.no_timestamp,
+        dry_run: args.dry_run,
+        no_empty_dirs: args.no_empty_dirs,
+        dedup: args.dedup,
+        flatten: args.flatten,
+        max_depth: args.max_depth,
+        files_from: args.listed_entries.clone(),
+        owner_filter: args.owner_filter.clone(),
+        fail_on_unreadable: args.fail_on_unreadable,
+        paranoid: args.paranoid,
+        ..CopyOptions::default()
+    };
+
+    // Only a dry run can get here without the target, and then there is nothing to exclude
+    if let Ok(target) = fs::canonicalize(&args.target_dir) {
+        // A snapshot holds the target at the same place relative to its root
+        let target = match target.strip_prefix(&original_source) {
+            Ok(relative) => source.join(relative),
+            Err(_) => target,
+        };
+        if target.starts_with(&source) {
+            sink.emit(&Event::Warning {
+                message: format!(
+                    "{:?} is inside the source directory and is excluded from the backup",
+                    target
+                ),
+            });
+            options.excluded_paths.push(target);
+        }
+    }
+    // The lock and marker files sit in the backup itself, where a file of the same name from
+    // the source would replace them and a mirror would delete them
+    if args.no_timestamp {
+        for name in [backup::LOCK_FILE_NAME, backup::MARKER_FILE_NAME] {
+            options.excluded_paths.push(source.join(name));
+        }
+    }
+    // Directories on another device than the source root are mount points
+    if args.one_file_system {
+        options.one_file_system = Some(backup::device_id(&fs::metadata(&source)?));
+    }
+
+    #[cfg(feature = "repo")]
+    if args.repo && !args.dry_run {
+        let target = Path::new(&args.target_dir);
+        let name = backup_dir.strip_prefix(target).unwrap_or(&backup_dir);
+        return repo::store_backup(
+            &source,
+            target,
+            &name.to_string_lossy(),
+            &options,
+            stats,
+            cancel,
+            sink,
+        );
+    }
+
+    let journal = if args.resume && !args.dry_run {
+        if let Ok((_, copied)) = journal::read_journal(&backup_dir) {
+            options.already_copied = copied.iter().map(|path| source.join(path)).collect();
+        }
+        Some(Journal::open(&backup_dir, &original_source, &source, sink)?)
+    } else {
+        None
+    };
+    let sink: &dyn EventSink = match &journal {
+        Some(journal) => journal,
+        None => sink,
+    };
+
+    // Fail fast instead of running out of space halfway through the copy. A copy updated in
+    // place (or a resumed one) only copies what is missing, so the full size of the source
+    // says little about what it needs
+    let resuming = !options.already_copied.is_empty();
+    if !args.no_space_check && !options.update_in_place && !resuming && !args.dry_run {
+        let space_check = backup::estimate_size(&source, &backup_dir.join(&subpath), &options)
+            .and_then(|needed| fs_space::ensure_space(&backup_dir, needed));
+        if let Err(e) = space_check {
+            // Nothing was written yet, so don't leave an empty backup directory behind
+            let _ = fs::remove_dir(&backup_dir);
+            return Err(e);
+        }
+    }
+
+    // Copy source directory to backup directory. Absolute paths let the standard library use
+    // extended-length (`\\?\`) paths on Windows, so deep trees aren't limited to 260 characters
+    let dest = if args.dry_run {
+        backup_dir.join(&subpath)
+    } else {
+        fs::create_dir_all(backup_dir.join(&subpath))?;
+        fs::canonicalize(backup_dir.join(&subpath))?
+    };
+    // Names that differ only in case would overwrite each other on targets such as exFAT. A
+    // dry run may not have a destination to probe, so only the option counts there
+    if args.target_case_insensitive || (!args.dry_run && backup::is_case_insensitive(&dest)?) {
+        options.case_collisions = Some(if args.rename_collisions {
+            CaseCollisions::Rename
+        } else {
+            CaseCollisions::Fail
+        });
+    }
+    let result = backup::copy_directory(&source, &dest, &options, stats, cancel, sink);
+
+    if options.update_in_place {
+        // The mirror is updated in place: keep it on failure, and only delete after a full copy.
+        // A mirror that a dry run would create has nothing to delete yet
+        result?;
+        if args.mirror && !args.no_delete && dest.is_dir() {
+            mirror::check_source_complete(&source, &dest, stats)?;
+            mirror::remove_deleted(&source, &dest, &options, stats, sink)?;
+        }
+        return Ok(backup_dir);
+    }
+    if args.dry_run {
+        result?;
+        return Ok(backup_dir);
+    }
+
+    if let Err(e) = result {
+        if args.clean_on_failure {
+            if let Err(clean_err) = fs::remove_dir_all(&backup_dir) {
+                sink.emit(&Event::Error {
+                    message: format!("Failed to remove {:?}: {}", backup_dir, clean_err),
+                });
+            }
+            journal::remove_journal(&backup_dir)?;
+        }
+        // Whatever was recorded stays usable for the next --resume
+        if let Some(Err(sync_err)) = journal.as_ref().map(Journal::sync) {
+            sink.emit(&Event::Warning {
+                message: format!("cannot write the resume journal: {}", sync_err),
+            });
+        }
+        return Err(e);
+    }
+
+    journal::remove_journal(&backup_dir)?;
+    backup::finalize_backup_dir(&backup_dir)
+}
+
+/// Runs the `diff` command: compares two trees and prints the differences.
+///
+/// # Parameters
+/// - `args`: Arguments following `diff`: two paths plus optional `--hash`, `--json`,
+///   `--section <name>` and `--config <path>`. With `--section` or `--config`, the exclusions
+///   of that configuration section are applied to both trees.
+///
+/// # Returns
+/// - The process exit code.
+fn run_diff(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: snapshotter diff <backup_a> <backup_b> [--hash] [--json] [--section <name>] [--config <path>]";
+
+    let mut paths = Vec::new();
+    let mut options = diff::DiffOptions::default();
+    let mut json = false;
+    let mut section = None;
+    let mut config_path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--hash" => options.hash = true,
+            "--json" => json = true,
+            "--section" | "--config" => {
+                let Some(value) = iter.next() else {
+                    eprintln!("{} requires a value\n{}", arg, USAGE);
+                    return 2;
+                };
+                if arg == "--section" {
+                    section = Some(value.as_str());
+                } else {
+                    config_path = Some(value.as_str());
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    if section.is_some() || config_path.is_some() {
+        let section = section.unwrap_or("default");
+        let config = match config_path {
+            Some(path) => config::load_config_from(Path::new(path), section),
+            None => config::load_config(section),
+        };
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error loading configuration: {}", e);
+                return 2;
+            }
+        };
+        options.exclusions = Some(CopyOptions {
+            exclude_list: config.exclusions,
+            exclude_extensions: config.excluded_extensions,
+            skip_hidden: config.skip_hidden.unwrap_or(false),
+            case_insensitive: config
+                .case_insensitive_patterns
+                .unwrap_or(IGNORE_CASE_BY_DEFAULT),
+            ..CopyOptions::default()
+        });
+    }
+
+    match diff::diff_backups(Path::new(paths[0]), Path::new(paths[1]), &options) {
+        Ok(report) if json => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            0
+        }
+        Ok(report) if report.is_empty() => {
+            println!("No differences");
+            0
+        }
+        Ok(report) => {
+            print!("{}", diff::format_report(&report));
+            0
+        }
+        Err(e) => {
+            eprintln!("Error comparing {} and {}: {}", paths[0], paths[1], e);
+            1
+        }
+    }
+}
+
+/// Arguments shared by the `list` and `latest` commands.
+struct CatalogArgs {
+    target_dir: String,
+    source_name: Option<String>,
+    template: NameTemplate,
+}
+
+/// Parses `<target_dir> [--source-name <name>] [--name-template <template>]`.
+fn parse_catalog_args(args: &[String]) -> Result<CatalogArgs, String> {
+    let mut paths = Vec::new();
+    let mut source_name = None;
+    let mut template = NameTemplate::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--source-name" => {
+                let value = iter.next().ok_or("--source-name requires a name")?;
+                source_name = Some(value.clone());
+            }
+            "--name-template" => {
+                let value = iter.next().ok_or("--name-template requires a template")?;
+                template = NameTemplate::parse(value)?;
+            }
+            option if option.starts_with("--") => {
+                return Err(format!("Unknown option: {}", option));
+            }
+            _ => paths.push(arg.clone()),
+        }
+    }
+
+    match <[String; 1]>::try_from(paths) {
+        Ok([target_dir]) => Ok(CatalogArgs {
+            target_dir,
+            source_name,
+            template,
+        }),
+        Err(_) => Err("Expected <target_dir>".to_string()),
+    }
+}
+
+/// Runs the `list` command: prints every backup found in a target directory.
+///
+/// # Parameters
+/// - `args`: Arguments following `list`: the target directory and optional `--source-name`
+///   and `--name-template`.
+///
+/// # Returns
+/// - The process exit code.
+fn run_list(args: &[String]) -> i32 {
+    let args = match parse_catalog_args(args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Usage: snapshotter list <target_dir> [--source-name <name>] [--name-template <template>]");
+            return 2;
+        }
+    };
+
+    let backups = match catalog::scan_backups(Path::new(&args.target_dir), &args.template) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.target_dir, e);
+            return 1;
+        }
+    };
+    let wanted = |backup: &&catalog::CatalogEntry| {
+        args.source_name.is_none() || backup.source_name == args.source_name
+    };
+    for backup in backups.iter().filter(wanted) {
+        // Nested templates put backups several levels below the target
+        let name = backup
+            .path
+            .strip_prefix(&args.target_dir)
+            .unwrap_or(&backup.path)
+            .to_string_lossy();
+        let size = catalog::directory_size(&backup.path)
+            .map(|size| size.to_string())
+            .unwrap_or_else(|_| "?".to_string());
+        let status = if backup.complete {
+            "complete"
+        } else {
+            "incomplete"
+        };
+        println!(
+            "{}\t{}\t{}\t{}",
+            name,
+            backup.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            size,
+            status
         );
-        return;
     }
+    0
+}
+
+/// Runs the `latest` command: prints the path of the newest complete backup.
+///
+/// # Parameters
+/// - `args`: Arguments following `latest`: the target directory and optional `--source-name`
+///   and `--name-template`.
+///
+/// # Returns
+/// - The process exit code; 1 if no complete backup exists.
+fn run_latest(args: &[String]) -> i32 {
+    let args = match parse_catalog_args(args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Usage: snapshotter latest <target_dir> [--source-name <name>] [--name-template <template>]");
+            return 2;
+        }
+    };
+
+    match catalog::scan_backups(Path::new(&args.target_dir), &args.template) {
+        Ok(backups) => match catalog::latest(&backups, args.source_name.as_deref()) {
+            Some(backup) => {
+                println!("{}", backup.path.display());
+                0
+            }
+            None => {
+                eprintln!("No complete backup found in {}", args.target_dir);
+                1
+            }
+        },
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.target_dir, e);
+            1
+        }
+    }
+}
+
+/// Runs the `contents` command: prints every entry stored in a backup.
+///
+/// # Parameters
+/// - `args`: Arguments following `contents`: the backup directory and optional `--json`.
+///
+/// # Returns
+/// - The process exit code.
+fn run_contents(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: snapshotter contents <backup_dir> [--json]";
+
+    let mut paths = Vec::new();
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            option if option.starts_with("--") => {
+                eprintln!("Unknown option: {}\n{}", option, USAGE);
+                return 2;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [backup_dir] = paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let entries = match catalog::backup_contents(Path::new(backup_dir)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", backup_dir, e);
+            return 1;
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return 0;
+    }
+    for entry in &entries {
+        let size = match entry.kind {
+            catalog::ContentKind::File => entry.size.to_string(),
+            _ => "-".to_string(),
+        };
+        let kind = match entry.kind {
+            catalog::ContentKind::File => "file",
+            catalog::ContentKind::Dir => "dir",
+            catalog::ContentKind::Symlink => "symlink",
+            catalog::ContentKind::Other => "other",
+        };
+        println!("{}\t{}\t{}", kind, size, entry.path.display());
+    }
+    0
+}
 
-    let source_dir = &args[1];
-    let target_dir = &args[2];
-    let config_section = if args.len() == 4 {
-        &args[3] // Use the specified section
+/// Runs the `restore` command: copies a backup, or only some of its entries, into a directory.
+///
+/// Picked entries are copied like the entries of `--files-from`: they keep their place below
+/// the destination, and a picked directory is copied with everything below it. A manifest of a
+/// `--repo` target is restored from the chunks of its repository instead.
+///
+/// # Parameters
+/// - `args`: Arguments following `restore`: the backup directory or manifest, the destination
+///   directory, and the entries to restore as `--pick <path>`, relative to the backup
+///   (everything by default).
+///
+/// # Returns
+/// - The process exit code; 1 if a picked entry does not exist or an entry cannot be copied.
+fn run_restore(args: &[String]) -> i32 {
+    const USAGE: &str =
+        "Usage: snapshotter restore <backup_dir|manifest> <dest_dir> [--pick <pattern>]...";
+
+    let mut paths = Vec::new();
+    let mut picks = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--pick" => {
+                let Some(value) = iter.next() else {
+                    eprintln!("--pick requires a pattern\n{}", USAGE);
+                    return 2;
+                };
+                picks.push(value.clone());
+            }
+            option if option.starts_with("--") => {
+                eprintln!("Unknown option: {}\n{}", option, USAGE);
+                return 2;
+            }
+            _ => paths.push(Path::new(arg)),
+        }
+    }
+    let [backup_dir, dest_dir] = paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let picks = match patterns::compile(&picks) {
+        Ok(picks) => picks,
+        Err(message) => {
+            eprintln!("--pick: {}", message);
+            return 2;
+        }
+    };
+    if backup_dir.is_file() {
+        return restore_manifest(backup_dir, dest_dir, &picks);
+    }
+    if !backup_dir.is_dir() {
+        eprintln!("Backup directory {:?} does not exist", backup_dir);
+        return 1;
+    }
+
+    // Picks use the syntax of exclusions, so they select entries of the backup before copying
+    let files_from = if picks.is_empty() {
+        None
     } else {
-        "default" // Fallback to default section
+        let (selected, used) =
+            match backup::find_matching(backup_dir, &picks, IGNORE_CASE_BY_DEFAULT) {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("Error reading {:?}: {}", backup_dir, e);
+                    return 1;
+                }
+            };
+        let unmatched: Vec<_> = picks
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|(pick, _)| format!("{:?}", pick.as_str()))
+            .collect();
+        if !unmatched.is_empty() {
+            eprintln!(
+                "--pick pattern matched nothing in {:?}: {}",
+                backup_dir,
+                unmatched.join(", ")
+            );
+            return 1;
+        }
+        Some(selected)
+    };
+
+    let options = CopyOptions {
+        files_from,
+        // A backup made with --no-timestamp holds the lock and marker files of its target
+        excluded_paths: [backup::LOCK_FILE_NAME, backup::MARKER_FILE_NAME]
+            .map(|name| backup_dir.join(name))
+            .to_vec(),
+        preserve_owner: backup::running_as_root(),
+        ..CopyOptions::default()
+    };
+    let sink = HumanSink {
+        quiet: false,
+        dry_run: false,
+    };
+    let mut stats = BackupStats::default();
+    let result = fs::create_dir_all(dest_dir).and_then(|()| {
+        backup::copy_directory(
+            backup_dir,
+            dest_dir,
+            &options,
+            &mut stats,
+            &AtomicBool::new(false),
+            &sink,
+        )
+    });
+    if let Err(e) = result {
+        eprintln!("Error restoring {:?}: {}", backup_dir, e);
+        return 1;
+    }
+    println!(
+        "Restored {} files ({}) to {:?}",
+        stats.files_copied,
+        format::format_bytes(stats.bytes_copied),
+        dest_dir
+    );
+    i32::from(stats.files_failed > 0)
+}
+
+/// Restores a manifest of a `--repo` target, or the entries of it matched by `picks`.
+///
+/// # Returns
+/// - The process exit code; 1 if a pick matches nothing or the manifest or one of its chunks
+///   cannot be read.
+#[cfg(feature = "repo")]
+fn restore_manifest(manifest_path: &Path, dest_dir: &Path, picks: &[patterns::Pattern]) -> i32 {
+    let Some(repository) = repo::repository_of(manifest_path) else {
+        eprintln!(
+            "{:?} is not in the {} directory of a repository",
+            manifest_path,
+            backup::MANIFESTS_DIR_NAME
+        );
+        return 1;
+    };
+    let manifest = match repo::read_manifest(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Error reading {:?}: {}", manifest_path, e);
+            return 1;
+        }
+    };
+    let entries = if picks.is_empty() {
+        manifest.entries.iter().collect()
+    } else {
+        let (selected, used) = manifest.select(picks, IGNORE_CASE_BY_DEFAULT);
+        let unmatched: Vec<_> = picks
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|(pick, _)| format!("{:?}", pick.as_str()))
+            .collect();
+        if !unmatched.is_empty() {
+            eprintln!(
+                "--pick pattern matched nothing in {:?}: {}",
+                manifest_path,
+                unmatched.join(", ")
+            );
+            return 1;
+        }
+        selected
+    };
+
+    let sink = HumanSink {
+        quiet: false,
+        dry_run: false,
+    };
+    let mut stats = BackupStats::default();
+    let result = fs::create_dir_all(dest_dir)
+        .and_then(|()| repo::restore_entries(repository, entries, dest_dir, &mut stats, &sink));
+    if let Err(e) = result {
+        eprintln!("Error restoring {:?}: {}", manifest_path, e);
+        return 1;
+    }
+    println!(
+        "Restored {} files ({}) to {:?}",
+        stats.files_copied,
+        format::format_bytes(stats.bytes_copied),
+        dest_dir
+    );
+    0
+}
+
+#[cfg(not(feature = "repo"))]
+fn restore_manifest(manifest_path: &Path, _dest_dir: &Path, _picks: &[patterns::Pattern]) -> i32 {
+    eprintln!(
+        "Cannot restore {:?}: restoring a manifest requires building with the repo feature (cargo build --features repo)",
+        manifest_path
+    );
+    2
+}
+
+/// Runs the `prune` command: deletes the chunks of a `--repo` target that no manifest refers
+/// to any more, such as those of deleted manifests or of interrupted backups.
+///
+/// # Parameters
+/// - `args`: Arguments following `prune`: the target directory.
+///
+/// # Returns
+/// - The process exit code; 1 if the target is locked by a running backup or a manifest or
+///   chunk cannot be read or deleted.
+fn run_prune(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: snapshotter prune <target_dir>";
+
+    let [target] = args else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    prune_target(Path::new(target))
+}
+
+/// Deletes the unreferenced chunks of a repository while holding its lock.
+///
+/// # Returns
+/// - The process exit code of `prune`.
+#[cfg(feature = "repo")]
+fn prune_target(target: &Path) -> i32 {
+    if !target.is_dir() {
+        eprintln!("Target directory {:?} does not exist", target);
+        return 1;
+    }
+    // A backup running meanwhile may be storing chunks its manifest does not list yet
+    let _lock = match backup::lock_target(target, Duration::ZERO) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    match repo::prune(target) {
+        Ok((count, bytes)) => {
+            println!(
+                "Removed {} unreferenced chunks ({}) from {:?}",
+                count,
+                format::format_bytes(bytes),
+                target
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Error pruning {:?}: {}", target, e);
+            1
+        }
+    }
+}
+
+#[cfg(not(feature = "repo"))]
+fn prune_target(_target: &Path) -> i32 {
+    eprintln!("prune requires building with the repo feature (cargo build --features repo)");
+    2
+}
+
+/// Runs the `check-config` command: validates configuration sections without running a backup
+/// and prints the options a backup with each of them would use.
+///
+/// Unknown keys, exclusions that never match and hook programs that cannot be found are
+/// warnings; values that cannot be parsed are errors.
+///
+/// # Parameters
+/// - `args`: Arguments following `check-config`: optional `--config <path>` and the sections
+///   to check (all sections of the file by default).
+///
+/// # Returns
+/// - The process exit code; 1 if a section has errors.
+fn run_check_config(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: snapshotter check-config [--config <path>] [section]...";
+
+    let mut config_path = None;
+    let mut sections = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--config" => {
+                let Some(value) = iter.next() else {
+                    eprintln!("--config requires a path\n{}", USAGE);
+                    return 2;
+                };
+                config_path = Some(PathBuf::from(value));
+            }
+            option if option.starts_with("--") => {
+                eprintln!("Unknown option: {}\n{}", option, USAGE);
+                return 2;
+            }
+            _ => sections.push(arg.clone()),
+        }
+    }
+    let config_path = match config_path.map_or_else(config::default_config_path, Ok) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Error locating the configuration: {}", e);
+            return 2;
+        }
+    };
+    let existing = match config::section_names(&config_path) {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+            return 1;
+        }
+    };
+    if sections.is_empty() {
+        sections = existing.clone();
+    }
+
+    let mut failed = false;
+    for section in &sections {
+        // A backup would fall back to the default section, but a check names what it checks
+        if !existing.contains(section) {
+            println!("[{}]\nerror: no such section", section);
+            failed = true;
+            continue;
+        }
+        let config = match config::load_config_from(&config_path, section) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("[{}]\nerror: {}", section, e);
+                failed = true;
+                continue;
+            }
+        };
+        // Placeholder directories stand in for the command line of a backup
+        let placeholders = ["<source_dir>", "<target_dir>", section].map(String::from);
+        let mut args = parse_args(&placeholders).expect("placeholder arguments are valid");
+        let result = apply_config(&mut args, &config).and_then(|()| validate_args(&args));
+
+        let mut warnings = config.warnings.clone();
+        warnings.extend(config.unmatchable_patterns().into_iter().map(|pattern| {
+            format!(
+                "excluded extension {:?} never matches: extensions are compared with the end of file names, not paths",
+                pattern
+            )
+        }));
+        let hooks = [
+            ("snapshot_command", &args.snapshot_command),
+            ("release_snapshot_command", &args.release_snapshot_command),
+            ("pre_hook", &args.pre_hook),
+            ("post_hook", &args.post_hook),
+        ];
+        for (key, command) in hooks {
+            if let Some(command) = command {
+                if hooks::find_program(command).is_none() {
+                    warnings.push(format!(
+                        "the program of {} {:?} was not found (fine if it is a shell builtin)",
+                        key, command
+                    ));
+                }
+            }
+        }
+
+        println!("[{}]", section);
+        for warning in &warnings {
+            println!("warning: {}", warning);
+        }
+        match result {
+            Ok(()) => {
+                let mut options = describe_options(&args, &config);
+                if let Some(options) = options.as_object_mut() {
+                    options.remove("source_dirs");
+                }
+                println!("{}", serde_json::to_string_pretty(&options).unwrap());
+            }
+            Err(message) => {
+                println!("error: {}", message);
+                failed = true;
+            }
+        }
+    }
+    i32::from(failed)
+}
+
+fn main() {
+    // Get command-line arguments
+    let raw_args: Vec<String> = env::args().collect();
+    // A bare `<source_dir> <target_dir>` invocation is the same as `backup <source_dir> <target_dir>`
+    let backup_args = match raw_args.get(1).map(String::as_str) {
+        Some("diff") => process::exit(run_diff(&raw_args[2..])),
+        Some("list") => process::exit(run_list(&raw_args[2..])),
+        Some("latest") => process::exit(run_latest(&raw_args[2..])),
+        Some("contents") => process::exit(run_contents(&raw_args[2..])),
+        Some("restore") => process::exit(run_restore(&raw_args[2..])),
+        Some("prune") => process::exit(run_prune(&raw_args[2..])),
+        Some("check-config") => process::exit(run_check_config(&raw_args[2..])),
+        Some("backup") => &raw_args[2..],
+        _ => &raw_args[1..],
+    };
+    let mut args = match parse_args(backup_args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!(
+                "Usage: {} <source_dir> <target_dir> [config_section] [--config <path>] [--source <dir>]... [--metrics-file <path>] [--summary-file <path>] [--clean-on-failure] [--skip-hidden] [--exclude-mime <type>]... [--special-files] [--quiet] [--warnings-as-errors] [--fail-on-unreadable] [--no-space-check] [--no-lock] [--wait-for-lock <duration>] [--[no-]preserve-owner] [--owner <user>]... [--group <group>]... [--one-file-system] [--dereference-root] [--preserve-acls] [--mirror [--no-delete]] [--paranoid] [--allow-mixed-target] [--dry-run] [--no-empty-dirs] [--dedup] [--flatten] [--repo] [--max-depth <n>] [--files-from|--files-from0 <path|->] [--target-case-insensitive] [--rename-collisions] [--ignore-case|--case-sensitive] [--output <human|json-lines>] [--sparse <mode>] [--backup-path-style <style>] [--relative-root <dir>] [--reflink <mode>] [--max-total-size <size>] [--name-template <template>] [--utc] [--no-timestamp] [--resume] [--retries <n>] [--retry-delay <ms>] [--retry-errno <code>]... [--notify-webhook|--notify-url <url>] [--snapshot-command <cmd>] [--release-snapshot-command <cmd>] [--pre-hook <cmd>] [--post-hook <cmd>]",
+                raw_args[0]
+            );
+            process::exit(2);
+        }
     };
 
     // Load configuration from config.json
-    let config: Config = config::load_config(config_section).expect("Failed to load configuration");
-
-    // Create new backup directory with timestamp
-    let source_dir_name = Path::new(source_dir).file_name().unwrap().to_string_lossy();
-    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-    let backup_dir_with_timestamp =
-        PathBuf::from(target_dir).join(format!("{}_backup_{}", source_dir_name, timestamp));
-
-    // Create backup directory
-    fs::create_dir_all(&backup_dir_with_timestamp).expect("Failed to create backup directory");
-
-    // Copy source directory to backup directory
-    backup::copy_directory(
-        Path::new(source_dir),
-        &backup_dir_with_timestamp,
-        &config.excluded_items,
-        &config.excluded_extensions,
-    )
-    .expect("Error during directory copying");
-
-    println!("Backup created at {:?}", backup_dir_with_timestamp);
+    let config = match &args.config_path {
+        Some(path) => config::load_config_from(Path::new(path), &args.config_section),
+        None => config::load_config(&args.config_section),
+    };
+    let config: Config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+            process::exit(2);
+        }
+    };
+    if let Err(message) = apply_config(&mut args, &config) {
+        eprintln!("Invalid configuration: {}", message);
+        process::exit(2);
+    }
+    if let Err(message) = validate_args(&args) {
+        eprintln!("{}", message);
+        process::exit(2);
+    }
+
+    let output: Box<dyn EventSink> = if args.json_lines {
+        Box::new(JsonLinesSink { quiet: args.quiet })
+    } else {
+        Box::new(HumanSink {
+            quiet: args.quiet,
+            dry_run: args.dry_run,
+        })
+    };
+    // Every warning and error of the run passes through here, to be counted at the end
+    let sink = Diagnostics::new(output);
+
+    // Stop copying cleanly on Ctrl-C instead of being killed mid-file
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = Arc::clone(&cancel);
+    if let Err(e) = ctrlc::set_handler(move || handler_cancel.store(true, Ordering::Relaxed)) {
+        sink.emit(&Event::Warning {
+            message: format!("failed to install Ctrl-C handler: {}", e),
+        });
+    }
+
+    for warning in &config.warnings {
+        sink.emit(&Event::Warning {
+            message: warning.clone(),
+        });
+    }
+    for pattern in config.unmatchable_patterns() {
+        sink.emit(&Event::Warning {
+            message: format!(
+                "excluded extension {:?} never matches: extensions are compared with the end of file names, not paths",
+                pattern
+            ),
+        });
+    }
+
+    if args.preserve_acls && !cfg!(windows) {
+        sink.emit(&Event::Warning {
+            message: "--preserve-acls only has an effect on Windows".to_string(),
+        });
+    }
+
+    // A run that fails before copying anything is still reported, from its start
+    let started_at = Local::now();
+
+    if let Some(files_from) = &args.files_from {
+        match backup::read_file_list(files_from, args.files_from_nul) {
+            Ok(entries) => args.listed_entries = Some(entries),
+            Err(e) => {
+                let message = format!("Failed to read the file list {}: {}", files_from, e);
+                fail_run(&args, &config, message, started_at, &sink);
+            }
+        }
+    }
+
+    // Refuse before the lock file or a backup directory is created in the source
+    if let Err(e) = check_source_not_target(&args) {
+        fail_run(&args, &config, e.to_string(), started_at, &sink);
+    }
+
+    if let Err(e) = check_mixed_target(&args, &sink) {
+        fail_run(&args, &config, e.to_string(), started_at, &sink);
+    }
+
+    // Keep overlapping runs (e.g. an overrunning cron job) from writing to the same target.
+    // A dry run writes nothing, not even the lock file
+    let _lock = if args.no_lock || args.dry_run {
+        None
+    } else {
+        match backup::lock_target(
+            Path::new(&args.target_dir),
+            args.wait_for_lock.unwrap_or_default(),
+        ) {
+            Ok(lock) => Some(lock),
+            Err(e) => fail_run(&args, &config, e.to_string(), started_at, &sink),
+        }
+    };
+
+    // All sources share the configuration and go into their own timestamped directory
+    let mut reports = Vec::new();
+    for source_dir in &args.source_dirs {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let started = Instant::now();
+        let mut stats = BackupStats::default();
+        // The size budget is shared by all sources of the run
+        let copied: u64 = reports
+            .iter()
+            .map(|r: &SourceReport| r.stats.bytes_copied)
+            .sum();
+        let budget = args.max_total_size.map(|max| max.saturating_sub(copied));
+        let result = backup_source(
+            Path::new(source_dir),
+            budget,
+            &args,
+            &config,
+            &mut stats,
+            &cancel,
+            &sink,
+        );
+
+        match &result {
+            Ok(backup_dir) => sink.emit(&Event::Summary {
+                source: source_dir,
+                backup_dir,
+                stats: &stats,
+                duration: started.elapsed(),
+                dry_run: args.dry_run,
+            }),
+            Err(e) => sink.emit(&Event::Error {
+                message: format!("Error during backup of {}: {}", source_dir, e),
+            }),
+        }
+
+        if let Some(post_hook) = args.post_hook.as_ref().filter(|_| !args.dry_run) {
+            let backup_dir = result
+                .as_ref()
+                .map(|dir| dir.as_os_str())
+                .unwrap_or_default();
+            let files_copied = stats.files_copied.to_string();
+            let status = if result.is_ok() && stats.files_failed == 0 {
+                "success"
+            } else {
+                "failure"
+            };
+            let env = [
+                ("SNAPSHOTTER_SOURCE", OsStr::new(source_dir)),
+                ("SNAPSHOTTER_BACKUP_DIR", backup_dir),
+                ("SNAPSHOTTER_FILES_COPIED", OsStr::new(&files_copied)),
+                ("SNAPSHOTTER_STATUS", OsStr::new(status)),
+            ];
+            // The backup itself is done, so a failing hook does not change its outcome
+            if let Err(e) = hooks::run_hook(post_hook, "post-hook", &env, &sink) {
+                sink.emit(&Event::Warning {
+                    message: e.to_string(),
+                });
+            }
+        }
+
+        reports.push(SourceReport {
+            source: source_dir.clone(),
+            stats,
+            duration: started.elapsed(),
+            result: result.map_err(|e| e.to_string()),
+        });
+    }
+
+    // A dry run only reports what would happen; metrics and notifications would describe a
+    // backup that never ran
+    let interrupted = cancel.load(Ordering::Relaxed) || reports.len() < args.source_dirs.len();
+    if !args.dry_run {
+        report_run(&args, &config, &reports, interrupted, started_at, &sink);
+    }
+
+    let warnings = sink.warnings().len();
+    sink.emit(&Event::RunFinished {
+        warnings,
+        errors: sink.errors().len(),
+    });
+
+    if interrupted
+        || reports.iter().any(|r| !r.succeeded())
+        || (args.warnings_as_errors && warnings > 0)
+    {
+        process::exit(1);
+    }
+    // Like rsync, a run that is complete except for files deleted while it ran gets a status
+    // of its own, so scripts can tell it apart from both a clean run and a failure
+    if reports.iter().any(|r| r.stats.files_vanished > 0) {
+        process::exit(24);
+    }
+}
+
+/// Ends a run that failed before any source was backed up, such as one that found the target
+/// locked by an overrunning run. Every source is reported as failed with `message`, so the
+/// metrics and summary files and the notification do not keep showing the previous run.
+fn fail_run(
+    args: &Args,
+    config: &Config,
+    message: String,
+    started_at: DateTime<Local>,
+    sink: &Diagnostics,
+) -> ! {
+    sink.emit(&Event::Error {
+        message: message.clone(),
+    });
+    if !args.dry_run {
+        let reports: Vec<_> = args
+            .source_dirs
+            .iter()
+            .map(|source| SourceReport {
+                source: source.clone(),
+                stats: BackupStats::default(),
+                duration: Duration::ZERO,
+                result: Err(message.clone()),
+            })
+            .collect();
+        report_run(args, config, &reports, false, started_at, sink);
+    }
+    sink.emit(&Event::RunFinished {
+        warnings: sink.warnings().len(),
+        errors: sink.errors().len(),
+    });
+    process::exit(1);
+}
+
+/// Writes the metrics and summary files and sends the webhook notification for a finished run.
+fn report_run(
+    args: &Args,
+    config: &Config,
+    reports: &[SourceReport],
+    interrupted: bool,
+    started_at: DateTime<Local>,
+    sink: &Diagnostics,
+) {
+    // Metrics are written for failed runs too, so monitoring can alert on them. Like the
+    // notification and the summary below, they are not part of the backup: failing to write
+    // them is a warning, which only fails the run with --warnings-as-errors
+    if let Some(metrics_file) = &args.metrics_file {
+        let contents = metrics::format_metrics(reports, Local::now().timestamp(), interrupted);
+        if let Err(e) = metrics::write_metrics(Path::new(metrics_file), &contents) {
+            sink.emit(&Event::Warning {
+                message: format!("Failed to write metrics file: {}", e),
+            });
+        }
+    }
+
+    let webhook = args
+        .notify_webhook
+        .as_ref()
+        .or(config.notifications.webhook_url.as_ref());
+    if let Some(url) = webhook {
+        #[cfg(feature = "notify")]
+        if let Err(e) = notify::send_webhook(url, reports, interrupted) {
+            sink.emit(&Event::Warning {
+                message: format!("Failed to send notification to {}: {}", url, e),
+            });
+        }
+        #[cfg(not(feature = "notify"))]
+        sink.emit(&Event::Warning {
+            message: format!(
+                "notification to {} not sent: snapshotter was built without the notify feature",
+                url
+            ),
+        });
+    }
+
+    // The summary records partial and failed runs too, including their errors. It is written
+    // last so that it also lists the problems of the steps above
+    if let Some(summary_file) = &args.summary_file {
+        let run = summary::RunInfo {
+            target_dir: &args.target_dir,
+            config_section: &args.config_section,
+            options: describe_options(args, config),
+            started_at,
+            finished_at: Local::now(),
+            interrupted,
+            warnings: sink.warnings(),
+            errors: sink.errors(),
+            warnings_as_errors: args.warnings_as_errors,
+        };
+        let contents = summary::build_summary(&run, reports);
+        if let Err(e) = summary::write_summary(Path::new(summary_file), &contents) {
+            sink.emit(&Event::Warning {
+                message: format!("Failed to write summary file: {}", e),
+            });
+        }
+    }
 }
@@ -0,0 +1,193 @@
+use crate::atomic::write_atomic;
+use crate::backup::SourceReport;
+use std::path::Path;
+
+/// Escapes a value for use inside a Prometheus label (`\`, `"` and newlines).
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats the statistics of a backup run in the Prometheus text exposition format.
+///
+/// # Parameters
+/// - `reports`: One report per backed-up source; each becomes a sample labeled with its source.
+///   Without any, the timestamp and success of the run are written as unlabeled samples.
+/// - `finished_at`: Unix timestamp (seconds) at which the run finished.
+/// - `interrupted`: The run stopped (e.g. on Ctrl-C) before every source was backed up; the
+///   run failed, so `snapshotter_success` is 0 for every source.
+///
+/// # Returns
+/// - A `String` holding one gauge family per metric, each preceded by `HELP` and `TYPE` lines.
+pub fn format_metrics(reports: &[SourceReport], finished_at: i64, interrupted: bool) -> String {
+    type Sample = fn(&SourceReport, i64, bool) -> String;
+    let gauges: [(&str, &str, Sample); 6] = [
+        (
+            "snapshotter_last_run_timestamp_seconds",
+            "Unix time at which the last backup run finished.",
+            |_, finished_at, _| finished_at.to_string(),
+        ),
+        (
+            "snapshotter_files_copied_total",
+            "Number of files copied by the last backup run.",
+            |r, _, _| r.stats.files_copied.to_string(),
+        ),
+        (
+            "snapshotter_bytes_copied_total",
+            "Number of bytes copied by the last backup run.",
+            |r, _, _| r.stats.bytes_copied.to_string(),
+        ),
+        (
+            "snapshotter_files_skipped_total",
+            "Number of items excluded by the last backup run.",
+            |r, _, _| r.stats.files_skipped.to_string(),
+        ),
+        (
+            "snapshotter_duration_seconds",
+            "Duration of the last backup run in seconds.",
+            |r, _, _| format!("{:.3}", r.duration.as_secs_f64()),
+        ),
+        (
+            "snapshotter_success",
+            "Whether the last backup run succeeded (1) or failed (0).",
+            |r, _, interrupted| u8::from(r.succeeded() && !interrupted).to_string(),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, help, sample) in gauges {
+        output.push_str(&format!("# HELP {} {}\n", name, help));
+        output.push_str(&format!("# TYPE {} gauge\n", name));
+        for report in reports {
+            output.push_str(&format!(
+                "{}{{source=\"{}\"}} {}\n",
+                name,
+                escape_label(&report.source),
+                sample(report, finished_at, interrupted)
+            ));
+        }
+        // A run interrupted before its first source still records when it ran and that it failed
+        if reports.is_empty() {
+            match name {
+                "snapshotter_last_run_timestamp_seconds" => {
+                    output.push_str(&format!("{} {}\n", name, finished_at));
+                }
+                "snapshotter_success" => {
+                    output.push_str(&format!("{} {}\n", name, u8::from(!interrupted)));
+                }
+                _ => {}
+            }
+        }
+    }
+    output
+}
+
+/// Atomically writes the metrics to `path` (see `write_atomic`).
+///
+/// # Parameters
+/// - `path`: Destination file, typically inside the node_exporter textfile directory.
+/// - `contents`: Metrics text produced by `format_metrics`.
+///
+/// # Errors
+/// - Will return an error if the temporary file cannot be written or renamed.
+pub fn write_metrics(path: &Path, contents: &str) -> std::io::Result<()> {
+    write_atomic(path, contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupStats;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn report(source: &str, result: Result<PathBuf, String>) -> SourceReport {
+        SourceReport {
+            source: source.to_string(),
+            stats: BackupStats {
+                files_copied: 12,
+                bytes_copied: 3456,
+                files_skipped: 2,
+                ..BackupStats::default()
+            },
+            duration: Duration::from_millis(1500),
+            result,
+        }
+    }
+
+    #[test]
+    fn report_is_formatted_exactly() {
+        let reports = [
+            report("docs", Ok(PathBuf::from("/backups/docs_backup"))),
+            report("my \"photos\"", Err("disk full".to_string())),
+        ];
+
+        let output = format_metrics(&reports, 1_706_745_600, false);
+
+        assert_eq!(
+            output,
+            "# HELP snapshotter_last_run_timestamp_seconds Unix time at which the last backup run finished.
+# TYPE snapshotter_last_run_timestamp_seconds gauge
+snapshotter_last_run_timestamp_seconds{source=\"docs\"} 1706745600
+snapshotter_last_run_timestamp_seconds{source=\"my \\\"photos\\\"\"} 1706745600
+# HELP snapshotter_files_copied_total Number of files copied by the last backup run.
+# TYPE snapshotter_files_copied_total gauge
+snapshotter_files_copied_total{source=\"docs\"} 12
+snapshotter_files_copied_total{source=\"my \\\"photos\\\"\"} 12
+# HELP snapshotter_bytes_copied_total Number of bytes copied by the last backup run.
+# TYPE snapshotter_bytes_copied_total gauge
+snapshotter_bytes_copied_total{source=\"docs\"} 3456
+snapshotter_bytes_copied_total{source=\"my \\\"photos\\\"\"} 3456
+# HELP snapshotter_files_skipped_total Number of items excluded by the last backup run.
+# TYPE snapshotter_files_skipped_total gauge
+snapshotter_files_skipped_total{source=\"docs\"} 2
+snapshotter_files_skipped_total{source=\"my \\\"photos\\\"\"} 2
+# HELP snapshotter_duration_seconds Duration of the last backup run in seconds.
+# TYPE snapshotter_duration_seconds gauge
+snapshotter_duration_seconds{source=\"docs\"} 1.500
+snapshotter_duration_seconds{source=\"my \\\"photos\\\"\"} 1.500
+# HELP snapshotter_success Whether the last backup run succeeded (1) or failed (0).
+# TYPE snapshotter_success gauge
+snapshotter_success{source=\"docs\"} 1
+snapshotter_success{source=\"my \\\"photos\\\"\"} 0
+"
+        );
+    }
+
+    #[test]
+    fn interrupted_run_reports_no_success() {
+        let reports = [report("docs", Ok(PathBuf::from("/backups/docs_backup")))];
+
+        let output = format_metrics(&reports, 1_706_745_600, true);
+
+        assert!(output.ends_with("snapshotter_success{source=\"docs\"} 0\n"));
+    }
+
+    #[test]
+    fn run_interrupted_before_any_source_reports_no_success() {
+        let output = format_metrics(&[], 1_706_745_600, true);
+
+        assert!(output.contains("\nsnapshotter_last_run_timestamp_seconds 1706745600\n"));
+        assert!(output.ends_with("\nsnapshotter_success 0\n"));
+        assert!(!output.contains("\nsnapshotter_files_copied_total"));
+    }
+
+    #[test]
+    fn existing_file_is_replaced_without_leftovers() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("snapshotter.prom");
+        fs::write(&path, "stale metrics\n").unwrap();
+
+        write_metrics(&path, "fresh metrics\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fresh metrics\n");
+        let names: Vec<_> = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["snapshotter.prom"]);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::backup::{self, BackupStats, CopyOptions};
+use crate::events::{Event, EventSink};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Returns the directory that mirrors `source_name` inside `target`: `<target>/<source_name>_mirror`.
+pub fn mirror_dir(target: &Path, source_name: &str) -> PathBuf {
+    target.join(format!("{}_mirror", source_name))
+}
+
+/// Refuses to clean up `mirror` when the source may not have been read completely, so that a
+/// source that is not mounted or could not be walked does not wipe the mirror.
+///
+/// # Errors
+/// - Will return an error if entries of the source could not be copied, or if the source is
+///   empty while the mirror is not.
+pub fn check_source_complete(
+    src: &Path,
+    mirror: &Path,
+    stats: &BackupStats,
+) -> std::io::Result<()> {
+    // Entries below a directory that could not be read would all look deleted
+    if stats.files_failed > 0 {
+        return Err(Error::other(format!(
+            "Not deleting anything from the mirror: {} entries of the source could not be copied",
+            stats.files_failed
+        )));
+    }
+    // An unmounted drive or share usually leaves an empty mount point behind
+    if fs::read_dir(src)?.next().is_none() && fs::read_dir(mirror)?.next().is_some() {
+        return Err(Error::other(format!(
+            "Not deleting anything from the mirror: the source {:?} is empty (is it mounted?). Use --no-delete to update the mirror anyway, or remove it to start over",
+            src
+        )));
+    }
+    Ok(())
+}
+
+/// Deletes entries from `mirror` that no longer exist in `src`.
+///
+/// Runs after the copy, so everything still present in the source is already up to date.
+/// Entries matching the exclusion rules are never deleted, even if they are missing from
+/// the source. With `options.dry_run` the entries are only reported, not deleted.
+///
+/// # Parameters
+/// - `src`: Source directory.
+/// - `mirror`: Mirror of `src` to clean up.
+/// - `options`: Exclusion settings, applied to the mirror's entries.
+/// - `stats`: `files_deleted` is incremented for every removed entry.
+/// - `sink`: Receives a `FileDeleted` event for every removed entry.
+///
+/// # Errors
+/// - Will return an error if a directory cannot be read or an entry cannot be removed.
+pub fn remove_deleted(
+    src: &Path,
+    mirror: &Path,
+    options: &CopyOptions,
+    stats: &mut BackupStats,
+    sink: &dyn EventSink,
+) -> std::io::Result<()> {
+    remove_deleted_below(src, src, mirror, options, stats, sink)
+}
+
+/// Deletes entries from `mirror`, the mirror of `src` below the source root `root`; see
+/// `remove_deleted`.
+fn remove_deleted_below(
+    root: &Path,
+    src: &Path,
+    mirror: &Path,
+    options: &CopyOptions,
+    stats: &mut BackupStats,
+    sink: &dyn EventSink,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(mirror)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let mirror_item = entry.path();
+        let src_item = src.join(&file_name);
+
+        // Exclusions are checked against the source path, where they were applied when copying
+        let file_type = entry.file_type()?;
+        let file_name = file_name.to_string_lossy();
+        if backup::is_excluded_name(options, root, &src_item, &file_name, || file_type.is_dir())
+            || (!file_type.is_dir() && backup::has_excluded_extension(options, &src_item))
+        {
+            continue;
+        }
+
+        match fs::metadata(&src_item) {
+            Ok(metadata) => {
+                if metadata.is_dir() && file_type.is_dir() {
+                    let deleted_before = stats.files_deleted;
+                    remove_deleted_below(root, &src_item, &mirror_item, options, stats, sink)?;
+                    // Deleting entries changed the directory's modification time
+                    if stats.files_deleted > deleted_before && !options.dry_run {
+                        backup::copy_dir_metadata(&mirror_item, &metadata)?;
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // The source is gone, so its content type is sniffed from the mirrored copy
+                if !file_type.is_dir() && backup::has_excluded_mime_type(options, &mirror_item) {
+                    continue;
+                }
+                if !options.dry_run {
+                    // The mirrored directory has the permissions of its source, which may be read-only
+                    backup::make_dir_writable(mirror)?;
+                    if file_type.is_dir() {
+                        fs::remove_dir_all(&mirror_item)?;
+                    } else {
+                        fs::remove_file(&mirror_item)?;
+                    }
+                }
+                stats.files_deleted += 1;
+                sink.emit(&Event::FileDeleted { path: &mirror_item });
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,470 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+/// Template used when none is configured: `<source>_backup_<timestamp>`.
+pub const DEFAULT_TEMPLATE: &str = "{source}_backup_{timestamp}";
+
+/// Format and width of the `{timestamp}` placeholder, e.g. `2024-01-31_23-59-59`.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+const TIMESTAMP_WIDTH: usize = 19;
+/// Format and width of the `{date}` placeholder, e.g. `20240131`.
+const DATE_FORMAT: &str = "%Y%m%d";
+const DATE_WIDTH: usize = 8;
+/// Format and width of the `{time}` placeholder, e.g. `235959`.
+const TIME_FORMAT: &str = "%H%M%S";
+const TIME_WIDTH: usize = 6;
+/// Appended to `{timestamp}` and `{time}` when the name is in UTC rather than local time.
+const UTC_MARKER: char = 'Z';
+/// Separates the directory levels of a nested template such as `{hostname}/{source}/{date}`.
+pub const LEVEL_SEPARATOR: char = '/';
+
+/// A piece of a parsed name template.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Source,
+    Timestamp,
+    Date,
+    Time,
+    Hostname,
+    Profile,
+}
+
+/// Values substituted for the placeholders of a template.
+pub struct NameValues<'a> {
+    pub source: &'a str,
+    pub time: NaiveDateTime,
+    /// `time` is in UTC: `{timestamp}` and `{time}` get a trailing `Z`.
+    pub utc: bool,
+    pub hostname: &'a str,
+    /// Configuration section used for the run.
+    pub profile: &'a str,
+}
+
+/// Source name and time recovered from a backup directory name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedName {
+    /// `None` if the template has no `{source}` placeholder.
+    pub source: Option<String>,
+    pub time: NaiveDateTime,
+    /// `time` is in UTC (the name carries a `Z` marker) rather than local time.
+    pub utc: bool,
+}
+
+/// A validated backup directory name template such as `{hostname}-{source}-{date}-{time}`.
+///
+/// Supported placeholders: `{source}`, `{timestamp}` (`2024-01-31_23-59-59`), `{date}`
+/// (`20240131`), `{time}` (`235959`), `{hostname}` (or `{host}`) and `{profile}` (the
+/// configuration section). A `/` nests backups in subdirectories, e.g. `{host}/{source}/{date}`;
+/// the last level is the backup directory itself.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    template: String,
+    tokens: Vec<Token>,
+}
+
+impl NameTemplate {
+    /// Parses and validates a template.
+    ///
+    /// # Errors
+    /// - Returns a message if the template contains an unknown or unterminated placeholder, a
+    ///   backslash, an empty or `.`/`..` level, or neither `{timestamp}` nor `{date}` (backups
+    ///   could not be ordered).
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        if template.contains('\\') {
+            return Err(format!(
+                "Name template {:?} must separate levels with / rather than \\",
+                template
+            ));
+        }
+        if template
+            .split(LEVEL_SEPARATOR)
+            .any(|level| level.is_empty() || level == "." || level == "..")
+        {
+            return Err(format!(
+                "Name template {:?} has an empty, . or .. directory level",
+                template
+            ));
+        }
+
+        let mut tokens = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                tokens.push(Token::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                format!("Unterminated placeholder in name template {:?}", template)
+            })?;
+            let token = match &rest[start + 1..start + end] {
+                "source" => Token::Source,
+                "timestamp" => Token::Timestamp,
+                "date" => Token::Date,
+                "time" => Token::Time,
+                "hostname" | "host" => Token::Hostname,
+                "profile" => Token::Profile,
+                unknown => {
+                    return Err(format!(
+                        "Unknown placeholder {{{}}} in name template {:?}",
+                        unknown, template
+                    ))
+                }
+            };
+            tokens.push(token);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(rest.to_string()));
+        }
+
+        if !tokens
+            .iter()
+            .any(|t| matches!(t, Token::Timestamp | Token::Date))
+        {
+            return Err(format!(
+                "Name template {:?} must contain {{timestamp}} or {{date}}",
+                template
+            ));
+        }
+        Ok(NameTemplate {
+            template: template.to_string(),
+            tokens,
+        })
+    }
+
+    /// Returns the template as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    /// Returns how many directory levels a rendered name has (1 unless the template nests).
+    pub fn levels(&self) -> usize {
+        self.template.matches(LEVEL_SEPARATOR).count() + 1
+    }
+
+    /// Substitutes `values` into the template.
+    ///
+    /// # Returns
+    /// - The relative path of the backup directory, with levels separated by `/`.
+    ///
+    /// # Errors
+    /// - Returns a message if a level of the rendered name is empty or `.`/`..`, or if a value
+    ///   contains a path separator.
+    pub fn render(&self, values: &NameValues) -> Result<String, String> {
+        for value in [values.source, values.hostname, values.profile] {
+            if value.contains(['/', '\\']) {
+                return Err(format!(
+                    "{:?} cannot be used in a backup directory name",
+                    value
+                ));
+            }
+        }
+
+        let mut name = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(text) => name.push_str(text),
+                Token::Source => name.push_str(values.source),
+                Token::Timestamp => {
+                    name.push_str(&values.time.format(TIMESTAMP_FORMAT).to_string());
+                    if values.utc {
+                        name.push(UTC_MARKER);
+                    }
+                }
+                Token::Date => name.push_str(&values.time.format(DATE_FORMAT).to_string()),
+                Token::Time => {
+                    name.push_str(&values.time.format(TIME_FORMAT).to_string());
+                    if values.utc {
+                        name.push(UTC_MARKER);
+                    }
+                }
+                Token::Hostname => name.push_str(values.hostname),
+                Token::Profile => name.push_str(values.profile),
+            }
+        }
+
+        if name
+            .split(LEVEL_SEPARATOR)
+            .any(|level| level.is_empty() || level == "." || level == "..")
+        {
+            return Err(format!("Invalid backup directory name {:?}", name));
+        }
+        Ok(name)
+    }
+
+    /// Recovers the source name and time from a directory name produced by `render`.
+    ///
+    /// The levels of `name` must be separated by `/`, as `render` returns them.
+    ///
+    /// # Returns
+    /// - `None` if `name` does not match the template.
+    pub fn parse_name(&self, name: &str) -> Option<ParsedName> {
+        let mut parsed = Fields::default();
+        if !match_tokens(&self.tokens, name, &mut parsed) {
+            return None;
+        }
+
+        let date = parsed.date?;
+        Some(ParsedName {
+            source: parsed.source,
+            time: date.and_time(parsed.time.unwrap_or_default()),
+            utc: parsed.utc,
+        })
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate::parse(DEFAULT_TEMPLATE).expect("the default name template is valid")
+    }
+}
+
+/// Returns the time to name a backup taken at `now` after: UTC with `utc`, local time otherwise.
+pub fn name_time(now: DateTime<Utc>, utc: bool) -> NaiveDateTime {
+    if utc {
+        now.naive_utc()
+    } else {
+        now.with_timezone(&Local).naive_local()
+    }
+}
+
+/// Returns the name of this machine, for the `{hostname}` placeholder.
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for `buffer.len()` bytes; the name is NUL-terminated on success
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+/// Returns the name of this machine, for the `{hostname}` placeholder.
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Fields captured while matching a name against the template tokens.
+#[derive(Default, Clone)]
+struct Fields {
+    source: Option<String>,
+    date: Option<NaiveDate>,
+    time: Option<NaiveTime>,
+    utc: bool,
+}
+
+/// Matches `name` against `tokens`, backtracking over the free-form placeholders.
+fn match_tokens(tokens: &[Token], name: &str, fields: &mut Fields) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return name.is_empty();
+    };
+
+    match token {
+        Token::Literal(text) => name
+            .strip_prefix(text.as_str())
+            .is_some_and(|name| match_tokens(rest, name, fields)),
+        Token::Timestamp => {
+            let Some(time) = name
+                .get(..TIMESTAMP_WIDTH)
+                .and_then(|value| NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT).ok())
+            else {
+                return false;
+            };
+            fields.date = Some(time.date());
+            fields.time = Some(time.time());
+            match_utc_marker(rest, &name[TIMESTAMP_WIDTH..], fields)
+        }
+        Token::Date => {
+            let Some(date) = name
+                .get(..DATE_WIDTH)
+                .and_then(|value| NaiveDate::parse_from_str(value, DATE_FORMAT).ok())
+            else {
+                return false;
+            };
+            fields.date = Some(date);
+            match_tokens(rest, &name[DATE_WIDTH..], fields)
+        }
+        Token::Time => {
+            let Some(time) = name
+                .get(..TIME_WIDTH)
+                .and_then(|value| NaiveTime::parse_from_str(value, TIME_FORMAT).ok())
+            else {
+                return false;
+            };
+            fields.time = Some(time);
+            match_utc_marker(rest, &name[TIME_WIDTH..], fields)
+        }
+        Token::Source | Token::Hostname | Token::Profile => {
+            // Free-form values may contain anything but a separator, so try every non-empty
+            // prefix of the current level
+            let level_end = name.find(LEVEL_SEPARATOR).unwrap_or(name.len());
+            (1..=level_end)
+                .filter(|&end| name.is_char_boundary(end))
+                .any(|end| {
+                    let mut candidate = fields.clone();
+                    if *token == Token::Source {
+                        candidate.source = Some(name[..end].to_string());
+                    }
+                    let matched = match_tokens(rest, &name[end..], &mut candidate);
+                    if matched {
+                        *fields = candidate;
+                    }
+                    matched
+                })
+        }
+    }
+}
+
+/// Matches the rest of a name after `{timestamp}` or `{time}`, which may carry a UTC marker.
+fn match_utc_marker(tokens: &[Token], name: &str, fields: &mut Fields) -> bool {
+    if let Some(after) = name.strip_prefix(UTC_MARKER) {
+        let mut candidate = fields.clone();
+        candidate.utc = true;
+        if match_tokens(tokens, after, &mut candidate) {
+            *fields = candidate;
+            return true;
+        }
+    }
+    match_tokens(tokens, name, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fixed_instant() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 31, 23, 59, 58).unwrap()
+    }
+
+    fn values(time: NaiveDateTime, utc: bool) -> NameValues<'static> {
+        NameValues {
+            source: "docs",
+            time,
+            utc,
+            hostname: "web1",
+            profile: "default",
+        }
+    }
+
+    #[test]
+    fn rendering_substitutes_every_placeholder() {
+        let time = NaiveDate::from_ymd_opt(2024, 1, 31)
+            .unwrap()
+            .and_hms_opt(9, 5, 7)
+            .unwrap();
+        let template =
+            NameTemplate::parse("{hostname}-{host}-{source}-{profile}-{date}-{time}_{timestamp}")
+                .unwrap();
+
+        assert_eq!(
+            template.render(&values(time, false)).unwrap(),
+            "web1-web1-docs-default-20240131-090507_2024-01-31_09-05-07"
+        );
+        assert_eq!(
+            template.render(&values(time, true)).unwrap(),
+            "web1-web1-docs-default-20240131-090507Z_2024-01-31_09-05-07Z"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_named_in_the_error() {
+        let error = NameTemplate::parse("{source}_{nope}_{date}").unwrap_err();
+        assert_eq!(
+            error,
+            "Unknown placeholder {nope} in name template \"{source}_{nope}_{date}\""
+        );
+        // Placeholders are case-sensitive
+        let error = NameTemplate::parse("{Source}_{date}").unwrap_err();
+        assert!(
+            error.starts_with("Unknown placeholder {Source}"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn default_template_in_utc_ends_with_marker() {
+        let time = name_time(fixed_instant(), true);
+        let name = NameTemplate::default().render(&values(time, true)).unwrap();
+        assert_eq!(name, "docs_backup_2024-01-31_23-59-58Z");
+    }
+
+    #[test]
+    fn default_template_in_local_time_has_no_marker() {
+        let time = name_time(fixed_instant(), false);
+        let name = NameTemplate::default()
+            .render(&values(time, false))
+            .unwrap();
+        let expected = fixed_instant()
+            .with_timezone(&Local)
+            .format("docs_backup_%Y-%m-%d_%H-%M-%S")
+            .to_string();
+        assert_eq!(name, expected);
+    }
+
+    #[test]
+    fn names_round_trip_in_both_modes() {
+        let template = NameTemplate::parse("{hostname}-{source}-{date}-{time}").unwrap();
+        for utc in [false, true] {
+            let time = name_time(fixed_instant(), utc);
+            let name = template.render(&values(time, utc)).unwrap();
+            let parsed = template.parse_name(&name).unwrap();
+            assert_eq!(
+                parsed,
+                ParsedName {
+                    source: Some("docs".to_string()),
+                    time,
+                    utc,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn marker_is_not_taken_from_a_literal() {
+        // The Z after {timestamp} belongs to the template here, not to a UTC timestamp
+        let template = NameTemplate::parse("{source}_{timestamp}Z").unwrap();
+        let time = name_time(fixed_instant(), false);
+        let name = template.render(&values(time, false)).unwrap();
+        let parsed = template.parse_name(&name).unwrap();
+        assert!(!parsed.utc);
+        assert_eq!(parsed.time, time);
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(NameTemplate::parse("{source}").is_err());
+        assert!(NameTemplate::parse("a\\{timestamp}").is_err());
+        assert!(NameTemplate::parse("a//{timestamp}").is_err());
+        assert!(NameTemplate::parse("../{timestamp}").is_err());
+        assert!(NameTemplate::parse("{timestamp}/").is_err());
+        assert!(NameTemplate::parse("{timestamp").is_err());
+    }
+
+    #[test]
+    fn nested_names_round_trip() {
+        let template = NameTemplate::parse("{host}/{source}/{date}").unwrap();
+        assert_eq!(template.levels(), 3);
+        let time = name_time(fixed_instant(), true);
+        let name = template.render(&values(time, true)).unwrap();
+        assert_eq!(name, "web1/docs/20240131");
+
+        let parsed = template.parse_name(&name).unwrap();
+        assert_eq!(parsed.source.as_deref(), Some("docs"));
+        assert!(template.parse_name("web1/docs/extra/20240131").is_none());
+    }
+
+    #[test]
+    fn values_with_separators_are_rejected() {
+        let time = name_time(fixed_instant(), false);
+        let values = NameValues {
+            profile: "a/b",
+            ..values(time, false)
+        };
+        let template = NameTemplate::parse("{profile}_{timestamp}").unwrap();
+        assert!(template.render(&values).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::backup::SourceReport;
+use crate::format::format_bytes;
+use serde_json::{json, Value};
+use std::io::Error;
+use std::time::Duration;
+
+/// Maximum time spent delivering a notification, so an unreachable endpoint can't stall the run.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the JSON document describing a backup run.
+///
+/// # Parameters
+/// - `reports`: One report per backed-up source.
+/// - `interrupted`: The run stopped (e.g. on Ctrl-C) before every source was backed up.
+///
+/// # Returns
+/// - A JSON object with an overall `success` flag (`false` for an interrupted run, whatever the
+///   sources backed up before it stopped), an `interrupted` flag, a one-line `text` summary
+///   (which Slack and compatible chat webhooks display as the message) and one entry per source
+///   holding its backup directory, duration, copied bytes and files, and error message.
+pub fn build_payload(reports: &[SourceReport], interrupted: bool) -> Value {
+    let sources: Vec<Value> = reports
+        .iter()
+        .map(|report| {
+            json!({
+                "source": report.source,
+                "success": report.succeeded(),
+                "backup_dir": report.result.as_ref().ok(),
+                "duration_seconds": report.duration.as_secs_f64(),
+                "files_copied": report.stats.files_copied,
+                "bytes_copied": report.stats.bytes_copied,
+                "files_skipped": report.stats.files_skipped,
+                "error": report.result.as_ref().err(),
+            })
+        })
+        .collect();
+
+    json!({
+        "success": !interrupted && reports.iter().all(SourceReport::succeeded),
+        "interrupted": interrupted,
+        "text": summary_text(reports, interrupted),
+        "sources": sources,
+    })
+}
+
+/// Summarizes a run in one line, e.g. `Backup succeeded: 2 sources, 120 files (1.5 MiB) copied`,
+/// `Backup failed for docs: <error>` or `Backup interrupted: 1 sources started, ...`.
+fn summary_text(reports: &[SourceReport], interrupted: bool) -> String {
+    let failed: Vec<String> = reports
+        .iter()
+        .filter(|report| !report.succeeded())
+        .map(|report| match &report.result {
+            Err(e) => format!("{}: {}", report.source, e),
+            Ok(_) => format!(
+                "{}: {} entries could not be copied",
+                report.source, report.stats.files_failed
+            ),
+        })
+        .collect();
+    let files: u64 = reports.iter().map(|report| report.stats.files_copied).sum();
+    let bytes: u64 = reports.iter().map(|report| report.stats.bytes_copied).sum();
+    // The source being copied when the run stopped failed with the cancellation, so it is
+    // counted as started rather than listed as a failure
+    if interrupted {
+        return format!(
+            "Backup interrupted: {} sources started, {} files ({}) copied",
+            reports.len(),
+            files,
+            format_bytes(bytes)
+        );
+    }
+    if !failed.is_empty() {
+        return format!("Backup failed for {}", failed.join("; "));
+    }
+
+    format!(
+        "Backup succeeded: {} sources, {} files ({}) copied",
+        reports.len(),
+        files,
+        format_bytes(bytes)
+    )
+}
+
+/// POSTs the run report as JSON to a webhook.
+///
+/// # Parameters
+/// - `url`: Webhook endpoint.
+/// - `reports`: One report per backed-up source.
+/// - `interrupted`: The run stopped before every source was backed up.
+///
+/// # Errors
+/// - Will return an error if the request fails, times out, or the server answers with an error
+///   status.
+pub fn send_webhook(url: &str, reports: &[SourceReport], interrupted: bool) -> std::io::Result<()> {
+    ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .send_json(build_payload(reports, interrupted))
+        .map_err(Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupStats;
+
+    fn report(source: &str, result: Result<&str, &str>, files_copied: u64) -> SourceReport {
+        SourceReport {
+            source: source.to_string(),
+            stats: BackupStats {
+                files_copied,
+                bytes_copied: files_copied * 1024,
+                ..BackupStats::default()
+            },
+            duration: Duration::from_secs(1),
+            result: result.map(Into::into).map_err(Into::into),
+        }
+    }
+
+    #[test]
+    fn successful_run_is_summarized_in_text() {
+        let payload = build_payload(
+            &[
+                report("docs", Ok("/b/docs"), 2),
+                report("src", Ok("/b/src"), 3),
+            ],
+            false,
+        );
+
+        assert_eq!(payload["success"], true);
+        assert_eq!(
+            payload["text"],
+            "Backup succeeded: 2 sources, 5 files (5.0 KiB) copied"
+        );
+    }
+
+    #[test]
+    fn failed_sources_are_named_in_text() {
+        let payload = build_payload(
+            &[
+                report("docs", Ok("/b/docs"), 2),
+                report("src", Err("disk full"), 0),
+            ],
+            false,
+        );
+
+        assert_eq!(payload["success"], false);
+        assert_eq!(payload["text"], "Backup failed for src: disk full");
+    }
+
+    #[test]
+    fn interrupted_run_is_a_failure() {
+        // Ctrl-C arrived after the first source; the second one was never started
+        let payload = build_payload(&[report("docs", Ok("/b/docs"), 2)], true);
+
+        assert_eq!(payload["success"], false);
+        assert_eq!(payload["interrupted"], true);
+        assert_eq!(payload["sources"][0]["success"], true);
+        assert_eq!(
+            payload["text"],
+            "Backup interrupted: 1 sources started, 2 files (2.0 KiB) copied"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use glob::MatchOptions;
+
+/// An exclusion pattern, compiled once, with the semantics of a `.gitignore` line.
+///
+/// - A pattern without a slash (`node_modules`, `*.log`) matches the name of an entry at any
+///   depth.
+/// - A pattern with a leading or inner slash (`/build`, `docs/*.pdf`) matches the path of an
+///   entry relative to the source root; `**` stands for any number of directories.
+/// - A trailing slash (`cache/`) makes a pattern match directories only.
+///
+/// `*`, `?` and `[...]` never match a slash.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    text: String,
+    glob: glob::Pattern,
+    /// Matched against the relative path rather than the name.
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    /// Compiles a pattern.
+    ///
+    /// # Errors
+    /// - Will return an error describing the problem if the glob syntax is invalid, such as an
+    ///   unclosed `[` or `**` that is not a whole path component.
+    pub fn new(text: &str) -> Result<Pattern, String> {
+        let (body, dir_only) = match text.strip_suffix('/') {
+            Some(body) => (body, true),
+            None => (text, false),
+        };
+        let anchored = body.contains('/');
+        let body = body.strip_prefix('/').unwrap_or(body);
+        let glob = glob::Pattern::new(body).map_err(|e| e.to_string())?;
+        Ok(Pattern {
+            text: text.to_string(),
+            glob,
+            anchored,
+            dir_only,
+        })
+    }
+
+    /// Returns the pattern as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Checks whether an entry matches the pattern.
+    ///
+    /// # Parameters
+    /// - `relative`: Path of the entry relative to the root, with `/` between its components.
+    /// - `is_dir`: Tells whether the entry is a directory; only called for directory-only
+    ///   patterns whose glob matches.
+    /// - `case_insensitive`: Match regardless of (ASCII) case.
+    pub fn matches(
+        &self,
+        relative: &str,
+        is_dir: impl FnOnce() -> bool,
+        case_insensitive: bool,
+    ) -> bool {
+        let options = MatchOptions {
+            case_sensitive: !case_insensitive,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let subject = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+        self.glob.matches_with(subject, options) && (!self.dir_only || is_dir())
+    }
+}
+
+/// Compiles a list of patterns.
+///
+/// # Errors
+/// - Will return an error listing every invalid pattern with its problem, not just the first.
+pub fn compile(patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    let mut compiled = Vec::new();
+    let mut invalid = Vec::new();
+    for text in patterns {
+        match Pattern::new(text) {
+            Ok(pattern) => compiled.push(pattern),
+            Err(e) => invalid.push(format!("{:?} ({})", text, e)),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(compiled)
+    } else {
+        Err(format!("invalid patterns: {}", invalid.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, relative: &str, is_dir: bool) -> bool {
+        Pattern::new(pattern)
+            .unwrap()
+            .matches(relative, || is_dir, false)
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        assert!(matches("node_modules", "node_modules", true));
+        assert!(matches("node_modules", "web/app/node_modules", true));
+        assert!(!matches("node_modules", "web/node_modules_old", true));
+        assert!(matches("*.log", "server.log", false));
+        assert!(matches("*.log", "var/logs/server.log", false));
+        assert!(!matches("*.log", "server.log.gz", false));
+        assert!(matches(".*", "home/.cache", true));
+    }
+
+    #[test]
+    fn slashes_anchor_patterns_to_the_root() {
+        assert!(matches("/build", "build", true));
+        assert!(!matches("/build", "src/build", true));
+        assert!(matches("docs/*.pdf", "docs/manual.pdf", false));
+        assert!(!matches("docs/*.pdf", "old/docs/manual.pdf", false));
+        // A wildcard does not match across directories
+        assert!(!matches("docs/*.pdf", "docs/v1/manual.pdf", false));
+    }
+
+    #[test]
+    fn double_stars_match_any_number_of_directories() {
+        assert!(matches("**/target", "target", true));
+        assert!(matches("**/target", "crates/core/target", true));
+        assert!(matches("docs/**/*.pdf", "docs/manual.pdf", false));
+        assert!(matches("docs/**/*.pdf", "docs/v1/old/manual.pdf", false));
+        assert!(matches("logs/**", "logs/2024/01/app.log", false));
+        assert!(!matches("logs/**", "var/logs/app.log", false));
+    }
+
+    #[test]
+    fn trailing_slashes_match_directories_only() {
+        assert!(matches("cache/", "cache", true));
+        assert!(matches("cache/", "app/cache", true));
+        assert!(!matches("cache/", "cache", false));
+        assert!(matches("/out/", "out", true));
+        assert!(!matches("/out/", "src/out", true));
+
+        // The entry type is only looked up once the name matches
+        let pattern = Pattern::new("cache/").unwrap();
+        assert!(!pattern.matches("src", || panic!("type looked up"), false));
+    }
+
+    #[test]
+    fn case_is_ignored_on_request() {
+        let pattern = Pattern::new("*.JPG").unwrap();
+        assert!(pattern.matches("photos/a.jpg", || false, true));
+        assert!(!pattern.matches("photos/a.jpg", || false, false));
+    }
+
+    #[test]
+    fn every_invalid_pattern_is_listed() {
+        let patterns = ["ok", "[abc", "*.log", "a**"].map(String::from);
+        let error = compile(&patterns).unwrap_err();
+
+        assert!(error.contains("\"[abc\""), "{}", error);
+        assert!(error.contains("\"a**\""), "{}", error);
+        assert!(!error.contains("\"ok\""), "{}", error);
+        assert_eq!(compile(&patterns[..1]).unwrap()[0].as_str(), "ok");
+    }
+}
@@ -0,0 +1,578 @@
+use crate::atomic::write_atomic;
+use crate::backup::{
+    copy_directory, BackupStats, CopyOptions, MANIFESTS_DIR_NAME, OBJECTS_DIR_NAME,
+};
+use crate::events::{Event, EventSink, SkipReason};
+use crate::patterns::Pattern;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Size of the chunks files are split into; the last chunk of a file is shorter.
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Suffix of the files in `manifests/`.
+const MANIFEST_EXTENSION: &str = ".json";
+
+/// The record of one backup stored in a repository: every entry and, for files, the chunks
+/// their contents are made of.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Source directory the backup was made of.
+    pub source: PathBuf,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A directory or file of a backup, under its path relative to the source with `/` between the
+/// components.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ManifestEntry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        size: u64,
+        /// Modification time as seconds and nanoseconds since the Unix epoch.
+        modified: (i64, u32),
+        /// SHA-256 digests of the chunks, in order.
+        chunks: Vec<String>,
+    },
+}
+
+impl ManifestEntry {
+    /// Returns the path of the entry relative to the source.
+    pub fn path(&self) -> &str {
+        match self {
+            ManifestEntry::Dir { path } | ManifestEntry::File { path, .. } => path,
+        }
+    }
+}
+
+/// Records the entries a dry-run copy would copy, and passes every other event on.
+struct Planner<'a> {
+    root: &'a Path,
+    sink: &'a dyn EventSink,
+    /// Entries in the order of the walk, with whether each is a directory.
+    entries: Mutex<Vec<(PathBuf, bool)>>,
+}
+
+impl EventSink for Planner<'_> {
+    fn emit(&self, event: &Event) {
+        let entry = match event {
+            Event::DirEntered { path } if *path != self.root => (path.to_path_buf(), true),
+            // Reported once the file is stored
+            Event::FileCopied { path, .. } => (path.to_path_buf(), false),
+            _ => return self.sink.emit(event),
+        };
+        self.entries.lock().unwrap().push(entry);
+    }
+}
+
+/// Returns the path of `path` relative to `root`, with `/` between the components.
+fn relative_name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Returns the path of the object holding the chunk with the digest `digest`.
+fn object_path(objects: &Path, digest: &str) -> PathBuf {
+    objects.join(&digest[..2]).join(digest)
+}
+
+/// Splits a file into chunks and stores those not yet in `objects`.
+///
+/// # Returns
+/// - The manifest entry of the file.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read or a chunk cannot be written.
+fn store_file(path: &Path, relative: String, objects: &Path) -> std::io::Result<ManifestEntry> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let modified = FileTime::from_last_modification_time(&metadata);
+
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    let mut chunk = Vec::new();
+    loop {
+        chunk.clear();
+        (&mut file).take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        let digest = format!("{:x}", Sha256::digest(&chunk));
+        let object = object_path(objects, &digest);
+        // A chunk is only ever renamed into place complete, so one that exists is usable
+        if !object.exists() {
+            fs::create_dir_all(object.parent().unwrap())?;
+            write_atomic(&object, &chunk)?;
+        }
+        size += chunk.len() as u64;
+        chunks.push(digest);
+    }
+    Ok(ManifestEntry::File {
+        path: relative,
+        size,
+        modified: (modified.unix_seconds(), modified.nanoseconds()),
+        chunks,
+    })
+}
+
+/// Backs up `src` into the repository `repo`: file contents are split into chunks of
+/// `CHUNK_SIZE` bytes, each stored once under its SHA-256 digest in `objects/`, and the backup
+/// itself is a manifest in `manifests/` listing every entry with its chunks.
+///
+/// The entries are chosen by a dry run of `copy_directory`, so exclusions and every other rule
+/// of `options` apply exactly as in a copy. Chunks are written to a temporary file, synced and
+/// renamed, and the manifest is written last, so an interrupted run leaves no partial backup;
+/// its chunks are removed by `prune`.
+///
+/// # Parameters
+/// - `src`: Source directory.
+/// - `repo`: Target directory holding the repository.
+/// - `name`: Name of the backup, rendered from the name template; it may contain `/`.
+/// - `options`: The options of the backup.
+/// - `stats`: Counters updated with every stored or skipped entry.
+/// - `cancel`: Flag checked before each file; once set, storing stops.
+/// - `sink`: Receives the events of the backup.
+///
+/// # Returns
+/// - The path of the manifest.
+///
+/// # Errors
+/// - Will return an error if `src` cannot be read, the target filesystem is full or the
+///   manifest cannot be written. Files that cannot be read are reported and counted instead.
+/// - Will return an error of kind `ErrorKind::Interrupted` if `cancel` was set.
+pub fn store_backup(
+    src: &Path,
+    repo: &Path,
+    name: &str,
+    options: &CopyOptions,
+    stats: &mut BackupStats,
+    cancel: &AtomicBool,
+    sink: &dyn EventSink,
+) -> std::io::Result<PathBuf> {
+    let planner = Planner {
+        root: src,
+        sink,
+        entries: Mutex::new(Vec::new()),
+    };
+    let plan_options = CopyOptions {
+        dry_run: true,
+        ..options.clone()
+    };
+    copy_directory(src, repo, &plan_options, stats, cancel, &planner)?;
+    // The dry run counted what a copy would copy; count what is stored instead
+    stats.files_copied = 0;
+    stats.bytes_copied = 0;
+
+    let objects = repo.join(OBJECTS_DIR_NAME);
+    let mut entries = Vec::new();
+    for (path, is_dir) in planner.entries.into_inner().unwrap() {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::Interrupted, "Backup cancelled"));
+        }
+        let relative = relative_name(src, &path);
+        if is_dir {
+            entries.push(ManifestEntry::Dir { path: relative });
+            continue;
+        }
+        // With `special_files` a dry run counts them as copied, but opening one may block forever
+        if fs::metadata(&path).is_ok_and(|metadata| !metadata.is_file()) {
+            stats.special_files_skipped += 1;
+            sink.emit(&Event::FileSkipped {
+                path: &path,
+                reason: SkipReason::SpecialFile,
+            });
+            continue;
+        }
+        match store_file(&path, relative, &objects) {
+            Ok(entry) => {
+                if let ManifestEntry::File { size, .. } = entry {
+                    stats.files_copied += 1;
+                    stats.bytes_copied += size;
+                    sink.emit(&Event::FileCopied {
+                        path: &path,
+                        bytes: size,
+                    });
+                }
+                entries.push(entry);
+            }
+            Err(e) if e.kind() == ErrorKind::StorageFull => return Err(e),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                stats.files_vanished += 1;
+                sink.emit(&Event::FileSkipped {
+                    path: &path,
+                    reason: SkipReason::Vanished,
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied && !options.fail_on_unreadable => {
+                stats.files_unreadable.push(path.clone());
+                sink.emit(&Event::FileSkipped {
+                    path: &path,
+                    reason: SkipReason::Unreadable,
+                });
+            }
+            Err(e) => {
+                stats.files_failed += 1;
+                sink.emit(&Event::Error {
+                    message: format!("Failed to copy {:?}: {}", path, e),
+                });
+            }
+        }
+    }
+
+    let manifest = Manifest {
+        source: src.to_path_buf(),
+        entries,
+    };
+    let path = free_manifest_path(&repo.join(MANIFESTS_DIR_NAME), name);
+    fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(&path, &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(path)
+}
+
+/// Returns the path of a manifest called `name` that does not exist yet, appending `_2`, `_3`,
+/// ... like backup directories do when two runs get the same name.
+fn free_manifest_path(manifests: &Path, name: &str) -> PathBuf {
+    (1..)
+        .map(|attempt| {
+            let mut file_name = OsString::from(name);
+            if attempt > 1 {
+                file_name.push(format!("_{}", attempt));
+            }
+            file_name.push(MANIFEST_EXTENSION);
+            manifests.join(file_name)
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Returns the repository a manifest belongs to: the parent of the `manifests` directory the
+/// manifest lies in.
+pub fn repository_of(manifest: &Path) -> Option<&Path> {
+    manifest
+        .ancestors()
+        .skip(1)
+        .find(|dir| {
+            dir.file_name()
+                .is_some_and(|name| name == MANIFESTS_DIR_NAME)
+        })
+        .and_then(Path::parent)
+}
+
+/// Reads a manifest.
+///
+/// # Errors
+/// - Will return an error if the file cannot be read or is not a manifest.
+pub fn read_manifest(path: &Path) -> std::io::Result<Manifest> {
+    let data = fs::read(path)?;
+    serde_json::from_slice(&data).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} is not a manifest: {}", path, e),
+        )
+    })
+}
+
+impl Manifest {
+    /// Selects the entries matched by any of `patterns`, along with everything below a matched
+    /// directory, the way `restore --pick` selects entries of a backup directory.
+    ///
+    /// # Returns
+    /// - The selected entries, and whether each pattern matched anything.
+    pub fn select(
+        &self,
+        patterns: &[Pattern],
+        case_insensitive: bool,
+    ) -> (Vec<&ManifestEntry>, Vec<bool>) {
+        let mut used = vec![false; patterns.len()];
+        let mut selected = Vec::new();
+        for entry in &self.entries {
+            let path = entry.path();
+            let is_dir = matches!(entry, ManifestEntry::Dir { .. });
+            // The entry itself and every directory above it
+            let prefixes = path
+                .match_indices('/')
+                .map(|(slash, _)| (&path[..slash], true))
+                .chain([(path, is_dir)]);
+            let mut matched = false;
+            for (prefix, prefix_is_dir) in prefixes {
+                for (pattern, used) in patterns.iter().zip(&mut used) {
+                    if pattern.matches(prefix, || prefix_is_dir, case_insensitive) {
+                        *used = true;
+                        matched = true;
+                    }
+                }
+            }
+            if matched {
+                selected.push(entry);
+            }
+        }
+        (selected, used)
+    }
+}
+
+/// Recreates entries of a manifest below `dest` from the chunks of the repository `repo`.
+///
+/// Every chunk is checked against its digest as it is read. Files get back their modification
+/// time; parent directories are created as needed.
+///
+/// # Parameters
+/// - `repo`: Repository holding the chunks.
+/// - `entries`: Entries to restore.
+/// - `dest`: Directory to restore into.
+/// - `stats`: `files_copied` and `bytes_copied` count the restored files.
+/// - `sink`: Receives a `FileCopied` event for every restored file.
+///
+/// # Errors
+/// - Will return an error if a chunk is missing or damaged, a path of the manifest leaves
+///   `dest`, or an entry cannot be written.
+pub fn restore_entries<'a>(
+    repo: &Path,
+    entries: impl IntoIterator<Item = &'a ManifestEntry>,
+    dest: &Path,
+    stats: &mut BackupStats,
+    sink: &dyn EventSink,
+) -> std::io::Result<()> {
+    let objects = repo.join(OBJECTS_DIR_NAME);
+    for entry in entries {
+        let relative = Path::new(entry.path());
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("the manifest path {:?} leaves the destination", relative),
+            ));
+        }
+        let path = dest.join(relative);
+        let ManifestEntry::File {
+            size,
+            modified,
+            chunks,
+            ..
+        } = entry
+        else {
+            fs::create_dir_all(&path)?;
+            continue;
+        };
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = File::create(&path)?;
+        for digest in chunks {
+            let chunk = fs::read(object_path(&objects, digest))?;
+            if format!("{:x}", Sha256::digest(&chunk)) != *digest {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("the chunk {} of {:?} is damaged", digest, relative),
+                ));
+            }
+            file.write_all(&chunk)?;
+        }
+        drop(file);
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(modified.0, modified.1))?;
+        stats.files_copied += 1;
+        stats.bytes_copied += size;
+        sink.emit(&Event::FileCopied {
+            path: &path,
+            bytes: *size,
+        });
+    }
+    Ok(())
+}
+
+/// Recursively collects the manifests below `dir`.
+fn find_manifests(dir: &Path, manifests: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_manifests(&path, manifests)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(MANIFEST_EXTENSION)
+        {
+            manifests.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the chunks of the repository `repo` that no manifest refers to, such as those of
+/// deleted manifests or of interrupted runs, along with temporary files left by interrupted
+/// writes. The caller holds the lock of the repository.
+///
+/// # Returns
+/// - The number of chunks deleted and their total size.
+///
+/// # Errors
+/// - Will return an error, before deleting anything, if a manifest cannot be read.
+/// - Will return an error if a chunk cannot be deleted.
+pub fn prune(repo: &Path) -> std::io::Result<(u64, u64)> {
+    let mut manifests = Vec::new();
+    let manifests_dir = repo.join(MANIFESTS_DIR_NAME);
+    if manifests_dir.is_dir() {
+        find_manifests(&manifests_dir, &mut manifests)?;
+    }
+    let mut referenced = HashSet::new();
+    for path in manifests {
+        for entry in read_manifest(&path)?.entries {
+            if let ManifestEntry::File { chunks, .. } = entry {
+                referenced.extend(chunks);
+            }
+        }
+    }
+
+    let objects = repo.join(OBJECTS_DIR_NAME);
+    if !objects.is_dir() {
+        return Ok((0, 0));
+    }
+    let (mut count, mut bytes) = (0, 0);
+    for fan_out in fs::read_dir(&objects)? {
+        let fan_out = fan_out?.path();
+        if !fan_out.is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(&fan_out)? {
+            let object = object?;
+            let name = object.file_name();
+            if referenced.contains(name.to_string_lossy().as_ref()) {
+                continue;
+            }
+            let size = object.metadata()?.len();
+            fs::remove_file(object.path())?;
+            if !name.to_string_lossy().ends_with(".tmp") {
+                count += 1;
+                bytes += size;
+            }
+        }
+        // Only succeeds once the directory is empty
+        let _ = fs::remove_dir(&fan_out);
+    }
+    Ok((count, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(src: &Path, repo: &Path) -> (PathBuf, BackupStats) {
+        let mut stats = BackupStats::default();
+        let manifest = store_backup(
+            src,
+            repo,
+            "src_backup",
+            &CopyOptions::default(),
+            &mut stats,
+            &AtomicBool::new(false),
+            &|_: &Event| {},
+        )
+        .unwrap();
+        (manifest, stats)
+    }
+
+    fn objects(repo: &Path) -> usize {
+        fs::read_dir(repo.join(OBJECTS_DIR_NAME))
+            .unwrap()
+            .map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn identical_chunks_are_stored_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let repo = temp.path().join("repo");
+        fs::create_dir_all(&src).unwrap();
+        // Two full chunks of zeros and a short tail, plus a copy of the tail as a file
+        let big = File::create(src.join("big")).unwrap();
+        big.set_len(2 * CHUNK_SIZE).unwrap();
+        drop(big);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(src.join("big"))
+            .unwrap()
+            .write_all(b"tail")
+            .unwrap();
+        fs::write(src.join("tail"), b"tail").unwrap();
+
+        let (manifest, stats) = store(&src, &repo);
+
+        assert_eq!(objects(&repo), 2);
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(stats.bytes_copied, 2 * CHUNK_SIZE + 8);
+        let manifest = read_manifest(&manifest).unwrap();
+        let chunks: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                ManifestEntry::File { chunks, .. } => chunks.len(),
+                ManifestEntry::Dir { .. } => 0,
+            })
+            .collect();
+        assert_eq!(chunks.iter().sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn damaged_chunks_and_escaping_paths_are_refused() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let repo = temp.path().join("repo");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"contents").unwrap();
+        let (manifest, _) = store(&src, &repo);
+        let manifest = read_manifest(&manifest).unwrap();
+        let ManifestEntry::File { chunks, .. } = &manifest.entries[0] else {
+            panic!("not a file");
+        };
+        fs::write(
+            object_path(&repo.join(OBJECTS_DIR_NAME), &chunks[0]),
+            b"other",
+        )
+        .unwrap();
+
+        let restore = |entries: &[ManifestEntry]| {
+            restore_entries(
+                &repo,
+                entries,
+                &temp.path().join("dest"),
+                &mut BackupStats::default(),
+                &|_: &Event| {},
+            )
+            .unwrap_err()
+            .to_string()
+        };
+
+        assert!(restore(&manifest.entries).contains("is damaged"));
+        let escaping = ManifestEntry::Dir {
+            path: "../outside".to_string(),
+        };
+        assert!(restore(&[escaping]).contains("leaves the destination"));
+        assert!(!temp.path().join("outside").exists());
+    }
+
+    #[test]
+    fn manifests_know_their_repository() {
+        assert_eq!(
+            repository_of(Path::new("/repo/manifests/host/src_backup.json")),
+            Some(Path::new("/repo"))
+        );
+        assert_eq!(repository_of(Path::new("/backups/src_backup.json")), None);
+    }
+}
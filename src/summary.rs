@@ -0,0 +1,73 @@
+use crate::atomic::write_atomic;
+use crate::backup::SourceReport;
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// Run-wide details recorded in the summary file next to the per-source reports.
+pub struct RunInfo<'a> {
+    pub target_dir: &'a str,
+    pub config_section: &'a str,
+    /// Effective options of the run, after configuration defaults were applied.
+    pub options: Value,
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    /// `true` if the run stopped (e.g. on Ctrl-C) before every source was backed up.
+    pub interrupted: bool,
+    /// Warnings reported during the run: entries left out or ancillary steps that failed.
+    pub warnings: Vec<String>,
+    /// Errors reported during the run: data that was not backed up.
+    pub errors: Vec<String>,
+    /// `--warnings-as-errors` was given, so any warning makes the run unsuccessful.
+    pub warnings_as_errors: bool,
+}
+
+/// Builds the JSON record of a backup run.
+///
+/// # Parameters
+/// - `run`: Times, target and options of the run.
+/// - `reports`: One report per source that was backed up, successfully or not.
+///
+/// # Returns
+/// - A JSON object with an overall `success` flag, the run details, the lists of warnings and
+///   errors, and one entry per source holding its backup directory, duration, statistics and
+///   error message.
+pub fn build_summary(run: &RunInfo, reports: &[SourceReport]) -> Value {
+    let sources: Vec<Value> = reports
+        .iter()
+        .map(|report| {
+            json!({
+                "source": report.source,
+                "success": report.succeeded(),
+                "backup_dir": report.result.as_ref().ok(),
+                "duration_seconds": report.duration.as_secs_f64(),
+                "stats": report.stats,
+                "error": report.result.as_ref().err(),
+            })
+        })
+        .collect();
+
+    let strict_failure = run.warnings_as_errors && !run.warnings.is_empty();
+    json!({
+        "success": !run.interrupted && !strict_failure && reports.iter().all(SourceReport::succeeded),
+        "interrupted": run.interrupted,
+        "started_at": run.started_at.to_rfc3339(),
+        "finished_at": run.finished_at.to_rfc3339(),
+        "target_dir": run.target_dir,
+        "config_section": run.config_section,
+        "options": run.options,
+        "sources": sources,
+        "warnings": run.warnings,
+        "errors": run.errors,
+    })
+}
+
+/// Atomically writes the summary to `path` as pretty-printed JSON (see `write_atomic`).
+///
+/// # Errors
+/// - Will return an error if the temporary file cannot be written or renamed.
+pub fn write_summary(path: &Path, summary: &Value) -> std::io::Result<()> {
+    let mut contents = serde_json::to_string_pretty(summary)?;
+    contents.push('\n');
+    write_atomic(path, contents.as_bytes())
+}
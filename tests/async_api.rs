@@ -0,0 +1,45 @@
+#![cfg(feature = "async")]
+
+use snapshotter::async_api::{copy_directory_async, Progress};
+use snapshotter::backup::CopyOptions;
+use std::fs;
+use std::io::ErrorKind;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn cancelled_copy_stops_and_delivers_the_events_so_far() {
+    let temp = tempfile::tempdir().unwrap();
+    let src = temp.path().join("src");
+    let dest = temp.path().join("dest");
+    fs::create_dir_all(&src).unwrap();
+    fs::create_dir_all(&dest).unwrap();
+    for i in 0..100 {
+        fs::write(src.join(format!("{}.txt", i)), "data").unwrap();
+    }
+    let cancel = CancellationToken::new();
+    // A single slot makes the copy wait for every event to be received
+    let (sender, mut receiver) = mpsc::channel(1);
+    let copy = tokio::spawn(copy_directory_async(
+        src.clone(),
+        dest.clone(),
+        CopyOptions::default(),
+        cancel.clone(),
+        sender,
+    ));
+
+    let mut copied = 0;
+    while let Some(event) = receiver.recv().await {
+        if let Progress::FileCopied { .. } = event {
+            copied += 1;
+            if copied == 5 {
+                cancel.cancel();
+            }
+        }
+    }
+    let error = copy.await.unwrap().unwrap_err();
+
+    assert_eq!(error.kind(), ErrorKind::Interrupted);
+    assert!((5..=7).contains(&copied), "{} files reported", copied);
+    assert_eq!(fs::read_dir(&dest).unwrap().count(), copied);
+}
@@ -0,0 +1,1335 @@
+//! End-to-end tests of the backup command. Features tested in depth have a file of their own
+//! next to this one (`mirror.rs`, `dry_run.rs`, `directories.rs`, ...), sharing the helpers in
+//! `common`.
+
+mod common;
+
+use common::{
+    assert_success, assert_trees_equal, empty_config, entries_starting_with, run, single_backup,
+    snapshot, write_config, TestTree,
+};
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn backup_copies_the_whole_tree() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 1024)
+        .file("nested/deeper/b.bin", 300_000)
+        .file("nested/empty.txt", 0)
+        .dir("empty_dir")
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    assert_trees_equal(&source, &backup);
+    #[cfg(unix)]
+    common::assert_modes_equal(&source, &backup);
+}
+
+#[test]
+fn configured_items_and_extensions_are_excluded() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("keep.txt", 10)
+        .file("debug.log", 10)
+        .file("archive.tar.gz", 10)
+        .file("node_modules/lib.js", 10)
+        .file("sub/node_modules/lib.js", 10)
+        .file("sub/keep.rs", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({
+            "excluded_items": ["node_modules"],
+            "excluded_extensions": ["log", "tar.gz"],
+        }),
+    );
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(
+        paths,
+        ["keep.txt", "sub", "sub/keep.rs"].map(std::path::PathBuf::from)
+    );
+}
+
+#[test]
+fn exclusions_match_names_anchored_paths_and_directories() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.log", 10)
+        .file("sub/b.log", 10)
+        .file("build/out.bin", 10)
+        .file("sub/build/out.bin", 10)
+        .file("docs/manual.pdf", 10)
+        .file("docs/v1/old.pdf", 10)
+        .file("docs/notes.txt", 10)
+        .file("cache/entry", 10)
+        .file("sub/cache", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({
+            "excluded_items": ["*.log", "/build", "docs/**/*.pdf", "cache/"],
+            "excluded_extensions": [],
+        }),
+    );
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(
+        paths,
+        [
+            "docs",
+            "docs/notes.txt",
+            "docs/v1",
+            "sub",
+            "sub/build",
+            "sub/build/out.bin",
+            "sub/cache",
+        ]
+        .map(std::path::PathBuf::from)
+    );
+}
+
+#[test]
+fn invalid_exclusion_patterns_are_all_reported() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": ["[abc", "*.log", "a**"], "excluded_extensions": [] }),
+    );
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(r#""[abc""#) && stderr.contains(r#""a**""#),
+        "{}",
+        stderr
+    );
+    assert!(!target.exists());
+
+    let output = run(&[&"check-config", &"--config", &config]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(r#"error: "#) && stdout.contains(r#""[abc""#),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn no_timestamp_updates_the_target_directory_itself() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("stays.txt", 100)
+        .file("goes/away.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let marker = std::path::PathBuf::from(".snapshotter");
+    let lock = std::path::PathBuf::from(".snapshotter.lock");
+
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--no-timestamp",
+    ]));
+    assert_eq!(
+        snapshot(&target).into_keys().collect::<Vec<_>>(),
+        [
+            marker.as_path(),
+            lock.as_path(),
+            "goes".as_ref(),
+            "goes/away.txt".as_ref(),
+            "stays.txt".as_ref()
+        ]
+    );
+
+    // Without --mirror, entries removed from the source stay in the target
+    fs::remove_dir_all(source.join("goes")).unwrap();
+    fs::write(source.join("added.txt"), b"added").unwrap();
+    let output = run(&[&source, &target, &"--config", &config, &"--no-timestamp"]);
+    assert_success(&output);
+    assert!(target.join("added.txt").exists() && target.join("goes/away.txt").exists());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 files unchanged"));
+
+    // With --mirror they are deleted, but the marker and lock files are kept
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--no-timestamp",
+        &"--mirror",
+    ]));
+    assert_eq!(
+        snapshot(&target).into_keys().collect::<Vec<_>>(),
+        [
+            marker.as_path(),
+            lock.as_path(),
+            "added.txt".as_ref(),
+            "stays.txt".as_ref()
+        ]
+    );
+}
+
+#[test]
+fn no_timestamp_target_is_recognized_without_a_lock_file() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let mirror = || {
+        run(&[
+            &source,
+            &target,
+            &"--config",
+            &config,
+            &"--no-timestamp",
+            &"--mirror",
+            &"--no-lock",
+        ])
+    };
+
+    assert_success(&mirror());
+    fs::write(source.join("b.txt"), b"b").unwrap();
+    let output = mirror();
+
+    assert_success(&output);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("did not create"));
+    let paths: Vec<_> = snapshot(&target).into_keys().collect();
+    assert_eq!(
+        paths,
+        [".snapshotter", "a.txt", "b.txt"].map(std::path::PathBuf::from)
+    );
+}
+
+#[test]
+fn paranoid_copies_damaged_files_again() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("intact.txt", 100)
+        .file("damaged.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let backup = |extra: &[&dyn AsRef<std::ffi::OsStr>]| {
+        let mut args: Vec<&dyn AsRef<std::ffi::OsStr>> =
+            vec![&source, &target, &"--config", &config, &"--no-timestamp"];
+        args.extend_from_slice(extra);
+        run(&args)
+    };
+    assert_success(&backup(&[]));
+
+    // Damage the copy without changing its size or modification time
+    let copy = target.join("damaged.txt");
+    let modified = fs::metadata(&copy).unwrap().modified().unwrap();
+    fs::write(&copy, vec![b'x'; 100]).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&copy)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    assert_success(&backup(&[]));
+    assert_ne!(
+        fs::read(&copy).unwrap(),
+        fs::read(source.join("damaged.txt")).unwrap()
+    );
+
+    let output = backup(&[&"--paranoid"]);
+    assert_success(&output);
+    assert_eq!(
+        fs::read(&copy).unwrap(),
+        fs::read(source.join("damaged.txt")).unwrap()
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 files unchanged"));
+    assert!(stdout.contains("1 files copied again"));
+}
+
+#[test]
+fn no_timestamp_refuses_a_source_inside_the_target() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &source,
+        &temp.path(),
+        &"--config",
+        &config,
+        &"--no-timestamp",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("contains the source"));
+    assert!(!temp.path().join("a.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn resume_continues_an_interrupted_backup() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("done.txt", 100)
+        .file("sub/missing.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let resume =
+        |target: &std::path::Path| run(&[&source, &target, &"--config", &config, &"--resume"]);
+
+    // A complete run leaves no journal behind
+    assert_success(&resume(&target));
+    let backup = single_backup(&target, "src");
+    assert_eq!(fs::read_dir(&target).unwrap().count(), 2); // The backup and the lock file
+
+    // Turn it back into what an interrupted run leaves: an in-progress directory holding part
+    // of the files, and a journal listing them after the source
+    let name = backup.file_name().unwrap().to_str().unwrap();
+    let in_progress = target.join(format!(".tmp.{}", name));
+    fs::rename(&backup, &in_progress).unwrap();
+    fs::remove_file(in_progress.join("sub/missing.txt")).unwrap();
+    let mut journal = fs::canonicalize(&source)
+        .unwrap()
+        .into_os_string()
+        .into_encoded_bytes();
+    journal.extend_from_slice(b"\0done.txt\0");
+    fs::write(target.join(format!(".tmp.{}.journal", name)), journal).unwrap();
+
+    let output = resume(&target);
+
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("1 files already copied by the interrupted run"));
+    assert_eq!(single_backup(&target, "src"), backup);
+    assert_trees_equal(&source, &backup);
+    assert_eq!(fs::read_dir(&target).unwrap().count(), 2);
+}
+
+#[test]
+fn mirror_is_kept_when_the_source_is_empty() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("sub/b.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let mirror = target.join("src_mirror");
+    assert_success(&run(&[&source, &target, &"--config", &config, &"--mirror"]));
+
+    // Like a drive that is not mounted: the directory is there, but empty
+    fs::remove_dir_all(&source).unwrap();
+    fs::create_dir(&source).unwrap();
+    let output = run(&[&source, &target, &"--config", &config, &"--mirror"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is empty (is it mounted?)"));
+    assert_eq!(snapshot(&mirror).len(), 3);
+}
+
+#[test]
+fn backing_up_into_the_source_itself_is_refused() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &source, &"--config", &config]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(fs::read_dir(&source).unwrap().count(), 1);
+}
+
+#[test]
+fn backup_dir_is_renamed_only_after_success() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+
+    assert!(entries_starting_with(&target, ".tmp.").is_empty());
+    single_backup(&target, "src");
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_entries_do_not_stop_the_backup() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .symlink("dangling", "missing.txt")
+        .file("z/b.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let backup = single_backup(&target, "src");
+    assert!(backup.join("a.txt").is_file());
+    assert!(backup.join("z/b.txt").is_file());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 entries could not be copied"));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_files_are_copied_as_files() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("real.txt", 64)
+        .symlink("link.txt", "real.txt")
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+
+    let backup = single_backup(&target, "src");
+    assert!(!backup.join("link.txt").is_symlink());
+    assert_trees_equal(&source, &backup);
+}
+
+#[cfg(unix)]
+#[test]
+fn dedup_hard_links_identical_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a/data.bin", 4096)
+        .file("b/data.bin", 4096)
+        .file("c/other.bin", 4096)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config, &"--dedup"]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    assert_trees_equal(&source, &backup);
+    let inode = |path: &str| fs::metadata(backup.join(path)).unwrap().ino();
+    assert_eq!(inode("a/data.bin"), inode("b/data.bin"));
+    assert_ne!(inode("a/data.bin"), inode("c/other.bin"));
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("1 duplicate files hard-linked (4.0 KiB saved)"));
+}
+
+#[test]
+fn diff_against_the_source_applies_the_exclusions() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .file("debug.log", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": [], "excluded_extensions": ["log"] }),
+    );
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+    let backup = single_backup(&target, "src");
+    fs::write(source.join("b.txt"), b"new").unwrap();
+    fs::write(source.join("other.log"), b"new").unwrap();
+
+    let output = run(&[&"diff", &backup, &source, &"--config", &config, &"--json"]);
+
+    assert_success(&output);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["added"], json!(["b.txt"]));
+    assert_eq!(report["removed"], json!([]));
+    assert_eq!(report["modified"], json!([]));
+}
+
+#[test]
+fn nested_name_template_creates_subdirectories() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let template = "{profile}/{source}/{timestamp}";
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--name-template",
+        &template,
+    ]);
+
+    assert_success(&output);
+    let backups = entries_starting_with(&target.join("default/src"), "");
+    assert_eq!(backups.len(), 1);
+    assert_trees_equal(&source, &backups[0]);
+
+    let output = run(&[&"latest", &target, &"--name-template", &template]);
+    assert_success(&output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        backups[0].to_string_lossy()
+    );
+}
+
+#[test]
+fn contents_lists_the_entries_of_a_backup() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("b.txt", 3)
+        .file("docs/a.txt", 10)
+        .dir("empty")
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+    let backup = single_backup(&target, "src");
+
+    let output = run(&[&"contents", &backup]);
+
+    assert_success(&output);
+    let separator = std::path::MAIN_SEPARATOR;
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "file\t3\tb.txt\ndir\t-\tdocs\nfile\t10\tdocs{}a.txt\ndir\t-\tempty\n",
+            separator
+        )
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn owner_filter_skips_files_of_other_users() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("mine.txt", 10)
+        .file("other/theirs.txt", 10)
+        .file("other/mine.txt", 10)
+        .build(&temp.path().join("src"));
+    // Giving a file to another user requires root
+    let nobody = 65534;
+    if std::os::unix::fs::chown(source.join("other/theirs.txt"), Some(nobody), Some(nobody))
+        .is_err()
+    {
+        return;
+    }
+    std::os::unix::fs::chown(source.join("other"), Some(nobody), Some(nobody)).unwrap();
+    let uid = fs::metadata(source.join("mine.txt")).unwrap().uid();
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--owner",
+        &uid.to_string(),
+    ]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(
+        paths,
+        ["mine.txt", "other", "other/mine.txt"].map(std::path::PathBuf::from)
+    );
+}
+
+#[test]
+fn flatten_copies_every_file_into_the_backup_root() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a/photo.jpg", 10)
+        .file("b/c/photo.jpg", 20)
+        .file("b/notes", 5)
+        .file("top.txt", 5)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--flatten",
+    ]));
+
+    let backup = single_backup(&target, "src");
+    let entries = snapshot(&backup);
+    let paths: Vec<_> = entries.keys().cloned().collect();
+    assert_eq!(
+        paths,
+        ["notes", "photo.jpg", "photo_2.jpg", "top.txt"].map(std::path::PathBuf::from)
+    );
+    let mut sizes: Vec<_> = ["photo.jpg", "photo_2.jpg"]
+        .map(|name| fs::metadata(backup.join(name)).unwrap().len())
+        .into();
+    sizes.sort();
+    assert_eq!(sizes, [10, 20]);
+}
+
+#[cfg(unix)]
+#[test]
+fn snapshot_command_backs_up_the_snapshot_and_releases_it() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .file("sub/b.txt", 20)
+        .build(&temp.path().join("src"));
+    let snapshot = temp.path().join("snapshot");
+    let released = temp.path().join("released");
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let take = format!(
+        "cp -R \"$SNAPSHOTTER_SOURCE\" {0} && echo extra > {0}/only_in_snapshot.txt && echo {0}",
+        snapshot.display()
+    );
+    let release = format!("echo \"$SNAPSHOTTER_SNAPSHOT\" > {}", released.display());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--snapshot-command",
+        &take,
+        &"--release-snapshot-command",
+        &release,
+    ]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    assert!(backup.join("only_in_snapshot.txt").is_file());
+    assert_trees_equal(&snapshot, &backup);
+    assert_eq!(
+        fs::read_to_string(&released).unwrap().trim(),
+        snapshot.to_string_lossy()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn failing_snapshot_command_fails_the_source() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--snapshot-command",
+        &"exit 3",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(entries_starting_with(&target, "src_backup_").is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn post_hook_gets_the_backup_details() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .file("b.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let hook = "echo \"$SNAPSHOTTER_STATUS $SNAPSHOTTER_FILES_COPIED $SNAPSHOTTER_BACKUP_DIR\"";
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--post-hook",
+        &hook,
+    ]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let expected = format!("[post-hook] success 2 {}", backup.display());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&expected));
+}
+
+#[cfg(unix)]
+#[test]
+fn failing_pre_hook_skips_the_source() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--pre-hook",
+        &"echo not now; exit 1",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("[pre-hook] not now"));
+    assert!(entries_starting_with(&target, "src_backup_").is_empty());
+}
+
+#[test]
+fn max_depth_prunes_deeper_directories() {
+    let temp = TempDir::new().unwrap();
+    let mut tree = TestTree::new().file("top.txt", 1);
+    let mut dir = String::new();
+    for level in 0..50 {
+        dir.push_str(&format!("d{}/", level));
+        tree = tree.file(&format!("{}f.txt", dir), 1);
+    }
+    let source = tree.build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config, &"--max-depth", &"2"]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(
+        paths,
+        ["d0", "d0/d1", "d0/d1/f.txt", "d0/f.txt", "top.txt"].map(std::path::PathBuf::from)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("1 directories deeper than --max-depth skipped"));
+}
+
+#[test]
+fn files_from_copies_only_the_listed_entries() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("top.txt", 1)
+        .file("unlisted.txt", 1)
+        .file("docs/my notes.txt", 2)
+        .file("docs/other.txt", 2)
+        .file("photos/2024/a.jpg", 3)
+        .file("photos/2024/b.jpg", 3)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let list = temp.path().join("list.txt");
+    fs::write(
+        &list,
+        "top.txt\n./docs/my notes.txt\nmissing.txt\nphotos/2024\n",
+    )
+    .unwrap();
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--files-from",
+        &list,
+    ]);
+
+    // The missing entry is reported and fails the run, but does not stop the other entries
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to copy") && stderr.contains("missing.txt"));
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(
+        paths,
+        [
+            "docs",
+            "docs/my notes.txt",
+            "photos",
+            "photos/2024",
+            "photos/2024/a.jpg",
+            "photos/2024/b.jpg",
+            "top.txt"
+        ]
+        .map(std::path::PathBuf::from)
+    );
+}
+
+// Both names can only be created in the source on a case-sensitive filesystem
+#[cfg(target_os = "linux")]
+#[test]
+fn case_collisions_fail_or_are_renamed_on_case_insensitive_targets() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("README.md", 1)
+        .file("readme.md", 2)
+        .build(&temp.path().join("src"));
+    let config = empty_config(temp.path());
+
+    let failing_target = temp.path().join("failing");
+    let output = run(&[
+        &source,
+        &failing_target,
+        &"--config",
+        &config,
+        &"--target-case-insensitive",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("differs only in case"));
+    assert_eq!(snapshot(&single_backup(&failing_target, "src")).len(), 1);
+
+    let renaming_target = temp.path().join("renaming");
+    let output = run(&[
+        &source,
+        &renaming_target,
+        &"--config",
+        &config,
+        &"--target-case-insensitive",
+        &"--rename-collisions",
+    ]);
+
+    assert_success(&output);
+    // Which of the two keeps its name depends on the order the directory is listed in
+    let mut names: Vec<_> = snapshot(&single_backup(&renaming_target, "src"))
+        .into_keys()
+        .map(|path| path.to_string_lossy().to_lowercase())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["readme.md", "readme_2.md"]);
+}
+
+#[test]
+fn relative_path_style_keeps_the_path_below_the_relative_root() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("file.txt", 10)
+        .build(&temp.path().join("lib/myapp/data"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let relative = |root: &std::path::Path| {
+        run(&[
+            &source,
+            &target,
+            &"--config",
+            &config,
+            &"--backup-path-style",
+            &"relative",
+            &"--relative-root",
+            &root,
+        ])
+    };
+
+    assert_success(&relative(&temp.path().join("lib")));
+    let backup = single_backup(&target, "data");
+    assert_trees_equal(&source, &backup.join("myapp/data"));
+
+    let output = relative(&temp.path().join("target"));
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not inside --relative-root"));
+    assert_eq!(single_backup(&target, "data"), backup);
+}
+
+#[test]
+fn warnings_are_counted_without_failing_the_run() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("top.txt", 1)
+        .file("a/b/deep.txt", 1)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config, &"--max-depth", &"1"]);
+
+    assert_success(&output);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Finished with 1 warnings and 0 errors")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_files_are_skipped_with_a_warning() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .file("secret.txt", 10)
+        .file("z.txt", 10)
+        .build(&temp.path().join("src"));
+    let secret = source.join("secret.txt");
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads files regardless of their permissions
+    if fs::File::open(&secret).is_ok() {
+        return;
+    }
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let summary_file = temp.path().join("summary.json");
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--summary-file",
+        &summary_file,
+    ]);
+
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&single_backup(&target, "src"))
+        .into_keys()
+        .collect();
+    assert_eq!(paths, ["a.txt", "z.txt"].map(std::path::PathBuf::from));
+    let summary: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&summary_file).unwrap()).unwrap();
+    assert_eq!(
+        summary["sources"][0]["stats"]["files_unreadable"],
+        serde_json::json!([secret])
+    );
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--fail-on-unreadable",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn warnings_as_errors_fails_a_run_with_warnings() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("top.txt", 1)
+        .file("a/b/deep.txt", 1)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let summary_file = temp.path().join("summary.json");
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--max-depth",
+        &"1",
+        &"--warnings-as-errors",
+        &"--summary-file",
+        &summary_file,
+    ]);
+
+    // The backup itself is complete; only the exit status and the summary reflect the warning
+    assert_eq!(output.status.code(), Some(1));
+    assert!(single_backup(&target, "src").join("top.txt").exists());
+    let summary: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&summary_file).unwrap()).unwrap();
+    assert_eq!(summary["success"], false);
+    assert_eq!(summary["sources"][0]["success"], true);
+    assert_eq!(summary["errors"], json!([]));
+    let warnings = summary["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0]
+        .as_str()
+        .unwrap()
+        .contains("deeper than --max-depth"));
+}
+
+#[test]
+fn locked_target_is_reported_as_failed_in_the_metrics() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 1)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let metrics = temp.path().join("metrics.prom");
+    // The previous run succeeded
+    fs::write(&metrics, "snapshotter_success{source=\"old\"} 1\n").unwrap();
+    let _lock = snapshotter::backup::lock_target(&target, Duration::ZERO).unwrap();
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--metrics-file",
+        &metrics,
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let contents = fs::read_to_string(&metrics).unwrap();
+    assert!(
+        contents.contains(&format!(
+            "snapshotter_success{{source=\"{}\"}} 0\n",
+            source.display()
+        )),
+        "{}",
+        contents
+    );
+    assert!(!contents.contains("old"));
+}
+
+#[test]
+fn failing_to_write_metrics_is_a_warning() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 1)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    // A path below a file cannot be created
+    let metrics = source.join("a.txt").join("metrics.prom");
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--metrics-file",
+        &metrics,
+    ]);
+
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Failed to write metrics file"),
+        "{}",
+        stderr
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Finished with 1 warnings and 0 errors")
+    );
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--metrics-file",
+        &metrics,
+        &"--warnings-as-errors",
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_to_parent_directories_are_skipped() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("sub/a.txt", 10)
+        .symlink("sub/loop", "..")
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_success(&output);
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(paths, ["sub", "sub/a.txt"].map(std::path::PathBuf::from));
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("1 symbolic links to parent directories skipped"));
+}
+
+#[test]
+fn check_config_reports_every_section() {
+    let temp = TempDir::new().unwrap();
+    let config = temp.path().join("config.json");
+    fs::write(
+        &config,
+        r#"{
+            "default": {
+                "excluded_items": ["target"],
+                "excluded_extensions": [],
+                "exclude_extensions": ["log"]
+            },
+            "python": {
+                "excluded_items": [],
+                "excluded_extensions": [],
+                "retries": "3"
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let output = run(&[&"check-config", &"--config", &config]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(
+        r#"warning: unknown configuration key "exclude_extensions" in section "default" (did you mean "excluded_extensions"?)"#
+    ));
+    assert!(stdout.contains(r#""excluded_items": ["#));
+    assert!(stdout.contains(r#"invalid value for "python.retries""#));
+
+    let output = run(&[&"check-config", &"--config", &config, &"default"]);
+    assert_success(&output);
+}
+
+#[test]
+fn usage_errors_exit_with_status_2() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+
+    for args in [
+        vec![source.as_os_str(), "--no-such-option".as_ref()],
+        vec![source.as_os_str()],
+    ] {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_snapshotter"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Usage:"));
+    }
+    assert!(!target.exists());
+}
+
+#[test]
+fn option_conflicts_are_found_after_merging_the_configuration() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+
+    let cases = [
+        (
+            json!({ "no_delete": true }),
+            vec![],
+            "--no-delete requires --mirror",
+        ),
+        (
+            json!({ "name_template": "{source}-{date}" }),
+            vec!["--no-timestamp"],
+            "--name-template cannot be combined with --no-timestamp",
+        ),
+        (
+            json!({ "mirror": true }),
+            vec!["--dedup"],
+            "--dedup cannot be combined with --mirror",
+        ),
+    ];
+    for (section, extra, message) in cases {
+        let mut section = section;
+        section["excluded_items"] = json!([]);
+        section["excluded_extensions"] = json!([]);
+        let config = write_config(temp.path(), section);
+        let mut args: Vec<&dyn AsRef<std::ffi::OsStr>> =
+            vec![&source, &target, &"--config", &config];
+        args.extend(extra.iter().map(|arg| arg as &dyn AsRef<std::ffi::OsStr>));
+
+        let output = run(&args);
+
+        assert_eq!(output.status.code(), Some(2), "{}", message);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains(message),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(!target.exists());
+
+        // The configuration alone conflicts unless the command line took part
+        if extra.is_empty() {
+            let output = run(&[&"check-config", &"--config", &config]);
+            assert_eq!(output.status.code(), Some(1));
+            assert!(String::from_utf8_lossy(&output.stdout).contains(message));
+        }
+    }
+}
+
+#[test]
+fn content_types_are_excluded_regardless_of_the_extension() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("notes.txt", 10)
+        .build(&temp.path().join("src"));
+    // An MP4 header behind a misleading extension, and a PNG without one
+    fs::write(source.join("holiday.txt"), b"\0\0\0\x20ftypisom\0\0\x02\0").unwrap();
+    fs::write(source.join("image"), b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR").unwrap();
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({
+            "excluded_items": [],
+            "excluded_extensions": [],
+            "skip_content_types": ["video/*"]
+        }),
+    );
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&single_backup(&target, "src"))
+        .into_keys()
+        .collect();
+    assert_eq!(paths, ["image", "notes.txt"].map(std::path::PathBuf::from));
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("1 files skipped: content type excluded")
+    );
+}
+
+#[test]
+fn restore_copies_only_the_picked_entries() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .file("docs/2024/report.txt", 20)
+        .file("docs/2024/other.txt", 30)
+        .file("photos/p.jpg", 40)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+    let backup = single_backup(&target, "src");
+    let restored = temp.path().join("restored");
+
+    let output = run(&[
+        &"restore",
+        &backup,
+        &restored,
+        &"--pick",
+        &"docs/2024/report.txt",
+        &"--pick",
+        &"photos",
+    ]);
+
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&restored).into_keys().collect();
+    assert_eq!(
+        paths,
+        [
+            "docs",
+            "docs/2024",
+            "docs/2024/report.txt",
+            "photos",
+            "photos/p.jpg"
+        ]
+        .map(std::path::PathBuf::from)
+    );
+    assert_eq!(
+        fs::read(restored.join("docs/2024/report.txt")).unwrap(),
+        fs::read(source.join("docs/2024/report.txt")).unwrap()
+    );
+
+    // Picks are globs with the syntax of exclusions
+    let globbed = temp.path().join("globbed");
+    let output = run(&[&"restore", &backup, &globbed, &"--pick", &"docs/**/r*.txt"]);
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&globbed).into_keys().collect();
+    assert_eq!(
+        paths,
+        ["docs", "docs/2024", "docs/2024/report.txt"].map(std::path::PathBuf::from)
+    );
+
+    let output = run(&[
+        &"restore", &backup, &restored, &"--pick", &"*.jpg", &"--pick", &"/2024", &"--pick",
+        &"*.png",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--pick pattern matched nothing"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.ends_with(": \"/2024\", \"*.png\"\n"), "{}", stderr);
+
+    let output = run(&[&"restore", &backup, &restored, &"--pick", &"[abc"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn deleting_runs_are_refused_in_a_target_with_foreign_entries() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let target = TestTree::new()
+        .file("taxes.pdf", 10)
+        .build(&temp.path().join("target"));
+    let config = empty_config(temp.path());
+
+    // Adding a backup only warns
+    let output = run(&[&source, &target, &"--config", &config]);
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("contains entries snapshotter did not create"));
+
+    let output = run(&[&source, &target, &"--config", &config, &"--mirror"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("\"taxes.pdf\""));
+    assert!(!target.join("src_mirror").exists());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--mirror",
+        &"--allow-mixed-target",
+    ]);
+    assert_success(&output);
+    assert!(target.join("taxes.pdf").exists());
+    assert_trees_equal(&source, &target.join("src_mirror"));
+}
@@ -0,0 +1,208 @@
+//! Helpers shared by the end-to-end tests: a fixture builder for source trees, a runner for
+//! the snapshotter binary and assertions comparing directory trees.
+
+#![allow(dead_code)]
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// An entry of a `TestTree`.
+enum Node {
+    File { path: String, size: usize },
+    Dir { path: String },
+    Symlink { path: String, target: String },
+}
+
+/// Builds a directory tree for a test, e.g.
+/// `TestTree::new().file("a/b.txt", 1024).dir("empty").build(&root)`.
+#[derive(Default)]
+pub struct TestTree {
+    nodes: Vec<Node>,
+}
+
+impl TestTree {
+    pub fn new() -> Self {
+        TestTree::default()
+    }
+
+    /// Adds a file of `size` bytes. Its contents are derived from the path, so different files
+    /// of the same size differ.
+    pub fn file(mut self, path: &str, size: usize) -> Self {
+        self.nodes.push(Node::File {
+            path: path.to_string(),
+            size,
+        });
+        self
+    }
+
+    /// Adds an (empty) directory.
+    pub fn dir(mut self, path: &str) -> Self {
+        self.nodes.push(Node::Dir {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    /// Adds a symbolic link to `target`, which is interpreted relative to the link.
+    pub fn symlink(mut self, path: &str, target: &str) -> Self {
+        self.nodes.push(Node::Symlink {
+            path: path.to_string(),
+            target: target.to_string(),
+        });
+        self
+    }
+
+    /// Creates the tree below `root` (which is created too) and returns `root`.
+    pub fn build(self, root: &Path) -> PathBuf {
+        fs::create_dir_all(root).unwrap();
+        for node in self.nodes {
+            match node {
+                Node::File { path, size } => {
+                    let path = root.join(path);
+                    fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    fs::write(&path, contents(&path, size)).unwrap();
+                }
+                Node::Dir { path } => fs::create_dir_all(root.join(path)).unwrap(),
+                Node::Symlink { path, target } => {
+                    let path = root.join(path);
+                    fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    symlink(Path::new(&target), &path);
+                }
+            }
+        }
+        root.to_path_buf()
+    }
+}
+
+/// Deterministic file contents seeded by the file name.
+fn contents(path: &Path, size: usize) -> Vec<u8> {
+    let seed = Sha256::digest(path.file_name().unwrap().as_encoded_bytes());
+    (0..size)
+        .map(|i| seed[i % seed.len()].wrapping_add((i / seed.len()) as u8))
+        .collect()
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) {
+    std::os::unix::fs::symlink(target, link).unwrap();
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) {
+    let resolved = link.parent().unwrap().join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link).unwrap();
+    } else {
+        std::os::windows::fs::symlink_file(target, link).unwrap();
+    }
+}
+
+/// Writes a configuration file with a single `default` section and returns its path.
+pub fn write_config(dir: &Path, section: serde_json::Value) -> PathBuf {
+    let path = dir.join("config.json");
+    let config = serde_json::json!({ "default": section });
+    fs::write(&path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    path
+}
+
+/// Writes a configuration file that excludes nothing and returns its path.
+pub fn empty_config(dir: &Path) -> PathBuf {
+    write_config(
+        dir,
+        serde_json::json!({ "excluded_items": [], "excluded_extensions": [] }),
+    )
+}
+
+/// Runs the snapshotter binary with `args` and returns its output.
+pub fn run(args: &[&dyn AsRef<std::ffi::OsStr>]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_snapshotter"))
+        .args(args.iter().map(|arg| arg.as_ref()))
+        .output()
+        .unwrap()
+}
+
+/// Panics with the output of the run unless it exited successfully.
+pub fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "snapshotter failed with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Returns the entries of `dir` whose name starts with `prefix`.
+pub fn entries_starting_with(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(prefix)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// Returns the only backup of a source named `source_name` in `target`.
+pub fn single_backup(target: &Path, source_name: &str) -> PathBuf {
+    let backups = entries_starting_with(target, &format!("{}_backup_", source_name));
+    assert_eq!(backups.len(), 1, "expected one backup, found {:?}", backups);
+    backups.into_iter().next().unwrap()
+}
+
+/// What is recorded about every entry of a tree.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Entry {
+    Dir,
+    File { size: u64, sha256: String },
+}
+
+/// Lists every entry below `root`, keyed by its path relative to `root`. Symbolic links are
+/// followed, like snapshotter does.
+pub fn snapshot(root: &Path) -> BTreeMap<PathBuf, Entry> {
+    fn walk(root: &Path, dir: &Path, entries: &mut BTreeMap<PathBuf, Entry>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let relative = path.strip_prefix(root).unwrap().to_path_buf();
+            if path.is_dir() {
+                entries.insert(relative, Entry::Dir);
+                walk(root, &path, entries);
+            } else {
+                let data = fs::read(&path).unwrap();
+                let entry = Entry::File {
+                    size: data.len() as u64,
+                    sha256: format!("{:x}", Sha256::digest(&data)),
+                };
+                entries.insert(relative, entry);
+            }
+        }
+    }
+
+    let mut entries = BTreeMap::new();
+    walk(root, root, &mut entries);
+    entries
+}
+
+/// Asserts that two trees hold the same paths with the same contents.
+pub fn assert_trees_equal(expected: &Path, actual: &Path) {
+    assert_eq!(snapshot(expected), snapshot(actual));
+}
+
+/// Asserts that the Unix permission bits of every entry below `expected` match `actual`.
+#[cfg(unix)]
+pub fn assert_modes_equal(expected: &Path, actual: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    for path in snapshot(expected).keys() {
+        let mode = |root: &Path| fs::metadata(root.join(path)).unwrap().permissions().mode();
+        assert_eq!(mode(expected), mode(actual), "mode of {:?}", path);
+    }
+}
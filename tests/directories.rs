@@ -0,0 +1,146 @@
+mod common;
+
+use common::{
+    assert_success, assert_trees_equal, empty_config, run, single_backup, snapshot, write_config,
+    TestTree,
+};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+#[test]
+fn empty_directories_are_kept_at_every_depth() {
+    let temp = TempDir::new().unwrap();
+    let empty_dirs = [
+        "top",
+        "a/next_to_a_file",
+        "a/b/c/d/deep",
+        "only_dirs/x/y",
+        "only_dirs/z",
+    ];
+    let source = empty_dirs
+        .iter()
+        .fold(TestTree::new().file("a/file.txt", 10), |tree, dir| {
+            tree.dir(dir)
+        })
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+
+    let backup = single_backup(&target, "src");
+    assert_trees_equal(&source, &backup);
+    for dir in empty_dirs {
+        let entries = fs::read_dir(backup.join(dir)).unwrap().count();
+        assert_eq!(entries, 0, "{} should be an empty directory", dir);
+    }
+}
+
+#[test]
+fn directory_modification_times_are_preserved() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("old/inner/a.txt", 10)
+        .build(&temp.path().join("src"));
+    let old_time = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(source.join("old/inner"), old_time).unwrap();
+    filetime::set_file_mtime(source.join("old"), old_time).unwrap();
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+
+    let backup = single_backup(&target, "src");
+    for dir in ["old", "old/inner"] {
+        let metadata = fs::metadata(backup.join(dir)).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&metadata),
+            old_time,
+            "mtime of {}",
+            dir
+        );
+    }
+}
+
+#[test]
+fn no_empty_dirs_removes_directories_without_files() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("kept/a.txt", 10)
+        .file("only_logs/debug.log", 10)
+        .dir("empty/nested")
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": [], "excluded_extensions": ["log"] }),
+    );
+
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--no-empty-dirs",
+    ]));
+
+    let backup = single_backup(&target, "src");
+    let paths: Vec<_> = snapshot(&backup).into_keys().collect();
+    assert_eq!(paths, ["kept", "kept/a.txt"].map(PathBuf::from));
+}
+
+#[cfg(unix)]
+#[test]
+fn no_empty_dirs_keeps_directories_of_resumed_files() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("resumed/done.txt", 100)
+        .file("copied/missing.txt", 100)
+        .build(&temp.path().join("src"));
+    let old_time = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(source.join("resumed"), old_time).unwrap();
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let resume = || {
+        run(&[
+            &source,
+            &target,
+            &"--config",
+            &config,
+            &"--resume",
+            &"--no-empty-dirs",
+        ])
+    };
+    assert_success(&resume());
+    let backup = single_backup(&target, "src");
+
+    // Turn it into an interrupted run that had copied resumed/done.txt only
+    let name = backup.file_name().unwrap().to_str().unwrap();
+    let in_progress = target.join(format!(".tmp.{}", name));
+    fs::rename(&backup, &in_progress).unwrap();
+    fs::remove_file(in_progress.join("copied/missing.txt")).unwrap();
+    // Directory times are copied once a directory is complete, which the interrupted run
+    // never got to
+    filetime::set_file_mtime(in_progress.join("resumed"), filetime::FileTime::now()).unwrap();
+    let mut journal = fs::canonicalize(&source)
+        .unwrap()
+        .into_os_string()
+        .into_encoded_bytes();
+    journal.extend_from_slice(b"\0resumed/done.txt\0");
+    fs::write(target.join(format!(".tmp.{}.journal", name)), journal).unwrap();
+
+    let output = resume();
+
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("1 files already copied by the interrupted run"));
+    assert_trees_equal(&source, &backup);
+    // The resumed file counts as content: the directory is kept and completed like any other
+    let metadata = fs::metadata(backup.join("resumed")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&metadata),
+        old_time
+    );
+}
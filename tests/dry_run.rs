@@ -0,0 +1,49 @@
+mod common;
+
+use common::{assert_success, empty_config, run, snapshot, TestTree};
+use tempfile::TempDir;
+
+#[test]
+fn dry_run_leaves_the_target_untouched() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("sub/b.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = TestTree::new()
+        .file("unrelated.txt", 5)
+        .build(&temp.path().join("target"));
+    let config = empty_config(temp.path());
+    let metrics = temp.path().join("metrics.prom");
+    let before = snapshot(&target);
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--dry-run",
+        &"--metrics-file",
+        &metrics,
+    ]);
+
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Dry run: would copy 2 files"));
+    assert_eq!(snapshot(&target), before);
+    assert!(!metrics.exists());
+}
+
+#[test]
+fn dry_run_does_not_create_a_missing_target() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config, &"--dry-run"]);
+
+    assert_success(&output);
+    assert!(!target.exists());
+}
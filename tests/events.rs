@@ -0,0 +1,103 @@
+mod common;
+
+use common::{assert_success, run, single_backup, write_config, TestTree};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Returns the events of `kind` among `events`.
+fn of_kind<'a>(events: &'a [Value], kind: &str) -> Vec<&'a Value> {
+    events
+        .iter()
+        .filter(|event| event["event"] == kind)
+        .collect()
+}
+
+/// Returns the `path` field of `event` relative to `root`.
+fn relative_path(event: &Value, root: &Path) -> PathBuf {
+    let path = Path::new(event["path"].as_str().unwrap());
+    path.strip_prefix(root).unwrap().to_path_buf()
+}
+
+#[test]
+fn json_lines_output_has_one_event_per_entry() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("sub/b.txt", 2000)
+        .file("sub/c.txt", 0)
+        .file("sub/debug.log", 10)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": [], "excluded_extensions": ["log"] }),
+    );
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--output",
+        &"json-lines",
+    ]);
+
+    assert_success(&output);
+    let events: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).unwrap_or_else(|e| panic!("{:?} is not JSON: {}", line, e))
+        })
+        .collect();
+
+    let copied: BTreeMap<PathBuf, u64> = of_kind(&events, "file_copied")
+        .into_iter()
+        .map(|event| {
+            let bytes = event["bytes"].as_u64().unwrap();
+            (relative_path(event, &source), bytes)
+        })
+        .collect();
+    assert_eq!(
+        copied,
+        BTreeMap::from([
+            (PathBuf::from("a.txt"), 100),
+            (PathBuf::from("sub/b.txt"), 2000),
+            (PathBuf::from("sub/c.txt"), 0),
+        ])
+    );
+
+    let skipped = of_kind(&events, "file_skipped");
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(
+        relative_path(skipped[0], &source),
+        Path::new("sub/debug.log")
+    );
+    assert_eq!(skipped[0]["reason"], "excluded");
+
+    let entered: Vec<PathBuf> = of_kind(&events, "dir_entered")
+        .into_iter()
+        .map(|event| relative_path(event, &source))
+        .collect();
+    assert_eq!(entered, [PathBuf::new(), PathBuf::from("sub")]);
+
+    let summary = of_kind(&events, "summary");
+    assert_eq!(summary.len(), 1);
+    assert_eq!(summary[0]["source"], source.to_str().unwrap());
+    assert_eq!(
+        Path::new(summary[0]["backup_dir"].as_str().unwrap()),
+        single_backup(&target, "src")
+    );
+    assert_eq!(summary[0]["dry_run"], false);
+    assert_eq!(summary[0]["stats"]["files_copied"], 3);
+    assert_eq!(summary[0]["stats"]["bytes_copied"], 2100);
+    assert_eq!(summary[0]["stats"]["files_skipped"], 1);
+    assert!(summary[0]["duration_seconds"].as_f64().unwrap() >= 0.0);
+
+    assert_eq!(
+        events.last().unwrap(),
+        &json!({ "event": "run_finished", "warnings": 0, "errors": 0 })
+    );
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::{
+    assert_success, assert_trees_equal, empty_config, run, snapshot, write_config, TestTree,
+};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+#[test]
+fn mirror_tracks_changes_and_deletions() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("stays.txt", 100)
+        .file("changes.txt", 100)
+        .file("goes/away.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    let mirror = target.join("src_mirror");
+
+    assert_success(&run(&[&source, &target, &"--config", &config, &"--mirror"]));
+    assert_trees_equal(&source, &mirror);
+
+    fs::write(source.join("changes.txt"), b"new contents").unwrap();
+    fs::remove_dir_all(source.join("goes")).unwrap();
+    fs::write(source.join("added.txt"), b"added").unwrap();
+    let output = run(&[&source, &target, &"--config", &config, &"--mirror"]);
+
+    assert_success(&output);
+    assert_trees_equal(&source, &mirror);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 files unchanged"));
+}
+
+#[test]
+fn excluded_entries_are_kept_in_the_mirror() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("debug.log", 100)
+        .file("old/x.txt", 100)
+        .file("sub/old/y.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let mirror = target.join("src_mirror");
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &empty_config(temp.path()),
+        &"--mirror",
+    ]));
+
+    // Excluded from now on, and gone from the source: an excluded entry is never deleted
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": ["/old/"], "excluded_extensions": ["log"] }),
+    );
+    fs::remove_file(source.join("debug.log")).unwrap();
+    fs::remove_dir_all(source.join("old")).unwrap();
+    // Not excluded: the pattern is anchored to the source root
+    fs::remove_dir_all(source.join("sub/old")).unwrap();
+    fs::remove_file(source.join("a.txt")).unwrap();
+    fs::write(source.join("b.txt"), b"b").unwrap();
+    let output = run(&[&source, &target, &"--config", &config, &"--mirror"]);
+
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&mirror).into_keys().collect();
+    assert_eq!(
+        paths,
+        ["b.txt", "debug.log", "old", "old/x.txt", "sub"].map(PathBuf::from)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Deleted"));
+}
+
+#[test]
+fn sources_with_the_same_name_are_refused() {
+    let temp = TempDir::new().unwrap();
+    let first = TestTree::new()
+        .file("a.txt", 100)
+        .build(&temp.path().join("one/data"));
+    let second = TestTree::new()
+        .file("b.txt", 100)
+        .build(&temp.path().join("two/data"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &first,
+        &target,
+        &"--source",
+        &second,
+        &"--config",
+        &config,
+        &"--mirror",
+    ]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("same mirror directory data_mirror"));
+    assert!(!target.exists());
+
+    // Without --mirror, each source gets a backup directory of its own
+    assert_success(&run(&[
+        &first,
+        &target,
+        &"--source",
+        &second,
+        &"--config",
+        &config,
+    ]));
+}
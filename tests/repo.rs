@@ -0,0 +1,171 @@
+#![cfg(feature = "repo")]
+
+mod common;
+
+use common::{
+    assert_success, assert_trees_equal, empty_config, entries_starting_with, run, snapshot,
+    write_config, TestTree,
+};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Returns the number of chunks stored in the repository `target`.
+fn chunk_count(target: &Path) -> usize {
+    fs::read_dir(target.join("objects"))
+        .unwrap()
+        .map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+        .sum()
+}
+
+/// Returns the manifests of a source named `src` in the repository `target`, oldest first.
+fn manifests(target: &Path) -> Vec<PathBuf> {
+    entries_starting_with(&target.join("manifests"), "src_backup_")
+}
+
+#[test]
+fn repo_backup_restores_the_copied_tree() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("sub/b.txt", 5000)
+        .file("node_modules/x.js", 100)
+        .dir("empty")
+        .build(&temp.path().join("src"));
+    let expected = TestTree::new()
+        .file("a.txt", 100)
+        .file("sub/b.txt", 5000)
+        .dir("empty")
+        .build(&temp.path().join("expected"));
+    let target = temp.path().join("target");
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": ["node_modules"], "excluded_extensions": [] }),
+    );
+
+    let output = run(&[&source, &target, &"--config", &config, &"--repo"]);
+
+    assert_success(&output);
+    // No backup directory, only the repository
+    let mut names: Vec<_> = fs::read_dir(&target)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, [".snapshotter.lock", "manifests", "objects"]);
+    assert_eq!(chunk_count(&target), 2);
+    let [manifest] = &manifests(&target)[..] else {
+        panic!("expected one manifest");
+    };
+
+    let restored = temp.path().join("restored");
+    let output = run(&[&"restore", manifest, &restored]);
+
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Restored 2 files"));
+    assert_trees_equal(&expected, &restored);
+    assert_eq!(
+        fs::metadata(source.join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        fs::metadata(restored.join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap()
+    );
+}
+
+#[test]
+fn prune_removes_only_chunks_no_manifest_refers_to() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("b.txt", 200)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    assert_success(&run(&[&source, &target, &"--config", &config, &"--repo"]));
+
+    // The second backup stores only the new file, and no longer refers to the deleted one
+    fs::remove_file(source.join("a.txt")).unwrap();
+    fs::write(source.join("c.txt"), "new").unwrap();
+    assert_success(&run(&[&source, &target, &"--config", &config, &"--repo"]));
+    assert_eq!(chunk_count(&target), 3);
+    let [first, second] = &manifests(&target)[..] else {
+        panic!("expected two manifests");
+    };
+
+    // Nothing is unreferenced while both manifests exist
+    let output = run(&[&"prune", &target]);
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Removed 0 unreferenced chunks"));
+
+    fs::remove_file(first).unwrap();
+    let output = run(&[&"prune", &target]);
+
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Removed 1 unreferenced chunks"));
+    assert_eq!(chunk_count(&target), 2);
+    let restored = temp.path().join("restored");
+    assert_success(&run(&[&"restore", second, &restored]));
+    assert_trees_equal(&source, &restored);
+}
+
+#[test]
+fn restore_picks_entries_of_a_manifest() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .file("docs/2024/report.txt", 100)
+        .file("docs/notes.md", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+    assert_success(&run(&[&source, &target, &"--config", &config, &"--repo"]));
+    let [manifest] = &manifests(&target)[..] else {
+        panic!("expected one manifest");
+    };
+
+    let restored = temp.path().join("restored");
+    let output = run(&[&"restore", manifest, &restored, &"--pick", &"/docs/2024"]);
+
+    assert_success(&output);
+    let paths: Vec<_> = snapshot(&restored).into_keys().collect();
+    assert_eq!(
+        paths,
+        [
+            Path::new("docs"),
+            Path::new("docs/2024"),
+            Path::new("docs/2024/report.txt")
+        ]
+    );
+
+    let output = run(&[&"restore", manifest, &restored, &"--pick", &"*.pdf"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("\"*.pdf\""));
+}
+
+#[test]
+fn repo_rejects_modes_that_update_a_copy_in_place() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 100)
+        .build(&temp.path().join("src"));
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--repo",
+        &"--mirror",
+    ]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--repo cannot be combined"));
+    assert!(!target.exists());
+}
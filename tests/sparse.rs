@@ -0,0 +1,50 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{assert_success, assert_trees_equal, empty_config, run, single_backup};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Size of the sparse test file; only a few kilobytes of it hold data.
+const LOGICAL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Creates a file with data at both ends and a hole in between.
+fn write_sparse_file(path: &Path) {
+    let mut file = File::create(path).unwrap();
+    file.write_all(&[1; 4096]).unwrap();
+    file.set_len(LOGICAL_SIZE).unwrap();
+    file.seek(SeekFrom::End(-4096)).unwrap();
+    file.write_all(&[2; 4096]).unwrap();
+}
+
+#[test]
+fn sparse_files_stay_sparse_in_the_backup() {
+    let temp = TempDir::new().unwrap();
+    let source = temp.path().join("src");
+    fs::create_dir(&source).unwrap();
+    write_sparse_file(&source.join("disk.img"));
+    let source_blocks = fs::metadata(source.join("disk.img")).unwrap().blocks();
+    assert!(
+        source_blocks * 512 < LOGICAL_SIZE,
+        "the filesystem of the test directory does not support holes"
+    );
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[&source, &target, &"--config", &config]));
+
+    let backup = single_backup(&target, "src");
+    let copy = fs::metadata(backup.join("disk.img")).unwrap();
+    assert_eq!(copy.len(), LOGICAL_SIZE);
+    assert!(
+        copy.blocks() <= source_blocks,
+        "the copy occupies {} blocks, the source {}",
+        copy.blocks(),
+        source_blocks
+    );
+    assert_trees_equal(&source, &backup);
+}
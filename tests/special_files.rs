@@ -0,0 +1,55 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{empty_config, single_backup, TestTree};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+#[test]
+fn fifos_are_skipped_without_hanging() {
+    let temp = TempDir::new().unwrap();
+    let source = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("src"));
+    let fifo = CString::new(source.join("pipe").as_os_str().as_bytes()).unwrap();
+    // SAFETY: `fifo` is a valid NUL-terminated path
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    // Nothing ever writes to the FIFO, so opening it for reading would block forever
+    let mut child = Command::new(env!("CARGO_BIN_EXE_snapshotter"))
+        .args([source.as_os_str(), target.as_os_str()])
+        .arg("--config")
+        .arg(&config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if started.elapsed() > Duration::from_secs(30) {
+            child.kill().unwrap();
+            panic!("the backup did not finish within 30 seconds");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("skipping special file"), "{}", stderr);
+    assert!(
+        stdout.contains("1 special files (FIFOs, sockets, devices) skipped"),
+        "{}",
+        stdout
+    );
+    let backup = single_backup(&target, "src");
+    assert!(backup.join("a.txt").is_file());
+    assert!(!backup.join("pipe").exists());
+}
@@ -0,0 +1,68 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{
+    assert_success, assert_trees_equal, empty_config, entries_starting_with, run, single_backup,
+    write_config, TestTree,
+};
+use serde_json::json;
+use std::os::unix::fs::symlink;
+use tempfile::TempDir;
+
+#[test]
+fn symlinked_source_root_is_refused() {
+    let temp = TempDir::new().unwrap();
+    let real = TestTree::new()
+        .file("a.txt", 10)
+        .build(&temp.path().join("real"));
+    let source = temp.path().join("src");
+    symlink(&real, &source).unwrap();
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    let output = run(&[&source, &target, &"--config", &config]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is a symbolic link; pass --dereference-root"),
+        "{}",
+        stderr
+    );
+    assert!(entries_starting_with(&target, "src").is_empty());
+}
+
+#[test]
+fn dereference_root_follows_a_symlinked_source_root() {
+    let temp = TempDir::new().unwrap();
+    let real = TestTree::new()
+        .file("a.txt", 10)
+        .file("sub/b.txt", 20)
+        .build(&temp.path().join("real"));
+    let source = temp.path().join("src");
+    symlink(&real, &source).unwrap();
+    let target = temp.path().join("target");
+    let config = empty_config(temp.path());
+
+    assert_success(&run(&[
+        &source,
+        &target,
+        &"--config",
+        &config,
+        &"--dereference-root",
+    ]));
+
+    // The backup is named after the link and holds the directory it points to
+    let backup = single_backup(&target, "src");
+    assert_trees_equal(&real, &backup);
+
+    // The configuration key does the same
+    let config = write_config(
+        temp.path(),
+        json!({ "excluded_items": [], "excluded_extensions": [], "dereference_root": true }),
+    );
+    let other_target = temp.path().join("other");
+    assert_success(&run(&[&source, &other_target, &"--config", &config]));
+    assert_trees_equal(&real, &single_backup(&other_target, "src"));
+}